@@ -0,0 +1,140 @@
+// Throughput of the rolling checksum, signing and diffing, without a bench framework.
+// Run with `cargo bench`; each case prints its best time of a few runs and MB/s, and the
+// allocation cases the number of allocations of one run
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rolling_hash_rs::diff::{generate_diff_borrowed, DiffOptions};
+use rolling_hash_rs::{generate_diff, get_signature, RollingChecksum, RollingWindow};
+
+// The system allocator, counting allocations and reallocations
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const BUFFER_LEN: usize = 16 * 1024 * 1024;
+const BLOCK_SIZE: u32 = 4096;
+const RUNS: usize = 3;
+
+// xorshift64* bytes, so the buffers have no repeated blocks
+fn pseudo_random_bytes(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed ^ 0x9e37_79b9_7f4a_7c15;
+    (0..len)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+        })
+        .collect()
+}
+
+fn bench(name: &str, bytes: usize, mut run: impl FnMut()) {
+    let best = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+    println!(
+        "{:<40} {:>10.2} ms {:>10.1} MB/s",
+        name,
+        best.as_secs_f64() * 1e3,
+        bytes as f64 / best.as_secs_f64() / 1e6
+    );
+}
+
+fn allocations(name: &str, run: impl FnOnce()) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    run();
+    println!(
+        "{:<40} {:>10} allocations",
+        name,
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    );
+}
+
+fn main() {
+    let old_file = pseudo_random_bytes(1, BUFFER_LEN);
+    let unrelated = pseudo_random_bytes(2, BUFFER_LEN);
+
+    bench("RollingWindow::add_bytes_at_end", BUFFER_LEN, || {
+        let mut window = RollingWindow::generate();
+        window.add_bytes_at_end(black_box(&old_file));
+        black_box(window.weak_key());
+    });
+
+    bench("RollingWindow::roll_window", BUFFER_LEN, || {
+        let mut window = RollingWindow::generate();
+        window.add_bytes_at_end(&old_file[..BLOCK_SIZE as usize]);
+        for (prev, next) in old_file.iter().zip(&old_file[BLOCK_SIZE as usize..]) {
+            window.roll_window(*prev, Some(*next));
+        }
+        black_box(window.weak_key());
+    });
+
+    bench("get_signature", BUFFER_LEN, || {
+        black_box(get_signature(black_box(&old_file), BLOCK_SIZE));
+    });
+
+    // Every block matches at its own offset: one strong hash per block
+    let signature = get_signature(&old_file, BLOCK_SIZE);
+    bench("generate_diff all match", BUFFER_LEN, || {
+        black_box(generate_diff(&old_file, &signature, BLOCK_SIZE as usize));
+    });
+
+    // Nothing matches: the window rolls over every byte of the new file
+    bench("generate_diff all literal", BUFFER_LEN, || {
+        black_box(generate_diff(&unrelated, &signature, BLOCK_SIZE as usize));
+    });
+
+    // Mostly changed: one block in eight of the old file survives, between literal runs
+    let mostly_changed: Vec<u8> = unrelated
+        .chunks(BLOCK_SIZE as usize)
+        .zip(old_file.chunks(BLOCK_SIZE as usize))
+        .enumerate()
+        .flat_map(|(index, (new, old))| if index % 8 == 0 { old } else { new })
+        .copied()
+        .collect();
+    allocations("generate_diff mostly changed", || {
+        black_box(generate_diff(
+            &mostly_changed,
+            &signature,
+            BLOCK_SIZE as usize,
+        ));
+    });
+    allocations("generate_diff_borrowed mostly changed", || {
+        black_box(
+            generate_diff_borrowed(
+                &mostly_changed,
+                &signature,
+                BLOCK_SIZE as usize,
+                &DiffOptions::default(),
+            )
+            .unwrap(),
+        );
+    });
+}
@@ -0,0 +1,37 @@
+// Sign an old buffer, diff a new one against the signature and patch the old buffer
+// with the delta, which must rebuild the new buffer exactly.
+//
+// Input layout: block size (1 byte, used as 1 to 256), rolling checksum (1 byte),
+// old buffer length (2 bytes, little endian), then the old buffer followed by the new
+// buffer. Short inputs fill the old buffer first
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rolling_hash_rs::diff::generate_diff;
+use rolling_hash_rs::patch::apply_patch;
+use rolling_hash_rs::signature::{get_signature_with_options, SignatureOptions};
+use rolling_hash_rs::RollingChecksumAlgorithm;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 4 {
+        return;
+    }
+    let block_size = data[0] as u32 + 1;
+    let rolling_checksum = match data[1] % 3 {
+        0 => RollingChecksumAlgorithm::Prime,
+        1 => RollingChecksumAlgorithm::WidePrime,
+        _ => RollingChecksumAlgorithm::Adler32,
+    };
+    let old_length = u16::from_le_bytes([data[2], data[3]]) as usize;
+    let (old, new) = data[4..].split_at(old_length.min(data.len() - 4));
+
+    let options = SignatureOptions {
+        rolling_checksum,
+        ..SignatureOptions::default()
+    };
+    let signature = get_signature_with_options(old, block_size, &options);
+    let delta = generate_diff(new, &signature, block_size as usize);
+    let mut rebuilt: Vec<u8> = Vec::new();
+    apply_patch(old, &delta, block_size, &mut rebuilt).unwrap();
+    assert_eq!(new, rebuilt.as_slice());
+});
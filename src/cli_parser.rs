@@ -1,35 +1,488 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser};
 use std::path::PathBuf;
+use std::time::Duration;
+
+use rolling_hash_rs::file_diff::{BlockIndexBase, CollisionPolicy};
+use rolling_hash_rs::librsync::SignatureFormat;
+use rolling_hash_rs::signature::StrongHashAlgorithm;
+use rolling_hash_rs::window_checksum::RollingChecksumAlgorithm;
 
 #[derive(Parser)]
 pub struct GenSignatureArgs {
+    /// File to sign, or a directory to sign every file under into one manifest
     #[arg(short, long, value_name = "OLD_FILE")]
     pub old_file: PathBuf,
 
     #[arg(short, long, value_name = "SIGNATURE_FILE")]
     pub signature_file: PathBuf,
+    /// Reuse the strong hash of identical blocks instead of recomputing it.
+    /// Faster on repetitive files, but keeps a copy of every distinct block in memory
+    #[arg(long)]
+    pub cache_strong_hashes: bool,
+
+    /// Use this block size for any file length instead of deriving it from the length,
+    /// so signatures of different files share block boundaries. Small blocks find more
+    /// duplicate data in small files
+    #[arg(
+        long,
+        visible_alias = "block-size",
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub fixed_block_size: Option<u32>,
+
+    /// Weak checksum of the blocks. Recorded in the signature, so diffing uses it too
+    #[arg(long, value_enum, default_value_t = RollingChecksumAlgorithm::Prime)]
+    pub rolling_checksum: RollingChecksumAlgorithm,
+
+    /// Record blocks repeating an earlier block as a reference to it instead of with
+    /// their own hashes. Makes signatures of files with repeated regions smaller
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Also record a rolling checksum and SHA-256 of the whole file. Diffing a new file
+    /// with both then writes an all-match delta without scanning it
+    #[arg(long)]
+    pub whole_file_hash: bool,
+
+    /// Prefix every strong hash input with this tag, so the block hashes can't be confused
+    /// with hashes computed elsewhere. Recorded in the signature, so diffing uses it too
+    #[arg(long, value_name = "TAG", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    pub domain_tag: Option<String>,
+
+    /// Strong hash of the blocks. Recorded in the signature, so diffing uses it too
+    #[arg(long, value_enum, default_value_t = StrongHashAlgorithm::Sha256)]
+    pub strong_hash: StrongHashAlgorithm,
+
+    /// Keep only this many leading bytes of each strong hash (default: the whole digest).
+    /// Shorter hashes make smaller signatures but raise the odds of a false match
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u8).range(1..=32))]
+    pub strong_hash_len: Option<u8>,
+
+    /// Hash blocks on this many threads (default: the number of logical CPUs).
+    /// 1 signs on a single thread, reading the file a block at a time
+    #[cfg(feature = "parallel")]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub threads: Option<u32>,
+
+    /// Print the block size, file length and block count the signature would have,
+    /// without signing the file or creating the signature file
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Signature file format. `librsync` writes an `rdiff signature` compatible file with
+    /// MD4 strong sums, for diffing with rdiff; of the other options only the block size
+    /// and strong hash length apply to it
+    #[arg(long, value_enum, default_value_t = SignatureFormat::Native)]
+    pub signature_format: SignatureFormat,
 }
 
 #[derive(Parser)]
 pub struct GenDiffArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "SIGNATURE_FILE",
+        required_unless_present = "against_self",
+        conflicts_with = "against_self"
+    )]
+    pub signature_file: Option<PathBuf>,
+
+    /// File to diff, or a directory to diff against the manifest of the old directory
+    #[arg(short, long, value_name = "NEW_FILE")]
+    pub new_file: PathBuf,
+    /// Delta file, or the directory to write a new directory's deltas to
+    #[arg(short, long, value_name = "DELTA_FILE")]
+    pub delta_file: PathBuf,
+    /// Handling of weak hash hits that no strong hash confirms
+    #[arg(long, value_enum, default_value_t = CollisionPolicy::Verify)]
+    pub on_collision: CollisionPolicy,
+    /// Collision rate (collisions per scanned window) tolerated by `--on-collision error`
+    #[arg(long, value_name = "RATE", default_value_t = 0.05)]
+    pub max_collision_rate: f64,
+    /// Give up if a streamed new file (e.g. a FIFO) isn't closed within this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub read_timeout: Option<Duration>,
+    /// At block aligned offsets, try the old block with the same index before the weak hash lookup
+    #[arg(long)]
+    pub position_hints: bool,
+    /// Debugging aid: write the rolling window state of every scanned window to this file
+    #[arg(long, value_name = "PATH")]
+    pub dump_rolling_state: Option<PathBuf>,
+    /// Inline short matches into neighbouring literals where that makes the delta smaller
+    #[arg(long)]
+    pub optimize: bool,
+    /// Split literal runs into delta entries of at most this many bytes
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_literal_run: Option<u64>,
+    /// Turn matches into literals unless they are part of a run of at least this many matched blocks
+    #[arg(long, value_name = "BLOCKS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_match_blocks: Option<u64>,
+    /// Number matched blocks in the delta file from 0, from 1, or by their byte offset in the
+    /// old file. Recorded in the delta, so apply-patch and verify read any of them
+    #[arg(long, value_enum, value_name = "BASE", default_value_t = BlockIndexBase::Zero)]
+    pub block_index_base: BlockIndexBase,
+    /// Compute the strong hashes of weak hash hits ahead of the scan on this many threads.
+    /// Helps inputs with many weak hash collisions, though some windows are hashed for nothing
+    #[cfg(feature = "parallel")]
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub threads: u32,
+    /// Sanity check: sign the new file and diff it against itself, failing unless every block matches
+    #[arg(long)]
+    pub against_self: bool,
+}
+
+#[derive(Parser)]
+pub struct DiffBatchArgs {
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: PathBuf,
+
+    #[arg(short, long, value_name = "NEW_FILE", num_args = 1.., required = true)]
+    pub new_files: Vec<PathBuf>,
+
+    /// Directory receiving one <new file name>.delta per new file
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output_dir: PathBuf,
+
+    /// Diff up to this many new files at once, each on its own thread
+    #[cfg(feature = "parallel")]
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub parallel_files: u32,
+}
+
+#[derive(Parser)]
+pub struct ChainArgs {
+    /// Versions of the file, oldest first
+    #[arg(short, long, value_name = "VERSION", num_args = 2.., required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Directory receiving the signature of the first version, the deltas and the manifest
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output_dir: PathBuf,
+}
+
+#[derive(Parser)]
+pub struct EstimateArgs {
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: PathBuf,
+
+    /// Only the length of the new file is used, its content is not read. A stream, such
+    /// as `-` for standard input, has to be read to its end to learn its length
+    #[arg(short, long, value_name = "NEW_FILE")]
+    pub new_file: PathBuf,
+}
+
+#[derive(Parser)]
+pub struct SelfDedupArgs {
+    #[arg(short, long, value_name = "FILE")]
+    pub file: PathBuf,
+
+    #[arg(short, long, value_name = "BYTES")]
+    pub block_size: u32,
+}
+
+#[derive(Parser)]
+pub struct InspectDeltaArgs {
+    #[arg(short, long, value_name = "DELTA_FILE")]
+    pub delta_file: PathBuf,
+
+    /// Show a hexdump of each literal run, limited to LINES lines per run
+    #[arg(long, value_name = "LINES", num_args = 0..=1, default_missing_value = "8")]
+    pub hexdump: Option<usize>,
+
+    /// Before listing, check that every matched block exists in the signed file
+    #[arg(long, requires = "signature_file")]
+    pub validate_delta: bool,
+
+    /// Signature of the old file, for --validate-delta
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct InspectSignatureArgs {
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: PathBuf,
+
+    /// Print the whole signature as JSON instead of a summary: the block size, the block
+    /// count and every block's index, weak hash and hex strong hash
+    #[arg(long)]
+    pub json: bool,
+
+    /// Also report how the entries share weak hashes: the buckets with more than one entry,
+    /// each costing strong hashes while diffing, and the largest bucket
+    #[arg(long, conflicts_with = "json")]
+    pub stats: bool,
+
+    /// Print every parameter the signature was generated with instead of a summary: the
+    /// format version, block size, chunking, weak checksum and modulus, strong hash and
+    /// how much of it is kept, block count and signed file length
+    #[arg(short, long, conflicts_with = "json")]
+    pub verbose: bool,
+}
+
+#[derive(Parser)]
+pub struct CompareArgs {
     #[arg(short, long, value_name = "SIGNATURE_FILE")]
     pub signature_file: PathBuf,
 
     #[arg(short, long, value_name = "NEW_FILE")]
     pub new_file: PathBuf,
-    /// Delta file
+
+    /// Only count blocks whose weak checksum is in the signature. Much faster, but approximate:
+    /// weak checksum collisions inflate the count and shifted content is missed
+    #[arg(long)]
+    pub count_only: bool,
+
+    /// Write a PBM image of the new file's blocks, black where a block changed
+    #[arg(long, value_name = "PATH")]
+    pub visualize: Option<PathBuf>,
+
+    /// Exit with the similarity bucket: 0 when every byte matched, 1 above --similar-above,
+    /// 2 above --partial-above, 3 otherwise. Errors then exit with 101 instead of 2
+    #[arg(long)]
+    pub exit_code: bool,
+
+    /// Matched fraction above which the new file counts as similar
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.9, value_parser = parse_fraction)]
+    pub similar_above: f64,
+
+    /// Matched fraction above which the new file counts as partially similar. At most
+    /// --similar-above
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.5, value_parser = parse_fraction)]
+    pub partial_above: f64,
+
+    /// Only check that the new file is identical to the signed file, stopping at the first
+    /// differing block. Exits with 1 and reports its offset if they differ
+    #[arg(long, conflicts_with_all = ["count_only", "visualize", "exit_code"])]
+    pub expect_identical: bool,
+}
+
+#[derive(Parser)]
+#[command(group(ArgGroup::new("block").required(true).args(["block_size", "signature_file"])))]
+pub struct BlockBoundariesArgs {
+    #[arg(short, long, value_name = "FILE")]
+    pub file: PathBuf,
+
+    #[arg(short, long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..))]
+    pub block_size: Option<u32>,
+
+    /// Use the block size recorded in this signature
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: Option<PathBuf>,
+
+    /// Print a JSON array instead of one offset per line
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct ApplyPatchArgs {
+    #[arg(short, long, value_name = "OLD_FILE")]
+    pub old_file: PathBuf,
+
+    #[arg(short, long, value_name = "DELTA_FILE")]
+    pub delta_file: PathBuf,
+
+    /// Receives the reconstructed new file
+    #[arg(long, value_name = "OUTPUT_FILE")]
+    pub output_file: PathBuf,
+
+    /// Signature the delta was generated against, for its block size, and to check that
+    /// the old file is the signed file by its length and first block.
+    /// Without it, the block size is derived from the old file's length as signing does
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: Option<PathBuf>,
+
+    /// Check every old block the delta copies against its strong hash in the signature
+    /// before writing anything
+    #[arg(long, visible_alias = "verify-copies", requires = "signature_file")]
+    pub verify_blocks: bool,
+}
+
+#[derive(Parser)]
+pub struct VerifyArgs {
+    #[arg(short, long, value_name = "OLD_FILE")]
+    pub old_file: PathBuf,
+
+    #[arg(short, long, value_name = "DELTA_FILE")]
+    pub delta_file: PathBuf,
+
+    /// File the delta should reconstruct
+    #[arg(short, long, value_name = "NEW_FILE")]
+    pub new_file: PathBuf,
+
+    /// Signature the delta was generated against, for its block size, and to check that
+    /// the old file is the signed file by its length and first block.
+    /// Without it, the block size is derived from the old file's length as signing does
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct RepairSignatureArgs {
+    #[arg(
+        short,
+        long,
+        visible_alias = "signature",
+        value_name = "SIGNATURE_FILE"
+    )]
+    pub signature_file: PathBuf,
+
+    /// File the signature was generated from
+    #[arg(long, value_name = "FILE")]
+    pub source: PathBuf,
+
+    /// Write the repaired signature here instead of replacing the signature file
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct BundleArgs {
+    #[arg(short, long, value_name = "SIGNATURE_FILE")]
+    pub signature_file: PathBuf,
+
     #[arg(short, long, value_name = "DELTA_FILE")]
     pub delta_file: PathBuf,
+
+    #[arg(short, long, value_name = "BUNDLE_FILE")]
+    pub bundle_file: PathBuf,
+}
+
+#[derive(Parser)]
+pub struct VerifyRoundtripArgs {
+    #[arg(long, value_name = "OLD_FILE")]
+    pub old: PathBuf,
+
+    #[arg(long, value_name = "NEW_FILE")]
+    pub new: PathBuf,
+
+    /// Also write the signature and the delta, as <NEW_FILE>.signature and <NEW_FILE>.delta
+    #[arg(long)]
+    pub keep_intermediates: bool,
+}
+
+#[derive(Parser)]
+pub struct SelfTestArgs {
+    /// Seed of the generated data and edits, to replay a failed run (default: from the clock)
+    #[arg(long, value_name = "SEED")]
+    pub seed: Option<u64>,
+
+    /// Length of the generated old file
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+    pub size: u64,
+
+    /// Random edits turning the old file into the new file
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub edits: u32,
 }
 
 #[derive(Parser)]
 pub enum SubCommand {
     GenerateSignature(GenSignatureArgs),
     GenerateDiff(GenDiffArgs),
+    /// Rebuild the new file from the old file and a delta
+    ApplyPatch(ApplyPatchArgs),
+    /// Check that applying a delta to the old file reproduces the new file, writing nothing.
+    /// Exits with 1 and reports the first differing offset if it doesn't
+    Verify(VerifyArgs),
+    /// Diff several new files against one signature, read only once
+    DiffBatch(DiffBatchArgs),
+    /// Diff every version of a file against the previous one, for applying in sequence
+    Chain(ChainArgs),
+    /// Estimate the best and worst case delta size without diffing
+    Estimate(EstimateArgs),
+    /// Report how many blocks of a single file are duplicates of each other
+    SelfDedup(SelfDedupArgs),
+    /// List the matched blocks and literal runs of a delta file
+    InspectDelta(InspectDeltaArgs),
+    /// Summarize a signature file, including its approximate in-memory size
+    InspectSignature(InspectSignatureArgs),
+    /// Report how much of a new file can be reused from the signed file
+    Compare(CompareArgs),
+    /// Print the start offset of every block a file is split into
+    BlockBoundaries(BlockBoundariesArgs),
+    /// Combine a signature file and a delta file into a single bundle file
+    Bundle(BundleArgs),
+    /// Split a bundle file back into its signature file and delta file
+    Unbundle(BundleArgs),
+    /// Rewrite the damaged entries of a signature from the file it was generated from
+    RepairSignature(RepairSignatureArgs),
+    /// Sign, diff and patch generated data in memory, printing PASS or FAIL with the seed.
+    /// Exits with 1 on FAIL
+    SelfTest(SelfTestArgs),
+    /// Sign the old file, diff the new file, patch the old file and compare the result with
+    /// the new file, printing PASS or FAIL and the delta stats. Exits with 1 on FAIL
+    VerifyRoundtrip(VerifyRoundtripArgs),
 }
 
 #[derive(Parser)]
+#[command(after_help = "A file path of - reads standard input or writes standard output")]
 pub struct CliOptions {
     #[clap(subcommand)]
     pub sub_command: SubCommand,
+
+    /// Keep the temporary output file when generation fails, for inspection
+    #[arg(long, global = true)]
+    pub retain_temp: bool,
+
+    /// fsync outputs and their directory before reporting success
+    #[arg(long, global = true)]
+    pub durable: bool,
+
+    /// Retry reads failing with a transient error (e.g. a timeout on a network mount) up to N times
+    #[arg(long, global = true, value_name = "N", default_value_t = 0)]
+    pub io_retries: u32,
+
+    /// Delay before the first read retry, doubling with each further retry
+    #[arg(
+        long,
+        global = true,
+        value_name = "MILLISECONDS",
+        default_value_t = 100
+    )]
+    pub io_retry_delay: u64,
+
+    /// Print only errors and requested output, not messages about generated files
+    /// or compare's similarity report
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+}
+
+impl CliOptions {
+    // Usage errors clap can't find argument by argument
+    pub fn check(&self) -> Result<(), clap::Error> {
+        if let SubCommand::Compare(compare) = &self.sub_command {
+            if compare.partial_above > compare.similar_above {
+                return Err(CliOptions::command().error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--partial-above {} is above --similar-above {}",
+                        compare.partial_above, compare.similar_above
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Fraction from 0 to 1. NaN and values outside the range would put every comparison in
+// the same similarity bucket
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|error| format!("{}", error))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("{} is not a fraction from 0 to 1", value));
+    }
+    Ok(fraction)
+}
+
+// Duration of a number of seconds. Negative, NaN and infinite values are usage errors
+// rather than a panic converting them later
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value.parse().map_err(|error| format!("{}", error))?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{} is not a duration in seconds", value))
 }
@@ -1,4 +1,18 @@
+pub mod bundle;
+pub mod chain;
+pub mod compare;
+pub mod dedup;
+pub mod error;
 pub mod file_diff;
 pub mod file_io;
+pub mod inspect;
+pub mod librsync;
+pub mod manifest;
+pub(crate) mod md4;
+pub mod patch;
+pub(crate) mod progress;
+pub mod self_test;
 pub mod signature;
+#[cfg(test)]
+pub(crate) mod testutil;
 pub mod window_checksum;
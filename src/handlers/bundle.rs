@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+
+use super::file_diff::read_delta;
+use super::file_io::read_file_to_buffer;
+use super::signature::read_signature;
+
+// Bundle layout:
+//   magic "RHBUNDLE", format version (1 byte)
+//   signature section, then delta section, each:
+//     section kind (1 byte), length (u64 little endian), serialized bytes
+pub(crate) const BUNDLE_MAGIC: &[u8; 8] = b"RHBUNDLE";
+const BUNDLE_VERSION: u8 = 1;
+const SIGNATURE_SECTION: u8 = b'S';
+const DELTA_SECTION: u8 = b'D';
+
+// Serialized signature and delta carried by a bundle
+#[derive(Debug, PartialEq, Eq)]
+pub struct Bundle {
+    pub signature: Vec<u8>,
+    pub delta: Vec<u8>,
+}
+
+fn write_section(kind: u8, bytes: &[u8], out: &mut impl Write) -> Result<()> {
+    out.write_all(&[kind])?;
+    out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    out.write_all(bytes)
+}
+
+fn read_section(kind: u8, input: &mut impl Read) -> Result<Vec<u8>> {
+    let mut section_kind = [0u8; 1];
+    input.read_exact(&mut section_kind)?;
+    if section_kind[0] != kind {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "expected bundle section {:?}, found {:?}",
+                kind as char, section_kind[0] as char
+            ),
+        ));
+    }
+    let mut length = [0u8; 8];
+    input.read_exact(&mut length)?;
+
+    let mut bytes: Vec<u8> = Vec::new();
+    input
+        .take(u64::from_le_bytes(length))
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != u64::from_le_bytes(length) {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "bundle section is truncated",
+        ));
+    }
+    Ok(bytes)
+}
+
+// Write a serialized signature and delta as one bundle
+pub fn write_bundle(bundle: &Bundle, out: &mut impl Write) -> Result<()> {
+    out.write_all(BUNDLE_MAGIC)?;
+    out.write_all(&[BUNDLE_VERSION])?;
+    write_section(SIGNATURE_SECTION, &bundle.signature, out)?;
+    write_section(DELTA_SECTION, &bundle.delta, out)
+}
+
+// Read a bundle back into its serialized signature and delta
+pub fn read_bundle(input: &mut impl Read) -> Result<Bundle> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != BUNDLE_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a bundle file"));
+    }
+    let mut version = [0u8; 1];
+    input.read_exact(&mut version)?;
+    if version[0] != BUNDLE_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported bundle version {}", version[0]),
+        ));
+    }
+
+    Ok(Bundle {
+        signature: read_section(SIGNATURE_SECTION, input)?,
+        delta: read_section(DELTA_SECTION, input)?,
+    })
+}
+
+// Bundle a signature file and a delta file, checking that both deserialize first
+pub fn bundle_files(
+    signature_file: &File,
+    delta_file: &File,
+    bundle_file: &mut File,
+) -> Result<()> {
+    let bundle = Bundle {
+        signature: read_file_to_buffer(&mut BufReader::new(signature_file))?,
+        delta: read_file_to_buffer(&mut BufReader::new(delta_file))?,
+    };
+    read_signature(&mut bundle.signature.as_slice())?;
+    read_delta(&mut bundle.delta.as_slice())?;
+
+    let mut bundle_writer = BufWriter::new(bundle_file);
+    write_bundle(&bundle, &mut bundle_writer)?;
+    bundle_writer.flush()
+}
+
+// Split a bundle file back into a signature file and a delta file
+pub fn unbundle_file(
+    bundle_file: &File,
+    signature_file: &mut File,
+    delta_file: &mut File,
+) -> Result<()> {
+    let bundle = read_bundle(&mut BufReader::new(bundle_file))?;
+    signature_file.write_all(&bundle.signature)?;
+    delta_file.write_all(&bundle.delta)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_io::read_handler;
+    use crate::handlers::testutil::temp_path;
+    use std::path::Path;
+
+    fn fixture_bundle() -> Bundle {
+        let read = |path: &str| {
+            let file = read_handler(Path::new(path)).unwrap();
+            read_file_to_buffer(&mut BufReader::new(&file)).unwrap()
+        };
+        Bundle {
+            signature: read("data/signature"),
+            delta: read("data/diff"),
+        }
+    }
+
+    #[test]
+    pub fn test_bundle_round_trip() {
+        let bundle = fixture_bundle();
+        let mut bytes: Vec<u8> = Vec::new();
+        write_bundle(&bundle, &mut bytes).unwrap();
+
+        assert!(bytes.starts_with(BUNDLE_MAGIC));
+        assert_eq!(
+            9 + 2 * 9 + bundle.signature.len() + bundle.delta.len(),
+            bytes.len()
+        );
+        assert_eq!(bundle, read_bundle(&mut bytes.as_slice()).unwrap());
+    }
+
+    #[test]
+    pub fn test_read_bundle_rejects_corrupt_input() {
+        let mut bytes: Vec<u8> = Vec::new();
+        write_bundle(&fixture_bundle(), &mut bytes).unwrap();
+
+        let truncated = read_bundle(&mut &bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, truncated.kind());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        let error = read_bundle(&mut wrong_magic.as_slice()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        // Delta section where the signature section belongs
+        let mut swapped = bytes;
+        swapped[9] = DELTA_SECTION;
+        let error = read_bundle(&mut swapped.as_slice()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    pub fn test_unbundle_reproduces_separate_files() {
+        let bundle_path = temp_path("rolling-hash-bundle");
+        let signature_path = temp_path("rolling-hash-unbundled-signature");
+        let delta_path = temp_path("rolling-hash-unbundled-delta");
+
+        bundle_files(
+            &read_handler(Path::new("data/signature")).unwrap(),
+            &read_handler(Path::new("data/diff")).unwrap(),
+            &mut File::create(&bundle_path).unwrap(),
+        )
+        .unwrap();
+        unbundle_file(
+            &read_handler(&bundle_path).unwrap(),
+            &mut File::create(&signature_path).unwrap(),
+            &mut File::create(&delta_path).unwrap(),
+        )
+        .unwrap();
+
+        let expected = fixture_bundle();
+        assert_eq!(expected.signature, std::fs::read(&signature_path).unwrap());
+        assert_eq!(expected.delta, std::fs::read(&delta_path).unwrap());
+        for path in [bundle_path, signature_path, delta_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_bundle_files_rejects_invalid_delta() {
+        let bundle_path = temp_path("rolling-hash-invalid-bundle");
+        let error = bundle_files(
+            &read_handler(Path::new("data/signature")).unwrap(),
+            &read_handler(Path::new("data/new.txt")).unwrap(),
+            &mut File::create(&bundle_path).unwrap(),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        std::fs::remove_file(bundle_path).unwrap();
+    }
+}
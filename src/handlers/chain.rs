@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+use super::file_diff::{generate_diff, write_delta};
+use super::file_io::{read_file_to_buffer, read_handler, AtomicOutput, OutputOptions};
+use super::signature::{find_blocksize, get_signature, write_signature};
+
+pub const CHAIN_SIGNATURE_FILE_NAME: &str = "base.signature";
+pub const CHAIN_MANIFEST_FILE_NAME: &str = "manifest";
+
+// One delta of a chain, upgrading the version `from` to the version `to`
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChainLink {
+    pub delta_path: PathBuf,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+fn read_version(path: &Path) -> Result<Vec<u8>> {
+    let file = read_handler(path)?;
+    read_file_to_buffer(&mut BufReader::new(&file))
+}
+
+// Write the signature of the first version and a delta of every version against the
+// one before it into output_dir, so a client holding the first version can apply the
+// deltas in order to reach the last one. The manifest lists the deltas in that order,
+// one "<delta file>\t<from version>\t<to version>" line each. Paths may hold spaces, but
+// not tabs or line breaks, which would make the manifest ambiguous
+pub fn write_chain(
+    versions: &[PathBuf],
+    output_dir: &Path,
+    output_options: &OutputOptions,
+) -> Result<Vec<ChainLink>> {
+    if versions.len() < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "a chain needs at least two versions",
+        ));
+    }
+    if let Some(version) = versions
+        .iter()
+        .find(|version| version.to_string_lossy().contains(['\t', '\n', '\r']))
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{:?} can't be listed in a chain manifest: it holds a tab or line break",
+                version
+            ),
+        ));
+    }
+
+    let mut previous = read_version(&versions[0])?;
+    let mut links: Vec<ChainLink> = Vec::with_capacity(versions.len() - 1);
+    for (position, to) in versions.iter().enumerate().skip(1) {
+        let block_size = find_blocksize(previous.len() as u64);
+        let signature = get_signature(&previous, block_size);
+        if position == 1 {
+            let signature_path = output_dir.join(CHAIN_SIGNATURE_FILE_NAME);
+            let mut signature_file = AtomicOutput::create(&signature_path, output_options)?;
+            let mut signature_writer = BufWriter::new(signature_file.file());
+            write_signature(&signature, &mut signature_writer)?;
+            signature_writer.flush()?;
+            drop(signature_writer);
+            signature_file.commit()?;
+        }
+
+        let current = read_version(to)?;
+        let delta = generate_diff(&current, &signature, block_size as usize);
+        let delta_path = output_dir.join(format!("{:04}.delta", position));
+        let mut delta_file = AtomicOutput::create(&delta_path, output_options)?;
+        let mut delta_writer = BufWriter::new(delta_file.file());
+        write_delta(&delta, &mut delta_writer)?;
+        delta_writer.flush()?;
+        drop(delta_writer);
+        delta_file.commit()?;
+
+        links.push(ChainLink {
+            delta_path,
+            from: versions[position - 1].clone(),
+            to: to.clone(),
+        });
+        previous = current;
+    }
+
+    let mut manifest =
+        AtomicOutput::create(&output_dir.join(CHAIN_MANIFEST_FILE_NAME), output_options)?;
+    write_manifest(&links, manifest.file())?;
+    manifest.commit()?;
+    Ok(links)
+}
+
+fn write_manifest(links: &[ChainLink], manifest_file: &mut File) -> Result<()> {
+    let mut manifest_writer = BufWriter::new(manifest_file);
+    for link in links {
+        let delta_name = link.delta_path.file_name().unwrap_or_default();
+        writeln!(
+            manifest_writer,
+            "{}\t{}\t{}",
+            Path::new(delta_name).display(),
+            link.from.display(),
+            link.to.display()
+        )?;
+    }
+    manifest_writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_diff::read_delta;
+    use crate::handlers::testutil::{reconstruct, temp_path};
+
+    #[test]
+    pub fn test_chain_applies_in_sequence() {
+        let output_dir = temp_path("rolling-hash-chain");
+        std::fs::create_dir_all(&output_dir).unwrap();
+
+        let v1: Vec<u8> = (0..1000u32).map(|i| (i % 97) as u8).collect();
+        let mut v2 = v1.clone();
+        v2.splice(300..300, b"inserted in v2".iter().copied());
+        let mut v3 = v2[100..].to_vec();
+        v3.extend_from_slice(b"appended in v3");
+        let versions: Vec<PathBuf> = [&v1, &v2, &v3]
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                let path = output_dir.join(format!("v{}", index + 1));
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+
+        let links = write_chain(&versions, &output_dir, &OutputOptions::default()).unwrap();
+        assert_eq!(2, links.len());
+        assert!(output_dir.join(CHAIN_SIGNATURE_FILE_NAME).exists());
+
+        let mut reconstructed = v1.clone();
+        for link in &links {
+            let delta = read_delta(&mut read_handler(&link.delta_path).unwrap()).unwrap();
+            let block_size = find_blocksize(reconstructed.len() as u64) as usize;
+            reconstructed = reconstruct(&reconstructed, &delta, block_size);
+        }
+        assert_eq!(v3, reconstructed);
+
+        let manifest = std::fs::read_to_string(output_dir.join(CHAIN_MANIFEST_FILE_NAME)).unwrap();
+        let manifest_deltas: Vec<&str> = manifest
+            .lines()
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        assert_eq!(vec!["0001.delta", "0002.delta"], manifest_deltas);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_chain_manifest_with_spaced_file_names() {
+        let output_dir = temp_path("rolling-hash-chain-spaces");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let versions: Vec<PathBuf> = ["release 1.0", "release 1.1 final"]
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let path = output_dir.join(name);
+                std::fs::write(&path, vec![index as u8; 300]).unwrap();
+                path
+            })
+            .collect();
+
+        write_chain(&versions, &output_dir, &OutputOptions::default()).unwrap();
+        let manifest = std::fs::read_to_string(output_dir.join(CHAIN_MANIFEST_FILE_NAME)).unwrap();
+        let fields: Vec<&str> = manifest.trim_end_matches('\n').split('\t').collect();
+        assert_eq!(
+            vec![
+                "0001.delta",
+                versions[0].to_str().unwrap(),
+                versions[1].to_str().unwrap()
+            ],
+            fields
+        );
+
+        let tabbed = vec![versions[0].clone(), output_dir.join("release\t2")];
+        let error = write_chain(&tabbed, &output_dir, &OutputOptions::default()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_chain_needs_two_versions() {
+        let error = write_chain(
+            &[PathBuf::from("data/old.txt")],
+            &std::env::temp_dir(),
+            &OutputOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+    }
+}
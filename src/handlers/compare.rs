@@ -0,0 +1,397 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Result, Write};
+
+use super::file_diff::{generate_diff, strong_hashes_by_index, VerifyMatch};
+use super::file_io::read_file_to_buffer;
+use super::signature::{read_signature_file, FileChunkSignature};
+use super::window_checksum::rolling_checksum;
+
+// Cells per row of the change map image
+const CHANGE_MAP_WIDTH: usize = 256;
+
+// How much of a new file can be reused from the file a signature was generated from
+#[derive(Debug, PartialEq, Eq)]
+pub struct Similarity {
+    pub new_file_length: u64,
+    pub matched_bytes: u64,
+    // Set when matched_bytes only counts weak hash hits at block boundaries
+    pub approximate: bool,
+}
+
+impl Similarity {
+    // Fraction of the new file that was matched. An empty new file is fully similar
+    pub fn fraction(&self) -> f64 {
+        if self.new_file_length == 0 {
+            1.0
+        } else {
+            self.matched_bytes as f64 / self.new_file_length as f64
+        }
+    }
+}
+
+// Exit code of `compare --exit-code` on failure, the same as a panic's, so it can't be
+// mistaken for a similarity bucket. Other commands exit with 2 on failure
+pub const COMPARE_ERROR_EXIT_CODE: i32 = 101;
+
+// Similarity buckets, reported through the exit code of `compare --exit-code`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimilarityBucket {
+    // Every byte of the new file was matched
+    Identical,
+    // Matched fraction above SimilarityThresholds::similar
+    Similar,
+    // Matched fraction above SimilarityThresholds::partial
+    Partial,
+    Different,
+}
+
+impl SimilarityBucket {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            SimilarityBucket::Identical => 0,
+            SimilarityBucket::Similar => 1,
+            SimilarityBucket::Partial => 2,
+            SimilarityBucket::Different => 3,
+        }
+    }
+}
+
+// Exclusive lower bounds of the matched fraction for the Similar and Partial buckets
+#[derive(Debug, Clone, Copy)]
+pub struct SimilarityThresholds {
+    pub similar: f64,
+    pub partial: f64,
+}
+
+impl Default for SimilarityThresholds {
+    fn default() -> Self {
+        Self {
+            similar: 0.9,
+            partial: 0.5,
+        }
+    }
+}
+
+impl Similarity {
+    pub fn bucket(&self, thresholds: &SimilarityThresholds) -> SimilarityBucket {
+        let fraction = self.fraction();
+        if self.matched_bytes == self.new_file_length {
+            SimilarityBucket::Identical
+        } else if fraction > thresholds.similar {
+            SimilarityBucket::Similar
+        } else if fraction > thresholds.partial {
+            SimilarityBucket::Partial
+        } else {
+            SimilarityBucket::Different
+        }
+    }
+}
+
+// Result of comparing a new file against a signature
+#[derive(Debug, PartialEq, Eq)]
+pub struct Comparison {
+    pub similarity: Similarity,
+    // One cell per block of the new file, in order: true where the block is literal
+    pub changed_blocks: Vec<bool>,
+}
+
+// Lay out a delta as block sized cells: one matched cell per matched block,
+// and as many changed cells as a literal run spans blocks
+pub fn changed_blocks(delta: &[VerifyMatch], block_size: u32) -> Vec<bool> {
+    let mut cells: Vec<bool> = Vec::new();
+    for entry in delta {
+        match entry {
+            VerifyMatch::Match(_) => cells.push(false),
+            VerifyMatch::NoMatch(bytes) => {
+                let literal_blocks = bytes.len().div_ceil(block_size as usize);
+                cells.extend(std::iter::repeat_n(true, literal_blocks));
+            }
+        }
+    }
+    cells
+}
+
+// Exact similarity from a full diff of the new file against the signature
+pub fn compare(new_file_buffer: &[u8], signature: &FileChunkSignature) -> Comparison {
+    let new_file_length = new_file_buffer.len() as u64;
+    let chunk_size = signature.block_chunk_size as usize;
+    let delta = generate_diff(new_file_buffer, signature, chunk_size);
+    let literal_bytes: u64 = delta
+        .iter()
+        .map(|entry| match entry {
+            VerifyMatch::Match(_) => 0,
+            VerifyMatch::NoMatch(bytes) => bytes.len() as u64,
+        })
+        .sum();
+
+    Comparison {
+        similarity: Similarity {
+            new_file_length,
+            matched_bytes: new_file_length - literal_bytes,
+            approximate: false,
+        },
+        changed_blocks: changed_blocks(&delta, signature.block_chunk_size),
+    }
+}
+
+// Approximate similarity counting the blocks of the new file, taken at fixed block boundaries,
+// whose weak checksum is present in the signature. No strong hash is computed and the window
+// never rolls, so this is much faster than a full diff but only a rough gauge:
+// weak hash collisions inflate the count, and content shifted off block boundaries is missed.
+// It does not produce a delta
+pub fn count_weak_matches(new_file_buffer: &[u8], signature: &FileChunkSignature) -> Comparison {
+    let mut matched_bytes = 0u64;
+    let mut changed_blocks: Vec<bool> = Vec::new();
+    for block_chunk in new_file_buffer.chunks(signature.block_chunk_size as usize) {
+        let weak_hit = signature
+            .block_chunk_hashes(&rolling_checksum(signature.rolling_checksum, block_chunk))
+            .is_some();
+        if weak_hit {
+            matched_bytes += block_chunk.len() as u64;
+        }
+        changed_blocks.push(!weak_hit);
+    }
+
+    Comparison {
+        similarity: Similarity {
+            new_file_length: new_file_buffer.len() as u64,
+            matched_bytes,
+            approximate: true,
+        },
+        changed_blocks,
+    }
+}
+
+// Compare a new file against a signature file, exactly or with only the weak checksum
+pub fn compare_files(
+    signature_file: &File,
+    new_file: &File,
+    count_only: bool,
+) -> Result<Comparison> {
+    let signature = read_signature_file(signature_file)?;
+    let new_file_buf = read_file_to_buffer(&mut BufReader::new(new_file))?;
+
+    if count_only {
+        Ok(count_weak_matches(&new_file_buf, &signature))
+    } else {
+        Ok(compare(&new_file_buf, &signature))
+    }
+}
+
+// Offset of the first block of the new file that differs from the block with the same index
+// in the signed file, or None if the files are identical. The new file is read one block
+// at a time and reading stops at the first difference. Blocks are compared in place,
+// so shifted content counts as a difference. A new file ending early differs at its end
+pub fn first_divergence(
+    new_file: &mut impl Read,
+    signature: &FileChunkSignature,
+) -> Result<Option<u64>> {
+    let block_size = signature.block_chunk_size as u64;
+    let mut block: Vec<u8> = Vec::with_capacity(block_size as usize);
+    let mut offset = 0u64;
+    for expected_hash in strong_hashes_by_index(signature) {
+        block.clear();
+        new_file.by_ref().take(block_size).read_to_end(&mut block)?;
+        if expected_hash.is_none_or(|hash| hash != signature.strong_hash_of(&block)) {
+            return Ok(Some(offset));
+        }
+        offset += block.len() as u64;
+    }
+
+    // Anything after the last block of the signed file was added
+    block.clear();
+    let added = new_file.take(1).read_to_end(&mut block)?;
+    Ok((added > 0).then_some(offset))
+}
+
+// first_divergence for a signature file and a new file
+pub fn first_divergence_in_files(signature_file: &File, new_file: &File) -> Result<Option<u64>> {
+    let signature = read_signature_file(signature_file)?;
+    first_divergence(&mut BufReader::new(new_file), &signature)
+}
+
+// Write the change map as a binary PBM image, one pixel per block, rows of up to 256 blocks.
+// Changed blocks are black, matched blocks and the padding of the last row are white
+pub fn write_change_map_pbm(changed_blocks: &[bool], out: &mut impl Write) -> Result<()> {
+    let width = changed_blocks.len().clamp(1, CHANGE_MAP_WIDTH);
+    let height = changed_blocks.len().div_ceil(width);
+    write!(out, "P4\n{} {}\n", width, height)?;
+
+    for row in changed_blocks.chunks(width) {
+        let mut packed = vec![0u8; width.div_ceil(8)];
+        for (column, _) in row.iter().enumerate().filter(|(_, changed)| **changed) {
+            packed[column / 8] |= 0x80 >> (column % 8);
+        }
+        out.write_all(&packed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::signature::get_signature;
+    use std::io::Cursor;
+
+    fn bucket_of(matched_bytes: u64, new_file_length: u64) -> SimilarityBucket {
+        let similarity = Similarity {
+            new_file_length,
+            matched_bytes,
+            approximate: false,
+        };
+        similarity.bucket(&SimilarityThresholds::default())
+    }
+
+    #[test]
+    pub fn test_similarity_bucket_exit_codes() {
+        assert_eq!(0, bucket_of(100, 100).exit_code());
+        assert_eq!(0, bucket_of(0, 0).exit_code());
+        assert_eq!(1, bucket_of(95, 100).exit_code());
+        assert_eq!(2, bucket_of(90, 100).exit_code());
+        assert_eq!(2, bucket_of(51, 100).exit_code());
+        assert_eq!(3, bucket_of(50, 100).exit_code());
+        assert_eq!(3, bucket_of(0, 100).exit_code());
+
+        let strict = SimilarityThresholds {
+            similar: 0.99,
+            partial: 0.9,
+        };
+        let similarity = Similarity {
+            new_file_length: 100,
+            matched_bytes: 95,
+            approximate: false,
+        };
+        assert_eq!(SimilarityBucket::Partial, similarity.bucket(&strict));
+    }
+
+    #[test]
+    pub fn test_compare_buckets() {
+        let block = [3u8, 1, 4, 1, 5, 9, 2, 6].repeat(8);
+        let signature = get_signature(&block, 64);
+        let thresholds = SimilarityThresholds::default();
+
+        let identical = compare(&block, &signature);
+        assert_eq!(
+            SimilarityBucket::Identical,
+            identical.similarity.bucket(&thresholds)
+        );
+        // 192 of 224 bytes matched
+        let mut edited = block.repeat(3);
+        edited.extend([0u8; 32]);
+        let partial = compare(&edited, &signature);
+        assert_eq!(
+            SimilarityBucket::Partial,
+            partial.similarity.bucket(&thresholds)
+        );
+        let different = compare(&[7u8; 64], &signature);
+        assert_eq!(
+            SimilarityBucket::Different,
+            different.similarity.bucket(&thresholds)
+        );
+    }
+
+    #[test]
+    pub fn test_count_weak_matches() {
+        let block_a = [3u8, 1, 4, 1, 5, 9, 2, 6].repeat(8);
+        let block_b = [8u8; 64];
+        let signature = get_signature(&block_a, 64);
+        let new_file_buffer = [&block_a[..], &block_a, &block_b, &block_a].concat();
+
+        let approximate = count_weak_matches(&new_file_buffer, &signature);
+        assert!(approximate.similarity.approximate);
+        assert_eq!(192, approximate.similarity.matched_bytes);
+        assert_eq!(0.75, approximate.similarity.fraction());
+        assert_eq!(vec![false, false, true, false], approximate.changed_blocks);
+
+        let exact = compare(&new_file_buffer, &signature);
+        assert!(!exact.similarity.approximate);
+        assert_eq!(0.75, exact.similarity.fraction());
+        assert_eq!(vec![false, false, true, false], exact.changed_blocks);
+    }
+
+    #[test]
+    pub fn test_count_weak_matches_is_inflated_by_collisions() {
+        // [1, 0, 0, 1] and [0, 1, 1, 0] share a weak hash but not a strong hash
+        let signature = get_signature(&[1, 0, 0, 1], 4);
+        let new_file_buffer = vec![0, 1, 1, 0];
+
+        let approximate = count_weak_matches(&new_file_buffer, &signature);
+        assert_eq!(1.0, approximate.similarity.fraction());
+        let exact = compare(&new_file_buffer, &signature);
+        assert_eq!(0.0, exact.similarity.fraction());
+    }
+
+    #[test]
+    pub fn test_changed_blocks_tally() {
+        let delta = vec![
+            VerifyMatch::Match(0),
+            VerifyMatch::NoMatch(vec![0; 65]),
+            VerifyMatch::Match(1),
+            VerifyMatch::NoMatch(vec![0; 3]),
+        ];
+        assert_eq!(
+            vec![false, true, true, false, true],
+            changed_blocks(&delta, 64)
+        );
+    }
+
+    #[test]
+    pub fn test_change_map_pbm() {
+        // 300 blocks: the first row is full, the second holds the remaining 44 blocks
+        let mut changed_blocks = vec![false; 300];
+        for index in [0, 9, 255, 256, 299] {
+            changed_blocks[index] = true;
+        }
+        let mut image: Vec<u8> = Vec::new();
+        write_change_map_pbm(&changed_blocks, &mut image).unwrap();
+
+        let header = b"P4\n256 2\n";
+        assert_eq!(header, &image[..header.len()]);
+        let pixels = &image[header.len()..];
+        assert_eq!(2 * 256 / 8, pixels.len());
+
+        let black_pixels: u32 = pixels.iter().map(|byte| byte.count_ones()).sum();
+        assert_eq!(5, black_pixels);
+        assert_eq!(0b1000_0000, pixels[0]);
+        assert_eq!(0b0100_0000, pixels[1]);
+        assert_eq!(0b0000_0001, pixels[31]);
+        assert_eq!(0b1000_0000, pixels[32]);
+        assert_eq!(0b0001_0000, pixels[37]);
+    }
+
+    #[test]
+    pub fn test_first_divergence() {
+        let old_file: Vec<u8> = (0..200u32).map(|i| (i * 13) as u8).collect();
+        let signature = get_signature(&old_file, 64);
+        let divergence = |new_file: &[u8]| {
+            let mut reader = Cursor::new(new_file);
+            let offset = first_divergence(&mut reader, &signature).unwrap();
+            (offset, reader.position())
+        };
+
+        assert_eq!((None, 200), divergence(&old_file));
+
+        // Reading stops with the block holding the first difference
+        let mut edited = old_file.clone();
+        edited[130] ^= 0xff;
+        edited[190] ^= 0xff;
+        assert_eq!((Some(128), 192), divergence(&edited));
+
+        assert_eq!(Some(128), divergence(&old_file[..150]).0);
+        assert_eq!(Some(192), divergence(&old_file[..192]).0);
+        // Bytes appended to the short last block change that block
+        assert_eq!(Some(192), divergence(&[&old_file[..], b"tail"].concat()).0);
+        assert_eq!(
+            Some(192),
+            divergence(&[&old_file[..192], b"tail"].concat()).0
+        );
+
+        let whole_blocks = get_signature(&old_file[..128], 64);
+        let mut appended = Cursor::new(&old_file[..]);
+        assert_eq!(
+            Some(128),
+            first_divergence(&mut appended, &whole_blocks).unwrap()
+        );
+        assert_eq!(Some(0), divergence(&[b"head", &old_file[..]].concat()).0);
+    }
+}
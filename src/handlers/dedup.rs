@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, Result};
+
+use super::file_io::read_file_to_buffer;
+use super::signature::chunk_sha256_hash;
+use super::window_checksum::validate_block_size;
+
+// Intra-file redundancy of a buffer split into fixed size blocks
+#[derive(Debug, PartialEq, Eq)]
+pub struct DedupReport {
+    pub block_size: u32,
+    pub total_blocks: u64,
+    pub unique_blocks: u64,
+    pub duplicate_blocks: u64,
+    // Bytes saved if every duplicated block was stored only once
+    pub bytes_saved: u64,
+}
+
+// Count unique and duplicated blocks by their SHA 256 hash
+pub fn self_dedup_report(buffer: &[u8], block_size: u32) -> Result<DedupReport> {
+    validate_block_size(block_size)?;
+
+    let mut seen_hashes: HashSet<[u8; 32]> = HashSet::new();
+    let mut report = DedupReport {
+        block_size,
+        total_blocks: 0,
+        unique_blocks: 0,
+        duplicate_blocks: 0,
+        bytes_saved: 0,
+    };
+
+    for block_chunk in buffer.chunks(block_size as usize) {
+        report.total_blocks += 1;
+        if seen_hashes.insert(chunk_sha256_hash(block_chunk)) {
+            report.unique_blocks += 1;
+        } else {
+            report.duplicate_blocks += 1;
+            report.bytes_saved += block_chunk.len() as u64;
+        }
+    }
+    Ok(report)
+}
+
+// Report intra-file redundancy of the input file
+pub fn self_dedup_file(input_file: &File, block_size: u32) -> Result<DedupReport> {
+    let input_file_buf = read_file_to_buffer(&mut BufReader::new(input_file))?;
+    self_dedup_report(&input_file_buf, block_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    pub fn test_self_dedup_report() {
+        let block_a = [1u8; 16];
+        let block_b = [2u8; 16];
+        let buffer = [
+            &block_a[..],
+            &block_b,
+            &block_a,
+            &block_a,
+            &block_b,
+            &[1u8; 5],
+        ]
+        .concat();
+
+        let report = self_dedup_report(&buffer, 16).unwrap();
+        assert_eq!(
+            DedupReport {
+                block_size: 16,
+                total_blocks: 6,
+                unique_blocks: 3,
+                duplicate_blocks: 3,
+                bytes_saved: 48,
+            },
+            report
+        );
+    }
+
+    #[test]
+    pub fn test_self_dedup_rejects_zero_block_size() {
+        let err = self_dedup_report(&[1, 2, 3], 0).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::io;
+
+use super::window_checksum::MAX_BLOCK_SIZE;
+
+// Error of the signature, diff and patch file operations.
+// Functions returning std::io::Result convert it back with `?`, keeping the kind of
+// I/O errors and reporting everything else as ErrorKind::InvalidData
+#[derive(Debug)]
+pub enum RollingHashError {
+    Io(io::Error),
+    // A signature or delta file that isn't a valid bincode encoding
+    Deserialize(bincode::Error),
+    Serialize(bincode::Error),
+    // A signature that deserializes but can't have been written by this tool
+    InvalidSignature(String),
+    // A file given as a signature without the signature header, with the kind of file it
+    // is instead when that is recognised
+    NotASignature(Option<&'static str>),
+    // A delta matching blocks the signed file doesn't have
+    InvalidDelta(String),
+    // A block size of zero or above MAX_BLOCK_SIZE
+    InvalidBlockSize(u32),
+    // An old file which isn't the file its signature was generated from
+    OldFileMismatch(String),
+}
+
+impl fmt::Display for RollingHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollingHashError::Io(error) => write!(f, "{}", error),
+            RollingHashError::Deserialize(error) => {
+                write!(f, "corrupt or truncated file: {}", error)
+            }
+            RollingHashError::Serialize(error) => write!(f, "cannot write file: {}", error),
+            RollingHashError::InvalidSignature(reason) => {
+                write!(f, "invalid signature: {}", reason)
+            }
+            RollingHashError::NotASignature(Some(kind)) => {
+                write!(f, "invalid signature: not a signature file but {}", kind)
+            }
+            RollingHashError::NotASignature(None) => {
+                write!(
+                    f,
+                    "invalid signature: not a signature file (no RHSG header)"
+                )
+            }
+            RollingHashError::InvalidDelta(reason) => write!(f, "invalid delta: {}", reason),
+            RollingHashError::InvalidBlockSize(block_size) => write!(
+                f,
+                "invalid block size {}: must be between 1 and {}",
+                block_size, MAX_BLOCK_SIZE
+            ),
+            RollingHashError::OldFileMismatch(reason) => {
+                write!(f, "old file does not match the signature: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RollingHashError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RollingHashError::Io(error) => Some(error),
+            RollingHashError::Deserialize(error) | RollingHashError::Serialize(error) => {
+                Some(error)
+            }
+            RollingHashError::InvalidSignature(_)
+            | RollingHashError::NotASignature(_)
+            | RollingHashError::InvalidDelta(_)
+            | RollingHashError::InvalidBlockSize(_)
+            | RollingHashError::OldFileMismatch(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for RollingHashError {
+    fn from(error: io::Error) -> Self {
+        RollingHashError::Io(error)
+    }
+}
+
+// A bincode error is a deserialization error unless the underlying read failed.
+// Running out of input is a truncated file, not a failed read.
+// Serialization errors are wrapped explicitly with RollingHashError::Serialize
+impl From<bincode::Error> for RollingHashError {
+    fn from(error: bincode::Error) -> Self {
+        match *error {
+            bincode::ErrorKind::Io(io_error) if io_error.kind() != io::ErrorKind::UnexpectedEof => {
+                RollingHashError::Io(io_error)
+            }
+            _ => RollingHashError::Deserialize(error),
+        }
+    }
+}
+
+impl From<RollingHashError> for io::Error {
+    fn from(error: RollingHashError) -> Self {
+        match error {
+            RollingHashError::Io(io_error) => io_error,
+            RollingHashError::InvalidBlockSize(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, error)
+            }
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_diff::{write_delta, write_diff_file, DiffOptions, VerifyMatch};
+    use crate::handlers::file_io::{ReadOptions, RetryPolicy};
+    use crate::handlers::inspect::read_delta_file;
+    use crate::handlers::signature::{
+        get_signature, read_signature, read_signature_file, write_signature, write_signature_file,
+        SignatureOptions,
+    };
+    use crate::handlers::testutil::{empty_signature, temp_path};
+    use bincode::serialized_size;
+    use std::io::Read;
+
+    #[test]
+    pub fn test_corrupt_signature_is_an_error() {
+        let path = temp_path("rolling-hash-corrupt-signature");
+
+        // Map length claiming more entries than the file holds, after a current header
+        let mut truncated = Vec::new();
+        write_signature(&get_signature(&[], 64), &mut truncated).unwrap();
+        truncated.truncate(5);
+        truncated.extend_from_slice(&[64, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        std::fs::write(&path, truncated).unwrap();
+        let error = read_signature_file(&std::fs::File::open(&path).unwrap()).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::Deserialize(_)),
+            "{:?}",
+            error
+        );
+        assert_eq!(io::ErrorKind::InvalidData, io::Error::from(error).kind());
+
+        // Block size no signature is written with
+        let signature = empty_signature(0);
+        write_signature(&signature, &mut std::fs::File::create(&path).unwrap()).unwrap();
+        let error = read_signature_file(&std::fs::File::open(&path).unwrap()).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::InvalidSignature(_)),
+            "{:?}",
+            error
+        );
+        assert_eq!(io::ErrorKind::InvalidData, io::Error::from(error).kind());
+
+        // Entry beyond the recorded block count
+        let mut signature = get_signature(&[1u8; 100], 64);
+        signature.checksum_map.values_mut().next().unwrap()[0].index = 2;
+        write_signature(&signature, &mut std::fs::File::create(&path).unwrap()).unwrap();
+        let error = read_signature_file(&std::fs::File::open(&path).unwrap()).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::InvalidSignature(_)),
+            "{:?}",
+            error
+        );
+
+        // Delta of one entry of an unknown kind, after a current header
+        let mut delta = Vec::new();
+        write_delta(&[VerifyMatch::Match(0)], &mut delta).unwrap();
+        delta.truncate(delta.len() - serialized_size(&VerifyMatch::Match(0)).unwrap() as usize);
+        delta.extend_from_slice(&[7, 0, 0, 0]);
+        std::fs::write(&path, delta).unwrap();
+        let error = read_delta_file(&std::fs::File::open(&path).unwrap()).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::Deserialize(_)),
+            "{:?}",
+            error
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn test_io_error_reaches_handler_caller() {
+        let old_path = temp_path("rolling-hash-write-only-old");
+        let new_path = temp_path("rolling-hash-write-only-new");
+        let output_path = temp_path("rolling-hash-write-only-output");
+        std::fs::write(&old_path, [1u8; 200]).unwrap();
+        std::fs::write(&new_path, [2u8; 200]).unwrap();
+        // Files opened for writing only, so that reading them fails
+        let write_only =
+            |path: &std::path::Path| std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let read_error_kind = (&write_only(&old_path))
+            .read(&mut [0u8; 1])
+            .unwrap_err()
+            .kind();
+
+        let error = write_signature_file(
+            &write_only(&old_path),
+            &mut std::fs::File::create(&output_path).unwrap(),
+            &RetryPolicy::default(),
+            &SignatureOptions::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&error, RollingHashError::Io(io_error) if io_error.kind() == read_error_kind),
+            "{:?}",
+            error
+        );
+
+        let signature_path = temp_path("rolling-hash-write-only-signature");
+        write_signature(
+            &get_signature(&[1u8; 200], 64),
+            &mut std::fs::File::create(&signature_path).unwrap(),
+        )
+        .unwrap();
+        let error = write_diff_file(
+            &std::fs::File::open(&signature_path).unwrap(),
+            &write_only(&new_path),
+            &mut std::fs::File::create(&output_path).unwrap(),
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&error, RollingHashError::Io(io_error) if io_error.kind() == read_error_kind),
+            "{:?}",
+            error
+        );
+        // The original kind also survives conversion back to io::Error
+        assert_eq!(read_error_kind, io::Error::from(error).kind());
+
+        // A reader failing with ErrorKind::Other
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("device gone"))
+            }
+        }
+        let error = read_signature(&mut FailingReader).unwrap_err();
+        assert!(
+            matches!(&error, RollingHashError::Io(io_error) if io_error.kind() == io::ErrorKind::Other),
+            "{:?}",
+            error
+        );
+
+        for path in [old_path, new_path, output_path, signature_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
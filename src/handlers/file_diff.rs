@@ -1,153 +1,3255 @@
+use std::cell::RefCell;
 use std::cmp::PartialEq;
+#[cfg(feature = "parallel")]
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Result};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::mem::size_of;
+#[cfg(feature = "parallel")]
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use bincode::{deserialize_from, serialize_into};
+use bincode::{deserialize_from, serialize_into, serialized_size};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
-use super::file_io::read_file_to_buffer;
+use super::error::RollingHashError;
+use super::file_io::{
+    is_stream, read_file_to_buffer_with_retry, read_handler, read_stream_to_buffer, AtomicOutput,
+    OutputOptions, ReadOptions,
+};
+use super::progress::ProgressReporter;
 use super::signature::{
-    chunk_sha256_hash, pointer_at_last_chunk, BlockChunkHashes, FileChunkSignature,
+    find_blocksize, get_signature, pointer_at_last_chunk, read_signature_file, BlockChunkHashes,
+    FileChunkSignature,
+};
+use super::window_checksum::{
+    validate_block_size, Adler32Window, RollingChecksum, RollingChecksumAlgorithm, RollingWindow,
+    WidePrimeWindow,
 };
-use super::window_checksum::RollingWindow;
 
+// One entry of a delta. More kinds of entries may be added, so code outside this crate
+// should use is_match/matched_block/literal_bytes or a DeltaVisitor rather than
+// matching on the variants
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum VerifyMatch {
-    Match(u32),
+    #[serde(rename = "match")]
+    Match(u64),
+    #[serde(rename = "literal")]
     NoMatch(Vec<u8>),
 }
 
-// Generate diff file based on signature file and contents of modified text file
-pub fn write_diff_file(signature_file: &File, new_file: &File, diff_file: &mut File) -> Result<()> {
-    let signature_buf = BufReader::new(signature_file);
-    let signature: FileChunkSignature = deserialize_from(signature_buf).unwrap();
-    let chunk_size = signature.block_chunk_size as usize;
-    let mut new_file_reader = BufReader::new(new_file);
-    let mut file_buf = read_file_to_buffer(&mut new_file_reader)?;
+// Entry of a delta whose literal runs are slices of the new file buffer, as the scan finds
+// them. Serializes exactly as the VerifyMatch it stands for, so a delta is written without
+// copying its literals
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum DeltaEntry<'a> {
+    #[serde(rename = "match")]
+    Match(u64),
+    #[serde(rename = "literal")]
+    Literal(&'a [u8]),
+}
 
-    let diff = generate_diff(&mut file_buf, &signature, chunk_size);
+impl DeltaEntry<'_> {
+    // The entry with its literal bytes copied out of the new file buffer
+    pub fn into_owned(self) -> VerifyMatch {
+        match self {
+            DeltaEntry::Match(index) => VerifyMatch::Match(index),
+            DeltaEntry::Literal(bytes) => VerifyMatch::NoMatch(bytes.to_vec()),
+        }
+    }
+}
 
-    let mut diff_writer = BufWriter::new(diff_file);
-    serialize_into(&mut diff_writer, &diff).unwrap();
+impl<'a> From<&'a VerifyMatch> for DeltaEntry<'a> {
+    fn from(entry: &'a VerifyMatch) -> Self {
+        match entry {
+            VerifyMatch::Match(index) => DeltaEntry::Match(*index),
+            VerifyMatch::NoMatch(bytes) => DeltaEntry::Literal(bytes),
+        }
+    }
+}
 
-    Ok(())
+// Receives the entries of a delta as copies of old blocks and literal runs.
+// Entry kinds added later are passed on as a sequence of these two calls
+pub trait DeltaVisitor {
+    fn visit_match(&mut self, index: u64);
+    fn visit_literal(&mut self, bytes: &[u8]);
 }
 
-fn match_index_and_checksum<'a>(
-    signature: &'a FileChunkSignature,
-    index_hash: u32,
-    chunk: &[u8],
-) -> Option<&'a BlockChunkHashes> {
-    if let Some(hashes) = signature.block_chunk_hashes(&index_hash) {
-        let sha256_checksum_hash = chunk_sha256_hash(chunk);
-        hashes.iter().find(|h| h.hash == sha256_checksum_hash)
-    } else {
-        None
+impl VerifyMatch {
+    pub fn is_match(&self) -> bool {
+        self.matched_block().is_some()
+    }
+
+    // Index of the old block this entry copies
+    pub fn matched_block(&self) -> Option<u64> {
+        match self {
+            VerifyMatch::Match(index) => Some(*index),
+            VerifyMatch::NoMatch(_) => None,
+        }
+    }
+
+    // Bytes this entry stores literally
+    pub fn literal_bytes(&self) -> Option<&[u8]> {
+        match self {
+            VerifyMatch::Match(_) => None,
+            VerifyMatch::NoMatch(bytes) => Some(bytes),
+        }
+    }
+
+    pub fn accept(&self, visitor: &mut impl DeltaVisitor) {
+        match self {
+            VerifyMatch::Match(index) => visitor.visit_match(*index),
+            VerifyMatch::NoMatch(bytes) => visitor.visit_literal(bytes),
+        }
     }
 }
 
-// Generates diff based on for file buffer, signature file and file chunk size
-pub fn generate_diff(
-    new_file_buffer: &mut Vec<u8>,
-    signature: &FileChunkSignature,
-    chunk_size: usize,
-) -> Vec<VerifyMatch> {
-    let mut match_verifier: Vec<VerifyMatch> = Vec::new();
-    loop {
-        // De-structure vector buffer to array chunk
-        let chunk = if chunk_size <= new_file_buffer.len() {
-            &new_file_buffer[..chunk_size]
-        } else {
-            &new_file_buffer[..new_file_buffer.len()]
-        };
+// Pass every entry of a delta to the visitor, in order
+pub fn visit_delta(delta: &[VerifyMatch], visitor: &mut impl DeltaVisitor) {
+    delta.iter().for_each(|entry| entry.accept(visitor));
+}
 
-        let mut actual_chunk_size = chunk.len();
-        if actual_chunk_size == 0 {
-            break;
+// What to do when a window's weak hash is found in the signature
+// but none of the strong hashes stored under it match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    // Compute the strong hash and keep rolling on a mismatch
+    Verify,
+    // Treat every weak hit as a mismatch without computing the strong hash.
+    // Unsafe: only useful to profile the cost of the weak hash alone
+    Skip,
+    // Verify, but fail once collisions exceed the allowed rate
+    Error,
+}
+
+// How the block a Match entry copies is numbered in a delta file. Deltas in memory always
+// hold 0-based indices; the base only changes the serialized value, for external appliers
+// with their own convention, and is recorded in the header so read_delta converts back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BlockIndexBase {
+    // Index of the block, the first block being 0
+    Zero,
+    // Index of the block, the first block being 1
+    One,
+    // Byte offset of the block in the old file
+    Offset,
+}
+
+impl BlockIndexBase {
+    fn tag(self) -> u8 {
+        match self {
+            BlockIndexBase::Zero => 0,
+            BlockIndexBase::One => 1,
+            BlockIndexBase::Offset => 2,
         }
+    }
 
-        // Calculate rolling window check-sum hash
-        let mut rolling_sum = RollingWindow::generate();
-        rolling_sum.add_bytes_at_end(chunk);
-        let index_hash = rolling_sum.sha256_digest();
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(BlockIndexBase::Zero),
+            1 => Some(BlockIndexBase::One),
+            2 => Some(BlockIndexBase::Offset),
+            _ => None,
+        }
+    }
 
-        // Verify if checksum of pattern and current window matches.
-        // If these two checksums don't match, move the window
-        if let Some(hash) = match_index_and_checksum(signature, index_hash, chunk) {
-            match_verifier.push(VerifyMatch::Match(hash.index));
+    fn name(self) -> &'static str {
+        match self {
+            BlockIndexBase::Zero => "0-based",
+            BlockIndexBase::One => "1-based",
+            BlockIndexBase::Offset => "byte offset",
+        }
+    }
 
-            if pointer_at_last_chunk(actual_chunk_size, new_file_buffer.len()) {
-                break;
-            }
-            // Prepare buffer for next iteration
-            new_file_buffer.drain(..actual_chunk_size);
-            continue;
+    // Serialized value of the 0-based block index
+    fn encode(self, index: u64, block_size: u32) -> Option<u64> {
+        match self {
+            BlockIndexBase::Zero => Some(index),
+            BlockIndexBase::One => index.checked_add(1),
+            BlockIndexBase::Offset => index.checked_mul(block_size as u64),
         }
+    }
 
-        // In case the checksum of pattern and current window doesn't match,
-        // run rolling window
-        let mut diff_bytes: Vec<u8> = Vec::new();
-        loop {
-            let mut buf_len = new_file_buffer.len();
-            let mut next: Option<u8> = None;
-            if !pointer_at_last_chunk(actual_chunk_size, buf_len) {
-                next = Some(new_file_buffer[chunk_size]);
-            }
-            if buf_len > 0 {
-                let prev = new_file_buffer.remove(0);
-                buf_len = new_file_buffer.len();
-                diff_bytes.push(prev);
-                rolling_sum.roll_window(prev, next);
-                let index_hash = rolling_sum.sha256_digest();
-                let chunk = if chunk_size < buf_len {
-                    &new_file_buffer[..chunk_size]
-                } else {
-                    &new_file_buffer[..buf_len]
-                };
-                actual_chunk_size = chunk.len();
+    // 0-based block index of a serialized value
+    fn decode(self, value: u64, block_size: u32) -> Option<u64> {
+        match self {
+            BlockIndexBase::Zero => Some(value),
+            BlockIndexBase::One => value.checked_sub(1),
+            BlockIndexBase::Offset => (block_size > 0 && value.is_multiple_of(block_size as u64))
+                .then(|| value / block_size as u64),
+        }
+    }
+}
 
-                if let Some(hash) = match_index_and_checksum(signature, index_hash, chunk) {
-                    match_verifier.push(VerifyMatch::NoMatch(diff_bytes));
-                    match_verifier.push(VerifyMatch::Match(hash.index));
+// Size of the reads of a streamed new file diffed against itself
+const SELF_DIFF_READ_BLOCK_SIZE: usize = 64 * 1024;
 
-                    new_file_buffer.drain(..actual_chunk_size);
-                    break;
+// Options controlling generate_diff_with_options
+#[derive(Clone, Copy)]
+pub struct DiffOptions<'a> {
+    pub collision_policy: CollisionPolicy,
+    // Collision rate (collisions per scanned window) tolerated by CollisionPolicy::Error
+    pub max_collision_rate: f64,
+    // Checked after every matched block and every window roll.
+    // Once set, the scan stops and the delta produced so far is returned
+    pub cancel: Option<&'a AtomicBool>,
+    // At block aligned positions of the new file, first check the strong hash of the old block
+    // with the same index before looking up the weak hash. Speeds up files edited in place.
+    // Where the old file has duplicate blocks, the aligned one is preferred
+    pub position_hints: bool,
+    // Receives the rolling window state of every window scanned, one line each.
+    // Diagnostic only; nothing is formatted when unset
+    pub dump_rolling_state: Option<&'a RefCell<dyn Write + 'a>>,
+    // Post-pass inlining short matches whose entries cost more than their bytes
+    pub optimize: bool,
+    // Split literal runs into entries of at most this many bytes, flushing them as the scan
+    // goes, so a long unmatched region isn't held as one allocation
+    pub max_literal_run: Option<usize>,
+    // Post-pass folding matches into literals unless they are part of a run of at least this
+    // many consecutive Match entries, so isolated short matches don't fragment the delta
+    pub min_match_blocks: Option<usize>,
+    // Compute the strong hashes of windows with a weak hash hit ahead of the scan, on up to
+    // this many threads. 0 and 1 hash each window as the scan reaches it. Some windows are
+    // hashed for nothing, as the scan skips those inside matched blocks; the delta is the same
+    pub strong_hash_threads: usize,
+    // Numbering of matched blocks in the delta files written
+    pub block_index_base: BlockIndexBase,
+    // Called with the bytes of the new file scanned so far and its length, every
+    // PROGRESS_INTERVAL_BLOCKS blocks and once the scan is complete
+    pub progress: Option<&'a RefCell<dyn FnMut(u64, u64) + 'a>>,
+}
+
+impl Default for DiffOptions<'_> {
+    fn default() -> Self {
+        Self {
+            collision_policy: CollisionPolicy::Verify,
+            max_collision_rate: 0.05,
+            cancel: None,
+            position_hints: false,
+            dump_rolling_state: None,
+            optimize: false,
+            max_literal_run: None,
+            min_match_blocks: None,
+            strong_hash_threads: 1,
+            block_index_base: BlockIndexBase::Zero,
+            progress: None,
+        }
+    }
+}
+
+// Delta produced by generate_diff_with_options, or by generate_diff_borrowed with entries
+// borrowing from the new file buffer
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiffOutcome<E = VerifyMatch> {
+    pub delta: Vec<E>,
+    // False when the scan was cancelled. The delta is still valid, but it only
+    // reconstructs a prefix of the new file
+    pub complete: bool,
+    // Number of windows whose weak hash was looked up in the signature
+    pub weak_lookups: u64,
+    // Number of windows whose strong hash was computed, at most one per window position
+    pub strong_hashes: u64,
+}
+
+impl DiffOutcome<DeltaEntry<'_>> {
+    // The outcome with its literal runs copied out of the new file buffer
+    pub fn into_owned(self) -> DiffOutcome {
+        DiffOutcome {
+            delta: self.delta.into_iter().map(DeltaEntry::into_owned).collect(),
+            complete: self.complete,
+            weak_lookups: self.weak_lookups,
+            strong_hashes: self.strong_hashes,
+        }
+    }
+}
+
+// Delta file layout:
+//   magic "RHDL", format version (1 byte)
+//   block index base (1 byte: 0 zero, 1 one, 2 offset)
+//   block size (u32, little endian), which offset based Match values are multiples of
+//   entry count (u64, little endian)
+//   that many bincode serialized VerifyMatch entries
+// The count is checked against the entries decoded, so a truncated delta is told apart
+// from a corrupt one
+pub(crate) const DELTA_MAGIC: &[u8; 4] = b"RHDL";
+const DELTA_VERSION: u8 = 1;
+// Magic, version, index base and block size. The entry count is counted with the entries,
+// like the length prefix of the Vec serialized_size measures
+const DELTA_HEADER_SIZE: u64 = DELTA_MAGIC.len() as u64 + 1 + 1 + 4;
+
+// Write a delta with its header, numbering matched blocks from 0
+pub fn write_delta(
+    delta: &[VerifyMatch],
+    out: &mut impl Write,
+) -> std::result::Result<(), RollingHashError> {
+    write_delta_with_base(delta, BlockIndexBase::Zero, 0, out)
+}
+
+// Write a delta with its header, numbering matched blocks of block_size bytes by base.
+// block_size is only needed by BlockIndexBase::Offset
+pub fn write_delta_with_base(
+    delta: &[VerifyMatch],
+    base: BlockIndexBase,
+    block_size: u32,
+    out: &mut impl Write,
+) -> std::result::Result<(), RollingHashError> {
+    write_delta_entries(delta.iter().map(DeltaEntry::from), base, block_size, out)
+}
+
+// write_delta_with_base for entries borrowing their literal runs, such as those of
+// generate_diff_borrowed. The file is the same as for the owned entries
+pub fn write_delta_entries<'a>(
+    delta: impl ExactSizeIterator<Item = DeltaEntry<'a>>,
+    base: BlockIndexBase,
+    block_size: u32,
+    out: &mut impl Write,
+) -> std::result::Result<(), RollingHashError> {
+    if base == BlockIndexBase::Offset {
+        validate_block_size(block_size)?;
+    }
+    out.write_all(DELTA_MAGIC)?;
+    out.write_all(&[DELTA_VERSION, base.tag()])?;
+    out.write_all(&block_size.to_le_bytes())?;
+    out.write_all(&(delta.len() as u64).to_le_bytes())?;
+    for entry in delta {
+        let entry = match entry {
+            DeltaEntry::Match(index) => {
+                DeltaEntry::Match(base.encode(index, block_size).ok_or_else(|| {
+                    RollingHashError::InvalidDelta(format!(
+                        "block {} has no {} block number",
+                        index,
+                        base.name()
+                    ))
+                })?)
+            }
+            literal => literal,
+        };
+        serialize_into(&mut *out, &entry).map_err(RollingHashError::Serialize)?;
+    }
+    Ok(())
+}
+
+// Decode the entry count of a delta and that many entries, failing with the number of
+// entries decoded when the input ends before the last one. Matched blocks are converted
+// from base to 0-based indices
+fn read_delta_entries(
+    input: &mut impl Read,
+    base: BlockIndexBase,
+    block_size: u32,
+) -> std::result::Result<Vec<VerifyMatch>, RollingHashError> {
+    let truncated = |decoded: u64, declared: u64| {
+        RollingHashError::InvalidDelta(format!(
+            "truncated delta: {} of {} declared entries decoded",
+            decoded, declared
+        ))
+    };
+    let mut count = [0u8; 8];
+    input
+        .read_exact(&mut count)
+        .map_err(|error| match error.kind() {
+            ErrorKind::UnexpectedEof => {
+                RollingHashError::InvalidDelta("file is too short for a delta".to_string())
+            }
+            _ => RollingHashError::Io(error),
+        })?;
+    let declared = u64::from_le_bytes(count);
+    // A corrupt count mustn't allocate more than the entries decoded need
+    let mut delta = Vec::with_capacity(declared.min(1024) as usize);
+    for decoded in 0..declared {
+        match deserialize_from(&mut *input) {
+            Ok(entry) => delta.push(match entry {
+                VerifyMatch::Match(value) => {
+                    VerifyMatch::Match(base.decode(value, block_size).ok_or_else(|| {
+                        RollingHashError::InvalidDelta(format!(
+                            "{} is not a {} block number for blocks of {} bytes",
+                            value,
+                            base.name(),
+                            block_size
+                        ))
+                    })?)
                 }
-            } else {
-                if !diff_bytes.is_empty() {
-                    match_verifier.push(VerifyMatch::NoMatch(diff_bytes));
+                literal => literal,
+            }),
+            Err(error) => match *error {
+                bincode::ErrorKind::Io(ref io_error)
+                    if io_error.kind() == ErrorKind::UnexpectedEof =>
+                {
+                    return Err(truncated(decoded, declared))
                 }
-                break;
+                _ => return Err(error.into()),
+            },
+        }
+    }
+    Ok(delta)
+}
+
+// Block numbering recorded in a delta's header. block_size is 0 when the writer didn't
+// know the block size, as write_delta doesn't
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaNumbering {
+    pub base: BlockIndexBase,
+    pub block_size: u32,
+}
+
+// Read a delta written by write_delta. A file without the header, such as a signature
+// or a delta written before the header was introduced, is rejected
+pub fn read_delta(
+    input: &mut impl Read,
+) -> std::result::Result<Vec<VerifyMatch>, RollingHashError> {
+    Ok(read_delta_with_numbering(input)?.1)
+}
+
+// Like read_delta, also returning how the delta file numbered its matched blocks
+pub fn read_delta_with_numbering(
+    input: &mut impl Read,
+) -> std::result::Result<(DeltaNumbering, Vec<VerifyMatch>), RollingHashError> {
+    let mut header = [0u8; DELTA_MAGIC.len() + 1];
+    input
+        .read_exact(&mut header)
+        .map_err(|error| match error.kind() {
+            ErrorKind::UnexpectedEof => {
+                RollingHashError::InvalidDelta("file is too short for a delta".to_string())
+            }
+            _ => RollingHashError::Io(error),
+        })?;
+    if &header[..4] != DELTA_MAGIC {
+        return Err(RollingHashError::InvalidDelta(
+            "not a delta file (no RHDL header)".to_string(),
+        ));
+    }
+    if header[4] != DELTA_VERSION {
+        return Err(RollingHashError::InvalidDelta(format!(
+            "unsupported delta format version {}",
+            header[4]
+        )));
+    }
+    let mut numbering = [0u8; 5];
+    input
+        .read_exact(&mut numbering)
+        .map_err(|error| match error.kind() {
+            ErrorKind::UnexpectedEof => {
+                RollingHashError::InvalidDelta("file is too short for a delta".to_string())
             }
+            _ => RollingHashError::Io(error),
+        })?;
+    let base = BlockIndexBase::from_tag(numbering[0]).ok_or_else(|| {
+        RollingHashError::InvalidDelta(format!("unknown block index base {}", numbering[0]))
+    })?;
+    let block_size = u32::from_le_bytes(numbering[1..].try_into().unwrap());
+    let delta = read_delta_entries(input, base, block_size)?;
+    Ok((DeltaNumbering { base, block_size }, delta))
+}
+
+// Generate diff file based on signature file and contents of modified text file.
+// A new file which is a stream (e.g. a FIFO) is read until the producer closes it,
+// failing if that takes longer than the read timeout
+pub fn write_diff_file(
+    signature_file: &File,
+    new_file: &File,
+    diff_file: &mut File,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+) -> std::result::Result<DiffStats, RollingHashError> {
+    let signature = read_signature_file(signature_file).map_err(|error| match error {
+        RollingHashError::NotASignature(kind) => RollingHashError::InvalidSignature(format!(
+            "the signature file argument is not a signature file{}; are the signature and new \
+             file arguments swapped?",
+            kind.map_or(" (no RHSG header)".to_string(), |kind| format!(
+                " but {}",
+                kind
+            ))
+        )),
+        error => error,
+    })?;
+    Ok(write_diff_with_signature(
+        &signature,
+        new_file,
+        diff_file,
+        options,
+        read_options,
+    )?)
+}
+
+// Summary of one generated delta
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiffStats {
+    pub new_file_length: u64,
+    pub matched_blocks: u64,
+    // Bytes of the new file copied from matched blocks
+    pub matched_bytes: u64,
+    pub literal_bytes: u64,
+    // Size of the delta file, header included
+    pub delta_size: u64,
+}
+
+impl DiffStats {
+    // Stats of a delta whose new file is known to be new_file_length bytes long,
+    // so a short final matched block is counted exactly
+    pub(crate) fn of(new_file_length: u64, delta: &[VerifyMatch], block_size: u32) -> Self {
+        Self::of_entries(
+            new_file_length,
+            delta.iter().map(DeltaEntry::from),
+            block_size,
+        )
+    }
+
+    fn of_entries<'a>(
+        new_file_length: u64,
+        delta: impl Iterator<Item = DeltaEntry<'a>>,
+        block_size: u32,
+    ) -> Self {
+        let stats = entry_stats(delta, block_size);
+        DiffStats {
+            new_file_length,
+            matched_bytes: new_file_length - stats.literal_bytes,
+            ..stats
+        }
+    }
+
+    // Fraction of the new file copied from the old file
+    pub fn reuse(&self) -> f64 {
+        if self.new_file_length == 0 {
+            0.0
+        } else {
+            self.matched_bytes as f64 / self.new_file_length as f64
+        }
+    }
+
+    // Size of the delta relative to the new file, below 1 when the delta is smaller
+    pub fn compression_ratio(&self) -> f64 {
+        if self.new_file_length == 0 {
+            0.0
+        } else {
+            self.delta_size as f64 / self.new_file_length as f64
         }
     }
-    match_verifier
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::handlers::file_io::read_handler;
-    use std::path::Path;
+// e.g. "matched 412 blocks (210 KiB), 18 KiB literal, 92% reuse"
+impl fmt::Display for DiffStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "matched {} blocks ({}), {} literal, {:.0}% reuse",
+            self.matched_blocks,
+            format_size(self.matched_bytes),
+            format_size(self.literal_bytes),
+            self.reuse() * 100.0
+        )
+    }
+}
 
-    #[test]
-    pub fn test_generate_diff() {
-        let signature_file = read_handler(Path::new("data/signature")).unwrap();
-        let signature_buf = BufReader::new(signature_file);
-        let signature: FileChunkSignature = deserialize_from(signature_buf).unwrap();
-        let chunk_size = signature.block_chunk_size;
+fn format_size(bytes: u64) -> String {
+    const KIB: u64 = 1024;
+    if bytes < KIB {
+        format!("{} B", bytes)
+    } else if bytes < KIB * KIB {
+        format!("{} KiB", (bytes + KIB / 2) / KIB)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (KIB * KIB) as f64)
+    }
+}
 
-        let new_file = read_handler(Path::new("data/new.txt")).unwrap();
-        let mut new_file_reader = BufReader::new(&new_file);
-        let mut buffer = read_file_to_buffer(&mut new_file_reader).unwrap();
+// Matched blocks, matched and literal bytes and delta file size of a delta.
+// Every matched block is counted as block_size bytes, which overcounts a short final
+// block of the old file; new_file_length is the sum of matched and literal bytes
+pub fn diff_stats(delta: &[VerifyMatch], block_size: u32) -> DiffStats {
+    entry_stats(delta.iter().map(DeltaEntry::from), block_size)
+}
+
+fn entry_stats<'a>(delta: impl Iterator<Item = DeltaEntry<'a>>, block_size: u32) -> DiffStats {
+    // The entry count is serialized as a u64 ahead of the entries
+    let mut stats = DiffStats {
+        new_file_length: 0,
+        matched_blocks: 0,
+        matched_bytes: 0,
+        literal_bytes: 0,
+        delta_size: DELTA_HEADER_SIZE + size_of::<u64>() as u64,
+    };
+    for entry in delta {
+        stats.delta_size += serialized_size(&entry).unwrap();
+        match entry {
+            DeltaEntry::Match(_) => {
+                stats.matched_blocks += 1;
+                stats.matched_bytes += block_size as u64;
+            }
+            DeltaEntry::Literal(bytes) => stats.literal_bytes += bytes.len() as u64,
+        }
+    }
+    stats.new_file_length = stats.matched_bytes + stats.literal_bytes;
+    stats
+}
+
+// Delta of a new file's contents against a signature, at the signature's block size.
+// crate::diff_of and write_diff_with_signature both diff through it
+pub fn diff_against_signature(
+    new_file_buffer: &[u8],
+    signature: &FileChunkSignature,
+    options: &DiffOptions,
+) -> Result<Vec<VerifyMatch>> {
+    let chunk_size = signature.block_chunk_size as usize;
+    Ok(generate_diff_with_options(new_file_buffer, signature, chunk_size, options)?.delta)
+}
 
-        let diff = generate_diff(&mut buffer, &signature, chunk_size as usize);
+// Like write_diff_file, for a signature already in memory
+pub fn write_diff_with_signature(
+    signature: &FileChunkSignature,
+    new_file: &File,
+    diff_file: &mut File,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+) -> Result<DiffStats> {
+    validate_block_size(signature.block_chunk_size)?;
+    let chunk_size = signature.block_chunk_size as usize;
+    let file_buf = read_new_file(new_file, chunk_size, read_options)?;
+    let new_file_length = file_buf.len() as u64;
 
-        let expected_diff_file = read_handler(Path::new("data/diff")).unwrap();
-        let expected_diff_reader = BufReader::new(expected_diff_file);
+    // The post-passes rewrite entries, so only a plain scan's literal runs are written
+    // straight from the buffer
+    let owned_diff: Vec<VerifyMatch>;
+    let diff: Vec<DeltaEntry> = if options.optimize || options.min_match_blocks.is_some() {
+        owned_diff = diff_against_signature(&file_buf, signature, options)?;
+        owned_diff.iter().map(DeltaEntry::from).collect()
+    } else {
+        generate_diff_borrowed(&file_buf, signature, chunk_size, options)?.delta
+    };
 
-        let expected_diff: Vec<VerifyMatch> = deserialize_from(expected_diff_reader).unwrap();
+    let mut diff_writer = BufWriter::new(diff_file);
+    write_delta_entries(
+        diff.iter().copied(),
+        options.block_index_base,
+        signature.block_chunk_size,
+        &mut diff_writer,
+    )?;
+    diff_writer.flush()?;
 
-        assert_eq!(expected_diff, diff);
+    Ok(DiffStats::of_entries(
+        new_file_length,
+        diff.into_iter(),
+        signature.block_chunk_size,
+    ))
+}
+
+// Path of the delta written for each new file of a batch, "<file name>.delta" in output_dir
+fn batch_delta_paths(new_files: &[PathBuf], output_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut delta_paths: Vec<PathBuf> = Vec::with_capacity(new_files.len());
+    for new_file_path in new_files {
+        let file_name = new_file_path.file_name().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("not a file: {:?}", new_file_path),
+            )
+        })?;
+        let mut delta_name = file_name.to_os_string();
+        delta_name.push(".delta");
+        let delta_path = output_dir.join(delta_name);
+        if delta_paths.contains(&delta_path) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("more than one new file is named {:?}", file_name),
+            ));
+        }
+        delta_paths.push(delta_path);
+    }
+    Ok(delta_paths)
+}
+
+pub(crate) fn diff_batch_file(
+    signature: &FileChunkSignature,
+    new_file_path: &Path,
+    delta_path: &Path,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+    output_options: &OutputOptions,
+) -> Result<DiffStats> {
+    let new_file = read_handler(new_file_path)?;
+    let mut delta_file = AtomicOutput::create(delta_path, output_options)?;
+    let stats = write_diff_with_signature(
+        signature,
+        &new_file,
+        delta_file.file(),
+        options,
+        read_options,
+    )?;
+    delta_file.commit()?;
+    Ok(stats)
+}
+
+// Diff many new files against one signature, writing "<file name>.delta" for each
+// new file into output_dir. The signature is read once and shared by every diff
+pub fn diff_batch(
+    signature_file: &File,
+    new_files: &[PathBuf],
+    output_dir: &Path,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+    output_options: &OutputOptions,
+) -> Result<Vec<(PathBuf, DiffStats)>> {
+    let delta_paths = batch_delta_paths(new_files, output_dir)?;
+
+    let signature = read_signature_file(signature_file)?;
+    let mut batch_stats = Vec::with_capacity(new_files.len());
+    for (new_file_path, delta_path) in new_files.iter().zip(delta_paths) {
+        let stats = diff_batch_file(
+            &signature,
+            new_file_path,
+            &delta_path,
+            options,
+            read_options,
+            output_options,
+        )?;
+        batch_stats.push((delta_path, stats));
+    }
+    Ok(batch_stats)
+}
+
+// diff_batch with up to parallel_files new files diffed at once, each on its own thread.
+// Every thread holds at most one new file and one delta file open. Results are in the
+// order of new_files, and the first failing file's error is returned once all threads
+// finish. The rolling state dump is not shared across threads and is ignored
+#[cfg(feature = "parallel")]
+pub fn diff_batch_parallel(
+    signature_file: &File,
+    new_files: &[PathBuf],
+    output_dir: &Path,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+    output_options: &OutputOptions,
+    parallel_files: usize,
+) -> Result<Vec<(PathBuf, DiffStats)>> {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    let delta_paths = batch_delta_paths(new_files, output_dir)?;
+
+    let signature = read_signature_file(signature_file)?;
+    // DiffOptions holds the dump writer, which can't be shared, so every thread
+    // rebuilds its options from the fields that can
+    let (
+        collision_policy,
+        max_collision_rate,
+        cancel,
+        position_hints,
+        optimize,
+        max_literal_run,
+        min_match_blocks,
+        strong_hash_threads,
+        block_index_base,
+    ) = (
+        options.collision_policy,
+        options.max_collision_rate,
+        options.cancel,
+        options.position_hints,
+        options.optimize,
+        options.max_literal_run,
+        options.min_match_blocks,
+        options.strong_hash_threads,
+        options.block_index_base,
+    );
+    let next_file = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<DiffStats>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel_files.clamp(1, new_files.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let thread_options = DiffOptions {
+                        collision_policy,
+                        max_collision_rate,
+                        cancel,
+                        position_hints,
+                        dump_rolling_state: None,
+                        optimize,
+                        max_literal_run,
+                        min_match_blocks,
+                        strong_hash_threads,
+                        block_index_base,
+                        progress: None,
+                    };
+                    let mut thread_results = Vec::new();
+                    loop {
+                        let index = next_file.fetch_add(1, Ordering::Relaxed);
+                        if index >= new_files.len() {
+                            return thread_results;
+                        }
+                        let stats = diff_batch_file(
+                            &signature,
+                            &new_files[index],
+                            &delta_paths[index],
+                            &thread_options,
+                            read_options,
+                            output_options,
+                        );
+                        thread_results.push((index, stats));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut batch_stats = Vec::with_capacity(new_files.len());
+    for ((_, stats), delta_path) in results.into_iter().zip(delta_paths) {
+        batch_stats.push((delta_path, stats?));
+    }
+    Ok(batch_stats)
+}
+
+// Sign the new file and diff it against its own signature, as a sanity check
+// of signing and matching. Fails unless every block of the file is matched
+pub fn write_self_diff_file(
+    new_file: &File,
+    diff_file: &mut File,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+) -> Result<DiffStats> {
+    let file_buf = read_new_file(new_file, SELF_DIFF_READ_BLOCK_SIZE, read_options)?;
+
+    let diff = diff_against_self(&file_buf, options)?;
+
+    let block_size = find_blocksize(file_buf.len() as u64);
+    let mut diff_writer = BufWriter::new(diff_file);
+    write_delta_with_base(
+        &diff,
+        options.block_index_base,
+        block_size,
+        &mut diff_writer,
+    )?;
+    diff_writer.flush()?;
+
+    Ok(DiffStats::of(file_buf.len() as u64, &diff, block_size))
+}
+
+// Read the whole new file. A stream is read in blocks of read_block_size
+fn read_new_file(
+    new_file: &File,
+    read_block_size: usize,
+    read_options: &ReadOptions,
+) -> Result<Vec<u8>> {
+    if is_stream(new_file)? {
+        read_stream_to_buffer(new_file, read_block_size, read_options)
+    } else {
+        let mut new_file_reader = BufReader::new(new_file);
+        read_file_to_buffer_with_retry(&mut new_file_reader, &read_options.retry)
+    }
+}
+
+// Diff a buffer against its own signature, with the block size generate-signature would pick.
+// The delta must consist of one match per block; anything else is a bug in signing or matching
+pub fn diff_against_self(
+    new_file_buffer: &[u8],
+    options: &DiffOptions,
+) -> Result<Vec<VerifyMatch>> {
+    let block_size = find_blocksize(new_file_buffer.len() as u64);
+    let block_count = new_file_buffer.len().div_ceil(block_size as usize);
+    let signature = get_signature(new_file_buffer, block_size);
+
+    let delta =
+        generate_diff_with_options(new_file_buffer, &signature, block_size as usize, options)?
+            .delta;
+    let matched_blocks = delta
+        .iter()
+        .filter(|entry| matches!(entry, VerifyMatch::Match(_)))
+        .count();
+    if matched_blocks != block_count || delta.len() != block_count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "diff against own signature matched {} of {} blocks with {} literal runs",
+                matched_blocks,
+                block_count,
+                delta.len() - matched_blocks
+            ),
+        ));
+    }
+    Ok(delta)
+}
+
+// Check that every match of a delta refers to one of the block_count blocks of the old file,
+// so that a corrupt delta is reported before reconstruction starts.
+// The error names the first invalid entry and its position in the delta
+pub fn validate_delta(delta: &[VerifyMatch], block_count: u64) -> Result<()> {
+    let invalid = delta
+        .iter()
+        .enumerate()
+        .find_map(|(position, entry)| match entry {
+            VerifyMatch::Match(index) if *index >= block_count => Some((position, index)),
+            _ => None,
+        });
+    match invalid {
+        Some((position, index)) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "delta entry {} matches block {}, but the old file has {} blocks",
+                position, index, block_count
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Range of delta sizes (in bytes) possible for a new file of a given length
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeltaSizeEstimate {
+    pub new_file_length: u64,
+    pub block_count: u64,
+    // Every block of the new file matched a block of the signature
+    pub best_case: u64,
+    // Nothing matched and the whole new file is stored as a literal
+    pub worst_case: u64,
+    pub match_entry_size: u64,
+    pub literal_entry_overhead: u64,
+}
+
+// Estimate delta size bounds from the signature block size and new file length
+// without scanning the new file
+pub fn estimate_delta_size(block_size: u32, new_file_length: u64) -> DeltaSizeEstimate {
+    let delta_overhead = DELTA_HEADER_SIZE + serialized_size(&Vec::<VerifyMatch>::new()).unwrap();
+    let match_entry_size = serialized_size(&VerifyMatch::Match(0)).unwrap();
+    let literal_entry_overhead = serialized_size(&VerifyMatch::NoMatch(Vec::new())).unwrap();
+
+    let block_count = new_file_length.div_ceil(block_size as u64);
+    let worst_case = if new_file_length == 0 {
+        delta_overhead
+    } else {
+        delta_overhead + literal_entry_overhead + new_file_length
+    };
+
+    DeltaSizeEstimate {
+        new_file_length,
+        block_count,
+        best_case: delta_overhead + block_count * match_entry_size,
+        worst_case,
+        match_entry_size,
+        literal_entry_overhead,
+    }
+}
+
+// Estimate delta size bounds for a signature file and the length of a new file.
+// A stream, such as standard input, reports no length, so it is read to its end
+pub fn estimate_diff_file(
+    signature_file: &File,
+    new_file: &File,
+    read_options: &ReadOptions,
+) -> Result<DeltaSizeEstimate> {
+    let signature = read_signature_file(signature_file)?;
+    let new_file_length = if is_stream(new_file)? {
+        read_stream_to_buffer(new_file, signature.block_chunk_size as usize, read_options)?.len()
+            as u64
+    } else {
+        new_file.metadata()?.len()
+    };
+
+    Ok(estimate_delta_size(
+        signature.block_chunk_size,
+        new_file_length,
+    ))
+}
+
+// Window of the new file at one position, with its strong hash once computed, so the
+// position hint and the weak hash lookup at the same position share one hash
+struct Window<'c> {
+    // Offset of the window in the new file, to find its speculated strong hash
+    #[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+    position: usize,
+    chunk: &'c [u8],
+    strong_hash: Option<Vec<u8>>,
+}
+
+impl<'c> Window<'c> {
+    fn new(position: usize, chunk: &'c [u8]) -> Self {
+        Self {
+            position,
+            chunk,
+            strong_hash: None,
+        }
+    }
+}
+
+// Windows of the new file each thread checks for weak hash hits per speculated batch
+#[cfg(feature = "parallel")]
+const SPECULATED_WINDOWS_PER_THREAD: usize = 4096;
+
+#[cfg(feature = "parallel")]
+type HashBatchFn =
+    fn(&[u8], usize, Range<usize>, &FileChunkSignature, usize) -> HashMap<usize, Vec<u8>>;
+
+// Strong hashes of the upcoming windows of the new file with a weak hash hit, computed in
+// batches on several threads once the scan first needs one at or past the last batch.
+// The scan is sequential, but which windows have a weak hash hit isn't up to it
+#[cfg(feature = "parallel")]
+struct Speculation<'b> {
+    new_file: &'b [u8],
+    chunk_size: usize,
+    threads: usize,
+    // Windows from this offset on are not in a batch yet
+    speculated_to: usize,
+    hashes: HashMap<usize, Vec<u8>>,
+    // weak_hit_strong_hashes for the rolling checksum of the signature
+    hash_batch: HashBatchFn,
+}
+
+#[cfg(feature = "parallel")]
+impl Speculation<'_> {
+    // The speculated strong hash of the window at position, if it has a weak hash hit,
+    // and the number of strong hashes computed to find it
+    fn take(&mut self, signature: &FileChunkSignature, position: usize) -> (Option<Vec<u8>>, u64) {
+        let mut hashed = 0;
+        if position >= self.speculated_to {
+            let batch_len = self.threads * SPECULATED_WINDOWS_PER_THREAD;
+            let end = self.new_file.len().min(position + batch_len);
+            // The scan only moves forward, so the previous batch is done with
+            self.hashes = (self.hash_batch)(
+                self.new_file,
+                self.chunk_size,
+                position..end,
+                signature,
+                self.threads,
+            );
+            hashed = self.hashes.len() as u64;
+            self.speculated_to = end;
+        }
+        (self.hashes.remove(&position), hashed)
+    }
+}
+
+// Strong hashes of the windows starting in positions whose weak hash is in the signature,
+// keyed by position. The positions are split into runs rolled and hashed on up to threads
+// threads
+#[cfg(feature = "parallel")]
+fn weak_hit_strong_hashes<C: RollingChecksum>(
+    new_file: &[u8],
+    chunk_size: usize,
+    positions: Range<usize>,
+    signature: &FileChunkSignature,
+    threads: usize,
+) -> HashMap<usize, Vec<u8>> {
+    use std::thread;
+
+    let window_at = |start: usize| &new_file[start..new_file.len().min(start + chunk_size)];
+    let run_len = positions.len().div_ceil(threads.max(1)).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = positions
+            .clone()
+            .step_by(run_len)
+            .map(|start| {
+                let end = positions.end.min(start + run_len);
+                scope.spawn(move || {
+                    let mut rolling_sum = C::default();
+                    rolling_sum.add_bytes_at_end(window_at(start));
+                    let mut hashes = Vec::new();
+                    for position in start..end {
+                        if position > start {
+                            let next = new_file.get(position - 1 + chunk_size).copied();
+                            rolling_sum.roll_window(new_file[position - 1], next);
+                        }
+                        let chunk = window_at(position);
+                        let hashable = chunk.len() >= signature.block_chunk_size as usize
+                            || chunk.len() == signature.last_block_size();
+                        if hashable
+                            && signature
+                                .block_chunk_hashes(&rolling_sum.weak_key())
+                                .is_some()
+                        {
+                            hashes.push((position, signature.strong_hash_of(chunk)));
+                        }
+                    }
+                    hashes
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("strong hash thread panicked"))
+            .collect()
+    })
+}
+
+// Counts weak hash lookups, collisions and strong hashes while applying the collision policy
+struct CollisionCounter<'b> {
+    policy: CollisionPolicy,
+    windows: u64,
+    collisions: u64,
+    strong_hashes: u64,
+    #[cfg(feature = "parallel")]
+    speculation: Option<Speculation<'b>>,
+    #[cfg(not(feature = "parallel"))]
+    speculation: std::marker::PhantomData<&'b ()>,
+}
+
+impl CollisionCounter<'_> {
+    fn new(policy: CollisionPolicy) -> Self {
+        Self {
+            policy,
+            windows: 0,
+            collisions: 0,
+            strong_hashes: 0,
+            #[cfg(feature = "parallel")]
+            speculation: None,
+            #[cfg(not(feature = "parallel"))]
+            speculation: std::marker::PhantomData,
+        }
+    }
+
+    // Strong hash of the window, computed on first use unless it was speculated
+    fn strong_hash<'w>(
+        &mut self,
+        signature: &FileChunkSignature,
+        window: &'w mut Window,
+    ) -> &'w [u8] {
+        #[cfg(feature = "parallel")]
+        if let (None, Some(speculation)) = (&window.strong_hash, &mut self.speculation) {
+            let (hash, hashed) = speculation.take(signature, window.position);
+            self.strong_hashes += hashed;
+            window.strong_hash = hash;
+        }
+        window.strong_hash.get_or_insert_with(|| {
+            self.strong_hashes += 1;
+            signature.strong_hash_of(window.chunk)
+        })
+    }
+
+    fn match_index_and_checksum<'a>(
+        &mut self,
+        signature: &'a FileChunkSignature,
+        index_hash: u32,
+        window: &mut Window,
+    ) -> Option<&'a BlockChunkHashes> {
+        self.windows += 1;
+        // Windows shrinking at the end of the new file can only be the short last block
+        let window_len = window.chunk.len();
+        if window_len < signature.block_chunk_size as usize
+            && window_len != signature.last_block_size()
+        {
+            return None;
+        }
+        let hashes = signature.block_chunk_hashes(&index_hash)?;
+        if self.policy == CollisionPolicy::Skip {
+            return None;
+        }
+
+        let sha256_checksum_hash = self.strong_hash(signature, window);
+        let found = hashes.iter().find(|h| h.hash == sha256_checksum_hash);
+        if found.is_none() {
+            self.collisions += 1;
+        }
+        found
+    }
+
+    fn collision_rate(&self) -> f64 {
+        if self.windows == 0 {
+            0.0
+        } else {
+            self.collisions as f64 / self.windows as f64
+        }
+    }
+}
+
+// Strong hashes of the signature's blocks indexed by block index
+pub(crate) fn strong_hashes_by_index(signature: &FileChunkSignature) -> Vec<Option<&[u8]>> {
+    let mut strong_hashes: Vec<Option<&[u8]>> = Vec::new();
+    for block_hashes in signature.checksum_map.values().flatten() {
+        let index = block_hashes.index as usize;
+        if index >= strong_hashes.len() {
+            strong_hashes.resize(index + 1, None);
+        }
+        strong_hashes[index] = Some(&block_hashes.hash);
+    }
+    // Runs follow their original, so it has its hash by now
+    for run in &signature.duplicates {
+        let end = (run.first_index + run.count) as usize;
+        if end > strong_hashes.len() {
+            strong_hashes.resize(end, None);
+        }
+        let original = strong_hashes.get(run.original as usize).copied().flatten();
+        strong_hashes[run.first_index as usize..end].fill(original);
+    }
+    strong_hashes
+}
+
+// Generates diff based on for file buffer, signature file and file chunk size
+pub fn generate_diff(
+    new_file_buffer: &[u8],
+    signature: &FileChunkSignature,
+    chunk_size: usize,
+) -> Vec<VerifyMatch> {
+    generate_diff_with_options(
+        new_file_buffer,
+        signature,
+        chunk_size,
+        &DiffOptions::default(),
+    )
+    .expect("verify policy never fails")
+    .delta
+}
+
+// generate_diff with every entry paired with the offset in the new file it starts at.
+// Matches of the old file's short last block advance the offset by its length
+pub fn generate_diff_positioned(
+    new_file_buffer: &[u8],
+    signature: &FileChunkSignature,
+    chunk_size: usize,
+) -> Vec<(u64, VerifyMatch)> {
+    let mut offset = 0u64;
+    generate_diff(new_file_buffer, signature, chunk_size)
+        .into_iter()
+        .map(|entry| {
+            let start = offset;
+            offset += match &entry {
+                VerifyMatch::Match(index) => signature.block_len(*index) as u64,
+                VerifyMatch::NoMatch(bytes) => bytes.len() as u64,
+            };
+            (start, entry)
+        })
+        .collect()
+}
+
+// Generates diff, resolving weak hash collisions according to the collision policy
+// and stopping early if cancelled.
+// With CollisionPolicy::Error, fails if the fraction of scanned windows whose
+// weak hash collided exceeds max_collision_rate.
+// The window moves over the buffer by a cursor, so the scan costs O(n) window rolls
+// plus one strong hash per weak hash hit, for a new file of n bytes
+pub fn generate_diff_with_options(
+    new_file_buffer: &[u8],
+    signature: &FileChunkSignature,
+    chunk_size: usize,
+    options: &DiffOptions,
+) -> Result<DiffOutcome> {
+    validate_block_size(u32::try_from(chunk_size).unwrap_or(u32::MAX))?;
+    if let Some(outcome) = unscanned_diff(new_file_buffer, signature, options) {
+        return Ok(outcome.into_owned());
+    }
+    let mut outcome =
+        scan_with_signature_checksum(new_file_buffer, signature, chunk_size, options)?.into_owned();
+    let max_literal_run = options.max_literal_run.unwrap_or(usize::MAX).max(1);
+    if options.optimize {
+        outcome.delta = optimize_delta(outcome.delta, new_file_buffer, chunk_size);
+    }
+    if let Some(min_match_blocks) = options.min_match_blocks {
+        outcome.delta =
+            fold_short_match_runs(outcome.delta, new_file_buffer, chunk_size, min_match_blocks);
+    }
+    // The post-passes merge literals, so runs are split again to honour max_literal_run
+    if (options.optimize || options.min_match_blocks.is_some()) && options.max_literal_run.is_some()
+    {
+        outcome.delta = split_literal_runs(outcome.delta, max_literal_run);
+    }
+    Ok(outcome)
+}
+
+// generate_diff_with_options with literal runs left as slices of the new file buffer, so
+// the scan copies none of its bytes. The optimize and min_match_blocks post-passes rewrite
+// entries and aren't applied; DiffOutcome::into_owned gives the delta
+// generate_diff_with_options returns without them
+pub fn generate_diff_borrowed<'a>(
+    new_file_buffer: &'a [u8],
+    signature: &FileChunkSignature,
+    chunk_size: usize,
+    options: &DiffOptions,
+) -> Result<DiffOutcome<DeltaEntry<'a>>> {
+    validate_block_size(u32::try_from(chunk_size).unwrap_or(u32::MAX))?;
+    match unscanned_diff(new_file_buffer, signature, options) {
+        Some(outcome) => Ok(outcome),
+        None => scan_with_signature_checksum(new_file_buffer, signature, chunk_size, options),
+    }
+}
+
+// Delta of a new file that needs no scan: one literal when the old file is empty, every
+// block when the new file is the signed one
+fn unscanned_diff<'a>(
+    new_file_buffer: &'a [u8],
+    signature: &FileChunkSignature,
+    options: &DiffOptions,
+) -> Option<DiffOutcome<DeltaEntry<'a>>> {
+    // Nothing of an empty old file can be reused, so the whole new file is one literal
+    if signature.total_chunks == 0 {
+        if let Some(progress) = options.progress {
+            let total = new_file_buffer.len() as u64;
+            (progress.borrow_mut())(total, total);
+        }
+        let run_len = options.max_literal_run.unwrap_or(usize::MAX).max(1);
+        let delta = new_file_buffer
+            .chunks(run_len)
+            .map(DeltaEntry::Literal)
+            .collect();
+        return Some(DiffOutcome {
+            delta,
+            complete: true,
+            weak_lookups: 0,
+            strong_hashes: 0,
+        });
+    }
+    // A new file identical to the signed one reuses every block, without scanning it
+    if signature.is_whole_file(new_file_buffer) {
+        if let Some(progress) = options.progress {
+            let total = new_file_buffer.len() as u64;
+            (progress.borrow_mut())(total, total);
+        }
+        return Some(DiffOutcome {
+            delta: (0..signature.total_chunks).map(DeltaEntry::Match).collect(),
+            complete: true,
+            weak_lookups: 0,
+            strong_hashes: 0,
+        });
+    }
+    None
+}
+
+fn scan_with_signature_checksum<'a>(
+    new_file_buffer: &'a [u8],
+    signature: &FileChunkSignature,
+    chunk_size: usize,
+    options: &DiffOptions,
+) -> Result<DiffOutcome<DeltaEntry<'a>>> {
+    // The windows must be summed with the checksum the signature was generated with
+    match signature.rolling_checksum {
+        RollingChecksumAlgorithm::Prime => {
+            scan_new_file::<RollingWindow>(new_file_buffer, signature, chunk_size, options)
+        }
+        RollingChecksumAlgorithm::WidePrime => {
+            scan_new_file::<WidePrimeWindow>(new_file_buffer, signature, chunk_size, options)
+        }
+        RollingChecksumAlgorithm::Adler32 => {
+            scan_new_file::<Adler32Window>(new_file_buffer, signature, chunk_size, options)
+        }
+    }
+}
+
+fn scan_new_file<'a, C: RollingChecksum>(
+    new_file_buffer: &'a [u8],
+    signature: &FileChunkSignature,
+    chunk_size: usize,
+    options: &DiffOptions,
+) -> Result<DiffOutcome<DeltaEntry<'a>>> {
+    let cancelled = || {
+        options
+            .cancel
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    };
+    let dump_state = |rolling_sum: &C| -> Result<()> {
+        match options.dump_rolling_state {
+            Some(out) => rolling_sum.write_state(&mut *out.borrow_mut()),
+            None => Ok(()),
+        }
+    };
+    let aligned_hashes = if options.position_hints {
+        strong_hashes_by_index(signature)
+    } else {
+        Vec::new()
+    };
+    // Window of up to chunk_size bytes starting at an offset of the new file
+    let window_at =
+        |start: usize| &new_file_buffer[start..new_file_buffer.len().min(start + chunk_size)];
+    let mut counter = CollisionCounter::new(options.collision_policy);
+    #[cfg(feature = "parallel")]
+    if options.strong_hash_threads > 1 {
+        counter.speculation = Some(Speculation {
+            new_file: new_file_buffer,
+            chunk_size,
+            threads: options.strong_hash_threads,
+            speculated_to: 0,
+            hashes: HashMap::new(),
+            hash_batch: weak_hit_strong_hashes::<C>,
+        });
+    }
+    let mut match_verifier: Vec<DeltaEntry> = Vec::new();
+    let mut complete = true;
+    let mut progress_callback = options.progress.map(RefCell::borrow_mut);
+    let mut progress = ProgressReporter::new(
+        progress_callback.as_deref_mut(),
+        new_file_buffer.len() as u64,
+        chunk_size as u64,
+    );
+    let max_literal_run = options.max_literal_run.unwrap_or(usize::MAX).max(1);
+    // Offset in the new file of the start of the window
+    let mut position = 0usize;
+    // Match the old block with the same index as a block aligned window
+    let aligned_match =
+        |counter: &mut CollisionCounter, position: usize, window: &mut Window| -> Option<u64> {
+            if !options.position_hints || !position.is_multiple_of(chunk_size) {
+                return None;
+            }
+            let index = position / chunk_size;
+            let hash = aligned_hashes.get(index).copied().flatten()?;
+            (hash == counter.strong_hash(signature, window)).then_some(index as u64)
+        };
+    'scan: loop {
+        let chunk = window_at(position);
+
+        let mut actual_chunk_size = chunk.len();
+        if actual_chunk_size == 0 {
+            break;
+        }
+
+        // Calculate rolling window check-sum hash
+        let mut rolling_sum = C::default();
+        rolling_sum.add_bytes_at_end(chunk);
+        dump_state(&rolling_sum)?;
+        let index_hash = rolling_sum.weak_key();
+
+        // Verify if checksum of pattern and current window matches.
+        // If these two checksums don't match, move the window
+        let mut window = Window::new(position, chunk);
+        let matched_index = aligned_match(&mut counter, position, &mut window).or_else(|| {
+            counter
+                .match_index_and_checksum(signature, index_hash, &mut window)
+                .map(|hash| hash.index)
+        });
+        if let Some(index) = matched_index {
+            match_verifier.push(DeltaEntry::Match(index));
+
+            if pointer_at_last_chunk(actual_chunk_size, new_file_buffer.len() - position) {
+                break;
+            }
+            // Move the window past the matched block
+            position += actual_chunk_size;
+            progress.advance(position as u64);
+            if cancelled() {
+                complete = false;
+                break;
+            }
+            continue;
+        }
+
+        // In case the checksum of pattern and current window doesn't match,
+        // run rolling window. The bytes rolled past are the literal run from literal_start
+        let mut literal_start = position;
+        loop {
+            let remaining = new_file_buffer.len() - position;
+            let mut next: Option<u8> = None;
+            if !pointer_at_last_chunk(actual_chunk_size, remaining) {
+                next = Some(new_file_buffer[position + chunk_size]);
+            }
+            if remaining > 0 {
+                if position - literal_start == max_literal_run {
+                    match_verifier.push(DeltaEntry::Literal(
+                        &new_file_buffer[literal_start..position],
+                    ));
+                    literal_start = position;
+                }
+                let prev = new_file_buffer[position];
+                position += 1;
+                rolling_sum.roll_window(prev, next);
+                dump_state(&rolling_sum)?;
+                progress.advance(position as u64);
+                let index_hash = rolling_sum.weak_key();
+                let chunk = window_at(position);
+                actual_chunk_size = chunk.len();
+
+                if let Some(hash) = counter.match_index_and_checksum(
+                    signature,
+                    index_hash,
+                    &mut Window::new(position, chunk),
+                ) {
+                    match_verifier.push(DeltaEntry::Literal(
+                        &new_file_buffer[literal_start..position],
+                    ));
+                    match_verifier.push(DeltaEntry::Match(hash.index));
+
+                    position += actual_chunk_size;
+                    progress.advance(position as u64);
+                    break;
+                }
+                if cancelled() {
+                    match_verifier.push(DeltaEntry::Literal(
+                        &new_file_buffer[literal_start..position],
+                    ));
+                    complete = false;
+                    break 'scan;
+                }
+            } else {
+                if position > literal_start {
+                    match_verifier.push(DeltaEntry::Literal(
+                        &new_file_buffer[literal_start..position],
+                    ));
+                }
+                break;
+            }
+        }
+    }
+
+    if options.collision_policy == CollisionPolicy::Error
+        && counter.collision_rate() > options.max_collision_rate
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "weak hash collision rate {:.4} ({} of {} windows) exceeds {}",
+                counter.collision_rate(),
+                counter.collisions,
+                counter.windows,
+                options.max_collision_rate
+            ),
+        ));
+    }
+    if complete {
+        progress.finish();
+    }
+    Ok(DiffOutcome {
+        delta: match_verifier,
+        complete,
+        weak_lookups: counter.windows,
+        strong_hashes: counter.strong_hashes,
+    })
+}
+
+// Longest literal entry of a streamed diff. Unmatched input is flushed in entries of this
+// size, as generate_diff_with_options does with max_literal_run
+pub const STREAMING_LITERAL_RUN: usize = 64 * 1024;
+
+// Diff of a new file read as it goes, for input that can't be loaded whole or seeked, such
+// as a socket. Entries are yielded as soon as they are known, holding at most a window, the
+// next byte, a block of already scanned bytes and one literal run in memory. The delta is
+// the one generate_diff_with_options produces with max_literal_run set to
+// STREAMING_LITERAL_RUN. A read error ends the iteration after it is yielded
+pub fn generate_diff_streaming<'a, R: Read + 'a>(
+    reader: R,
+    signature: &'a FileChunkSignature,
+) -> impl Iterator<Item = Result<VerifyMatch>> + 'a {
+    let diff: Box<dyn Iterator<Item = Result<VerifyMatch>> + 'a> = match signature.rolling_checksum
+    {
+        RollingChecksumAlgorithm::Prime => {
+            Box::new(StreamingDiff::<R, RollingWindow>::new(reader, signature))
+        }
+        RollingChecksumAlgorithm::WidePrime => {
+            Box::new(StreamingDiff::<R, WidePrimeWindow>::new(reader, signature))
+        }
+        RollingChecksumAlgorithm::Adler32 => {
+            Box::new(StreamingDiff::<R, Adler32Window>::new(reader, signature))
+        }
+    };
+    diff
+}
+
+// The scan of scan_new_file over a buffer refilled from the reader. The window starts at
+// buffer[position]; bytes before it are dropped once they add up to a block
+struct StreamingDiff<'a, R, C> {
+    reader: R,
+    signature: &'a FileChunkSignature,
+    chunk_size: usize,
+    counter: CollisionCounter<'a>,
+    buffer: Vec<u8>,
+    position: usize,
+    eof: bool,
+    done: bool,
+    // Checksum of the window while rolling, None at the start of a fresh window
+    rolling_sum: Option<C>,
+    diff_bytes: Vec<u8>,
+    pending: VecDeque<VerifyMatch>,
+}
+
+impl<'a, R: Read, C: RollingChecksum> StreamingDiff<'a, R, C> {
+    fn new(reader: R, signature: &'a FileChunkSignature) -> Self {
+        Self {
+            reader,
+            signature,
+            chunk_size: signature.block_chunk_size as usize,
+            counter: CollisionCounter::new(CollisionPolicy::Verify),
+            buffer: Vec::new(),
+            position: 0,
+            eof: false,
+            done: false,
+            rolling_sum: None,
+            diff_bytes: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Read until the buffer holds the window and the byte after it, or the input ends
+    fn fill(&mut self) -> Result<()> {
+        if self.position >= self.chunk_size {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+        let wanted = self.position + self.chunk_size + 1;
+        while !self.eof && self.buffer.len() < wanted {
+            let filled = self.buffer.len();
+            self.buffer.resize(wanted, 0);
+            match self.reader.read(&mut self.buffer[filled..]) {
+                Ok(0) => {
+                    self.buffer.truncate(filled);
+                    self.eof = true;
+                }
+                Ok(read) => self.buffer.truncate(filled + read),
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    self.buffer.truncate(filled)
+                }
+                Err(error) => {
+                    self.buffer.truncate(filled);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> &[u8] {
+        let end = self.buffer.len().min(self.position + self.chunk_size);
+        &self.buffer[self.position..end]
+    }
+
+    // Move the window past a matched block
+    fn skip_window(&mut self) {
+        self.position += self.window().len();
+    }
+
+    fn matched_index(&mut self, index_hash: u32) -> Option<u64> {
+        let end = self.buffer.len().min(self.position + self.chunk_size);
+        let chunk = &self.buffer[self.position..end];
+        self.counter
+            .match_index_and_checksum(
+                self.signature,
+                index_hash,
+                &mut Window::new(self.position, chunk),
+            )
+            .map(|hash| hash.index)
+    }
+
+    // Advance the scan until it has entries to yield or the input is exhausted
+    fn scan(&mut self) -> Result<()> {
+        while self.pending.is_empty() && !self.done {
+            self.fill()?;
+            let available = self.buffer.len() - self.position;
+            match self.rolling_sum.take() {
+                None => {
+                    if available == 0 {
+                        self.done = true;
+                        break;
+                    }
+                    let mut rolling_sum = C::default();
+                    rolling_sum.add_bytes_at_end(self.window());
+                    match self.matched_index(rolling_sum.weak_key()) {
+                        Some(index) => {
+                            self.pending.push_back(VerifyMatch::Match(index));
+                            self.skip_window();
+                        }
+                        None => self.rolling_sum = Some(rolling_sum),
+                    }
+                }
+                Some(mut rolling_sum) => {
+                    if available == 0 {
+                        push_literal_run(&mut self.pending, &mut self.diff_bytes);
+                        self.done = true;
+                        break;
+                    }
+                    let prev = self.buffer[self.position];
+                    let next = (available > self.chunk_size)
+                        .then(|| self.buffer[self.position + self.chunk_size]);
+                    self.position += 1;
+                    if self.diff_bytes.len() == STREAMING_LITERAL_RUN {
+                        push_literal_run(&mut self.pending, &mut self.diff_bytes);
+                    }
+                    self.diff_bytes.push(prev);
+                    rolling_sum.roll_window(prev, next);
+                    match self.matched_index(rolling_sum.weak_key()) {
+                        Some(index) => {
+                            push_literal_run(&mut self.pending, &mut self.diff_bytes);
+                            self.pending.push_back(VerifyMatch::Match(index));
+                            self.skip_window();
+                        }
+                        None => self.rolling_sum = Some(rolling_sum),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read, C: RollingChecksum> Iterator for StreamingDiff<'_, R, C> {
+    type Item = Result<VerifyMatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(error) = self.scan() {
+            self.done = true;
+            return Some(Err(error));
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+fn push_literal_run(pending: &mut VecDeque<VerifyMatch>, diff_bytes: &mut Vec<u8>) {
+    if !diff_bytes.is_empty() {
+        pending.push_back(VerifyMatch::NoMatch(std::mem::take(diff_bytes)));
+    }
+}
+
+// Inline matched blocks into the neighbouring literals wherever the Match entry,
+// and the extra literal entry it splits off, serialize larger than the block itself.
+// new_file must be the buffer the delta was generated from
+pub fn optimize_delta(
+    delta: Vec<VerifyMatch>,
+    new_file: &[u8],
+    chunk_size: usize,
+) -> Vec<VerifyMatch> {
+    let match_entry_size = serialized_size(&VerifyMatch::Match(0)).unwrap();
+    let literal_entry_overhead = serialized_size(&VerifyMatch::NoMatch(Vec::new())).unwrap();
+
+    let mut optimized: Vec<VerifyMatch> = Vec::with_capacity(delta.len());
+    let mut offset = 0usize;
+    let mut entries = delta.into_iter().peekable();
+    while let Some(entry) = entries.next() {
+        match entry {
+            VerifyMatch::Match(index) => {
+                let block_len = chunk_size.min(new_file.len() - offset);
+                let block = &new_file[offset..offset + block_len];
+                offset += block_len;
+
+                let literal_before = matches!(optimized.last(), Some(VerifyMatch::NoMatch(_)));
+                let literal_after = matches!(entries.peek(), Some(VerifyMatch::NoMatch(_)));
+                let kept_size = match (literal_before, literal_after) {
+                    (true, true) => match_entry_size + literal_entry_overhead,
+                    _ => match_entry_size,
+                };
+                let inlined_size = match (literal_before, literal_after) {
+                    (false, false) => block_len as u64 + literal_entry_overhead,
+                    _ => block_len as u64,
+                };
+                if inlined_size < kept_size {
+                    push_literal(&mut optimized, block.to_vec());
+                } else {
+                    optimized.push(VerifyMatch::Match(index));
+                }
+            }
+            VerifyMatch::NoMatch(bytes) => {
+                offset += bytes.len();
+                push_literal(&mut optimized, bytes);
+            }
+        }
+    }
+    optimized
+}
+
+// Fold every run of fewer than min_match_blocks consecutive Match entries into the
+// neighbouring literals, copying the matched bytes from new_file. Runs of at least
+// min_match_blocks entries are kept as they are. new_file must be the buffer the delta was
+// generated from
+pub fn fold_short_match_runs(
+    delta: Vec<VerifyMatch>,
+    new_file: &[u8],
+    chunk_size: usize,
+    min_match_blocks: usize,
+) -> Vec<VerifyMatch> {
+    let mut folded: Vec<VerifyMatch> = Vec::with_capacity(delta.len());
+    let mut offset = 0usize;
+    let mut entries = delta.into_iter().peekable();
+    while let Some(entry) = entries.next() {
+        match entry {
+            VerifyMatch::Match(index) => {
+                let mut run = vec![index];
+                while let Some(&VerifyMatch::Match(next)) = entries.peek() {
+                    run.push(next);
+                    entries.next();
+                }
+                let run_start = offset;
+                for _ in &run {
+                    offset += chunk_size.min(new_file.len() - offset);
+                }
+                if run.len() < min_match_blocks {
+                    push_literal(&mut folded, new_file[run_start..offset].to_vec());
+                } else {
+                    folded.extend(run.into_iter().map(VerifyMatch::Match));
+                }
+            }
+            VerifyMatch::NoMatch(bytes) => {
+                offset += bytes.len();
+                push_literal(&mut folded, bytes);
+            }
+        }
+    }
+    folded
+}
+
+// Compose the delta first, turning old_file into an intermediate file, and the delta
+// second, generated against the intermediate file's signature, into one delta turning
+// old_file into second's new file. The result matches blocks of old_file of
+// old_block_size bytes, like first. Blocks of the intermediate file matched by second are
+// resolved through first: whole blocks of old_file stay matches, and the rest becomes
+// literal bytes, taken from first's literals or from old_file
+pub fn combine_deltas(
+    first: &[VerifyMatch],
+    second: &[VerifyMatch],
+    old_file: &[u8],
+    old_block_size: u32,
+    intermediate_signature: &FileChunkSignature,
+) -> Result<Vec<VerifyMatch>> {
+    validate_block_size(old_block_size)?;
+    let block_size = old_block_size as u64;
+    let old_length = old_file.len() as u64;
+    validate_delta(first, old_length.div_ceil(block_size))?;
+    validate_delta(second, intermediate_signature.total_chunks)?;
+    let entry_len = |entry: &VerifyMatch| match entry {
+        VerifyMatch::Match(index) => block_size.min(old_length - index * block_size),
+        VerifyMatch::NoMatch(bytes) => bytes.len() as u64,
+    };
+
+    // Offset in the intermediate file of every entry of first
+    let mut starts: Vec<u64> = Vec::with_capacity(first.len());
+    let mut intermediate_length = 0u64;
+    for entry in first {
+        starts.push(intermediate_length);
+        intermediate_length += entry_len(entry);
+    }
+    if intermediate_length != intermediate_signature.original_length {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "the first delta builds a file of {} bytes, but the signature of the second \
+                 delta's old file is of {} bytes",
+                intermediate_length, intermediate_signature.original_length
+            ),
+        ));
+    }
+
+    // Copy a range of the intermediate file, as matches of the old file's blocks it holds
+    // whole and literal bytes for the rest
+    let resolve = |combined: &mut Vec<VerifyMatch>, mut offset: u64, end: u64| {
+        // Last entry of first starting at or before the range, skipping empty literals
+        let mut piece = starts.partition_point(|start| *start <= offset) - 1;
+        while offset < end {
+            let piece_start = starts[piece];
+            let piece_len = entry_len(&first[piece]);
+            let from = offset - piece_start;
+            let to = (end - piece_start).min(piece_len);
+            match &first[piece] {
+                VerifyMatch::Match(old_index) if from == 0 && to == piece_len => {
+                    combined.push(VerifyMatch::Match(*old_index))
+                }
+                VerifyMatch::Match(old_index) => {
+                    let block_start = (old_index * block_size) as usize;
+                    push_literal(
+                        combined,
+                        old_file[block_start + from as usize..block_start + to as usize].to_vec(),
+                    )
+                }
+                VerifyMatch::NoMatch(bytes) if to > from => {
+                    push_literal(combined, bytes[from as usize..to as usize].to_vec())
+                }
+                VerifyMatch::NoMatch(_) => {}
+            }
+            offset = piece_start + to;
+            piece += 1;
+        }
+    };
+
+    let intermediate_block_size = intermediate_signature.block_chunk_size as u64;
+    let mut combined: Vec<VerifyMatch> = Vec::new();
+    // Range of the intermediate file copied by the current run of second's matches of
+    // consecutive blocks, resolved as a whole so it can hold whole blocks of the old file
+    let mut run: Option<(u64, u64)> = None;
+    for entry in second {
+        match entry {
+            VerifyMatch::Match(index) => {
+                let start = index * intermediate_block_size;
+                let end = start + intermediate_signature.block_len(*index) as u64;
+                run = match run {
+                    Some((run_start, run_end)) if run_end == start => Some((run_start, end)),
+                    Some((run_start, run_end)) => {
+                        resolve(&mut combined, run_start, run_end);
+                        Some((start, end))
+                    }
+                    None => Some((start, end)),
+                };
+            }
+            VerifyMatch::NoMatch(bytes) => {
+                if let Some((run_start, run_end)) = run.take() {
+                    resolve(&mut combined, run_start, run_end);
+                }
+                if !bytes.is_empty() {
+                    push_literal(&mut combined, bytes.clone());
+                }
+            }
+        }
+    }
+    if let Some((run_start, run_end)) = run {
+        resolve(&mut combined, run_start, run_end);
+    }
+    Ok(combined)
+}
+
+// Split literal entries longer than max_run bytes into several entries
+fn split_literal_runs(delta: Vec<VerifyMatch>, max_run: usize) -> Vec<VerifyMatch> {
+    let mut split: Vec<VerifyMatch> = Vec::with_capacity(delta.len());
+    for entry in delta {
+        match entry {
+            VerifyMatch::NoMatch(bytes) if bytes.len() > max_run => split.extend(
+                bytes
+                    .chunks(max_run)
+                    .map(|run| VerifyMatch::NoMatch(run.to_vec())),
+            ),
+            entry => split.push(entry),
+        }
+    }
+    split
+}
+
+// Append literal bytes, merging them into a trailing literal entry
+fn push_literal(delta: &mut Vec<VerifyMatch>, bytes: Vec<u8>) {
+    match delta.last_mut() {
+        Some(VerifyMatch::NoMatch(literal)) => literal.extend(bytes),
+        _ => delta.push(VerifyMatch::NoMatch(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_io::{read_file_to_buffer, RetryPolicy};
+    use crate::handlers::patch::apply_patch;
+    use crate::handlers::progress::PROGRESS_INTERVAL_BLOCKS;
+    use crate::handlers::signature::{
+        chunk_sha256_hash, get_signature_with_options, read_signature, write_signature,
+        write_signature_file, SignatureOptions, StrongHashAlgorithm,
+    };
+    use crate::handlers::testutil::{
+        apply_edits, apply_from_slices, empty_signature, gen_data, reconstruct,
+        signature_from_slice, temp_path, SeededRng, ShortReads,
+    };
+    use crate::handlers::window_checksum::{rolling_window_checksum, MAX_BLOCK_SIZE};
+
+    // [1, 0, 0, 1] and [0, 1, 1, 0] have the same byte sum and the same
+    // position weighted sum, so they share a weak hash but not a strong hash
+    fn collision_heavy_input() -> (FileChunkSignature, Vec<u8>) {
+        let signature = get_signature(&[1, 0, 0, 1], 4);
+        let new_file_buffer = [0u8, 1, 1, 0, 5].repeat(8);
+        (signature, new_file_buffer)
+    }
+
+    #[test]
+    pub fn test_generate_diff() {
+        // The fixtures are compiled in, so only data/diff's format is read from a delta file
+        let old_file = include_bytes!("../../data/old.txt");
+        let new_file = include_bytes!("../../data/new.txt");
+        let expected_diff_file = include_bytes!("../../data/diff");
+        let signature = signature_from_slice(old_file, 64);
+
+        let diff = generate_diff(new_file, &signature, 64);
+
+        let expected_diff = read_delta(&mut &expected_diff_file[..]).unwrap();
+        assert_eq!(expected_diff, diff);
+        assert_eq!(
+            new_file[..],
+            apply_from_slices(old_file, expected_diff_file, 64)
+        );
+    }
+
+    #[test]
+    pub fn test_estimate_delta_size_bounds() {
+        let block = [3u8, 1, 4, 1, 5, 9, 2, 6].repeat(8);
+        let signature = get_signature(&block, 64);
+        let delta_file_size = |delta: &[VerifyMatch]| {
+            let mut written: Vec<u8> = Vec::new();
+            write_delta(delta, &mut written).unwrap();
+            written.len() as u64
+        };
+
+        let all_matched = block.repeat(4);
+        let estimate = estimate_delta_size(64, all_matched.len() as u64);
+        let best = generate_diff(&all_matched, &signature, 64);
+        assert_eq!(4, estimate.block_count);
+        assert_eq!(delta_file_size(&best), estimate.best_case);
+
+        let all_literal = vec![0u8; 200];
+        let estimate = estimate_delta_size(64, all_literal.len() as u64);
+        let worst = generate_diff(&all_literal, &signature, 64);
+        assert_eq!(delta_file_size(&worst), estimate.worst_case);
+
+        let estimate = estimate_delta_size(64, 0);
+        assert_eq!(0, estimate.block_count);
+        assert_eq!(estimate.best_case, estimate.worst_case);
+    }
+
+    #[test]
+    pub fn test_verify_match_json_schema_keys() {
+        let diff = vec![VerifyMatch::Match(3), VerifyMatch::NoMatch(vec![1, 2])];
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(r#"[{"match":3},{"literal":[1,2]}]"#, json);
+    }
+
+    fn diff_with_policy(
+        buffer: &[u8],
+        signature: &FileChunkSignature,
+        collision_policy: CollisionPolicy,
+        max_collision_rate: f64,
+    ) -> Result<Vec<VerifyMatch>> {
+        let options = DiffOptions {
+            collision_policy,
+            max_collision_rate,
+            ..DiffOptions::default()
+        };
+        generate_diff_with_options(buffer, signature, 4, &options).map(|outcome| outcome.delta)
+    }
+
+    #[test]
+    pub fn test_collision_policy_verify() {
+        let (signature, buffer) = collision_heavy_input();
+        let diff = diff_with_policy(&buffer, &signature, CollisionPolicy::Verify, 0.0).unwrap();
+
+        assert_eq!(vec![VerifyMatch::NoMatch(buffer.clone())], diff);
+        assert_eq!(generate_diff(&buffer, &signature, 4), diff);
+    }
+
+    #[test]
+    pub fn test_collision_policy_skip() {
+        let (signature, _) = collision_heavy_input();
+        // Even a genuine match is skipped without computing the strong hash
+        let diff = diff_with_policy(&[1, 0, 0, 1], &signature, CollisionPolicy::Skip, 0.0).unwrap();
+
+        assert_eq!(vec![VerifyMatch::NoMatch(vec![1, 0, 0, 1])], diff);
+        assert_eq!(
+            vec![VerifyMatch::Match(0)],
+            generate_diff(&[1, 0, 0, 1], &signature, 4)
+        );
+    }
+
+    #[test]
+    pub fn test_strong_hash_computed_once_per_window() {
+        // Aligned windows are checked against the position hint, then looked up by weak
+        // hash, sharing one strong hash
+        let (signature, buffer) = collision_heavy_input();
+        let options = DiffOptions {
+            position_hints: true,
+            ..DiffOptions::default()
+        };
+        let outcome = generate_diff_with_options(&buffer, &signature, 4, &options).unwrap();
+
+        assert_eq!(vec![VerifyMatch::NoMatch(buffer.clone())], outcome.delta);
+        let colliding_windows = buffer
+            .windows(4)
+            .filter(|window| {
+                signature
+                    .block_chunk_hashes(&rolling_window_checksum(window))
+                    .is_some()
+            })
+            .count() as u64;
+        assert!(colliding_windows > 1);
+        assert_eq!(colliding_windows, outcome.strong_hashes);
+    }
+
+    #[test]
+    pub fn test_collision_policy_error() {
+        let (signature, buffer) = collision_heavy_input();
+        let err = diff_with_policy(&buffer, &signature, CollisionPolicy::Error, 0.1).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("collision rate"));
+
+        let diff = diff_with_policy(&buffer, &signature, CollisionPolicy::Error, 0.5).unwrap();
+        assert_eq!(vec![VerifyMatch::NoMatch(buffer)], diff);
+    }
+
+    // Signature built one block at a time
+    fn signature_of_blocks(buffer: &[u8], block_size: u32) -> FileChunkSignature {
+        let mut signature = FileChunkSignature {
+            total_chunks: buffer.len().div_ceil(block_size as usize) as u64,
+            original_length: buffer.len() as u64,
+            ..empty_signature(block_size)
+        };
+        for (index, block_chunk) in buffer.chunks(block_size as usize).enumerate() {
+            signature
+                .checksum_map
+                .entry(rolling_window_checksum(block_chunk))
+                .or_default()
+                .push(BlockChunkHashes {
+                    index: index as u64,
+                    hash: chunk_sha256_hash(block_chunk).to_vec(),
+                });
+        }
+        signature
+    }
+
+    #[test]
+    pub fn test_position_hints_on_lightly_edited_file() {
+        // Pseudo random old file, so that no two blocks are the same.
+        // Byte values are kept small so the rolling sums never wrap the modulus
+        let old_file_buffer: Vec<u8> = gen_data(0x2545_f491, 64 * 1024)
+            .iter()
+            .map(|byte| byte % 128)
+            .collect();
+        let signature = signature_of_blocks(&old_file_buffer, 16);
+
+        // Edit about 1% of the bytes in place
+        let mut new_file_buffer = old_file_buffer.clone();
+        for offset in [300, 10_000, 30_000, 50_000] {
+            new_file_buffer[offset..offset + 160].fill(0);
+        }
+
+        let scan = |position_hints| {
+            let options = DiffOptions {
+                position_hints,
+                ..DiffOptions::default()
+            };
+            generate_diff_with_options(&new_file_buffer, &signature, 16, &options).unwrap()
+        };
+        let without_hints = scan(false);
+        let with_hints = scan(true);
+
+        assert_eq!(without_hints.delta, with_hints.delta);
+        let matched_blocks = with_hints
+            .delta
+            .iter()
+            .filter(|entry| matches!(entry, VerifyMatch::Match(_)))
+            .count();
+        assert!(matched_blocks > 4000);
+        assert!(with_hints.weak_lookups < without_hints.weak_lookups);
+    }
+
+    #[test]
+    pub fn test_cancelled_diff_returns_partial_delta() {
+        let block = [3u8, 1, 4, 1];
+        let signature = get_signature(&block, 4);
+        let new_file_buffer = [&block[..], &[9, 9, 9, 9, 9, 9], &block[..]].concat();
+
+        let cancel = AtomicBool::new(false);
+        let options = DiffOptions {
+            cancel: Some(&cancel),
+            ..DiffOptions::default()
+        };
+        let outcome =
+            generate_diff_with_options(&new_file_buffer, &signature, 4, &options).unwrap();
+        assert!(outcome.complete);
+        assert_eq!(
+            generate_diff(&new_file_buffer, &signature, 4),
+            outcome.delta
+        );
+
+        // Cancelled after the first matched block
+        cancel.store(true, Ordering::Relaxed);
+        let outcome =
+            generate_diff_with_options(&new_file_buffer, &signature, 4, &options).unwrap();
+        assert!(!outcome.complete);
+        assert_eq!(vec![VerifyMatch::Match(0)], outcome.delta);
+
+        // Cancelled after the first roll, the rolled byte is kept as a literal
+        let outcome =
+            generate_diff_with_options(&new_file_buffer[4..], &signature, 4, &options).unwrap();
+        assert!(!outcome.complete);
+        assert_eq!(vec![VerifyMatch::NoMatch(vec![9])], outcome.delta);
+    }
+
+    #[test]
+    pub fn test_dump_rolling_state_matches_manual_trace() {
+        let signature = get_signature(&[9, 9], 2);
+        let dump: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+        let options = DiffOptions {
+            dump_rolling_state: Some(&dump),
+            ..DiffOptions::default()
+        };
+        generate_diff_with_options(&[1, 2, 3], &signature, 2, &options).unwrap();
+
+        // [1, 2]: sum 1 + 2, weighted sum 1 * 2 + 2 * 1
+        // roll out 1, in 3: sum 3 - 1 + 3, weighted 4 - 2 * 1 + 5
+        // roll out 2 at the tail: sum 5 - 2, weighted 7 - 2 * 2, window shrinks to 1
+        // roll out 3 at the tail: sum 3 - 3, weighted 3 - 1 * 3, window shrinks to 0
+        let expected = [
+            "block_sum=3 all_blocks_sum=4 window_size=2 weak_key=84767",
+            "block_sum=5 all_blocks_sum=7 window_size=2 weak_key=148342",
+            "block_sum=3 all_blocks_sum=3 window_size=1 weak_key=63576",
+            "block_sum=0 all_blocks_sum=0 window_size=0 weak_key=0",
+        ];
+        let dumped = String::from_utf8(dump.into_inner()).unwrap();
+        assert_eq!(expected.to_vec(), dumped.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn test_optimize_inlines_isolated_short_match() {
+        let old_file: Vec<u8> = (0..64).collect();
+        let signature = signature_of_blocks(&old_file, 16);
+        let literal: Vec<u8> = (100..130).collect();
+        let new_file = [
+            &literal[..],
+            &old_file[16..32],
+            &literal[..],
+            &old_file[32..],
+        ]
+        .concat();
+
+        let raw = generate_diff(&new_file, &signature, 16);
+        let options = DiffOptions {
+            optimize: true,
+            ..DiffOptions::default()
+        };
+        let optimized = generate_diff_with_options(&new_file, &signature, 16, &options)
+            .unwrap()
+            .delta;
+
+        // The isolated block is inlined, the trailing run of two blocks is kept
+        assert_eq!(
+            vec![
+                VerifyMatch::NoMatch([&literal[..], &old_file[16..32], &literal[..]].concat()),
+                VerifyMatch::Match(2),
+                VerifyMatch::Match(3),
+            ],
+            optimized
+        );
+        assert!(serialized_size(&optimized).unwrap() < serialized_size(&raw).unwrap());
+        assert_eq!(new_file, reconstruct(&old_file, &optimized, 16));
+    }
+
+    #[test]
+    pub fn test_optimize_never_increases_delta_size() {
+        let signature_file = read_handler(Path::new("data/signature")).unwrap();
+        let signature = read_signature_file(&signature_file).unwrap();
+        let chunk_size = signature.block_chunk_size as usize;
+        let new_file = read_handler(Path::new("data/new.txt")).unwrap();
+        let buffer = read_file_to_buffer(&mut BufReader::new(&new_file)).unwrap();
+
+        let raw = generate_diff(&buffer, &signature, chunk_size);
+        let optimized = optimize_delta(
+            generate_diff(&buffer, &signature, chunk_size),
+            &buffer,
+            chunk_size,
+        );
+        assert!(serialized_size(&optimized).unwrap() <= serialized_size(&raw).unwrap());
+
+        let (signature, buffer) = collision_heavy_input();
+        let raw = generate_diff(&buffer, &signature, 4);
+        let optimized = optimize_delta(generate_diff(&buffer, &signature, 4), &buffer, 4);
+        assert!(serialized_size(&optimized).unwrap() <= serialized_size(&raw).unwrap());
+    }
+
+    #[test]
+    pub fn test_min_match_blocks_folds_isolated_matches() {
+        let old_file = gen_data(7, 16 * 4);
+        let block = |index: usize| &old_file[index * 4..(index + 1) * 4];
+        // One isolated block, a run of two, then another isolated block
+        let new_file = [
+            &[0xee; 3][..],
+            block(2),
+            &[0xee; 3],
+            block(5),
+            block(6),
+            &[0xee; 3],
+            block(9),
+        ]
+        .concat();
+        let signature = get_signature(&old_file, 4);
+        let raw = generate_diff(&new_file, &signature, 4);
+        assert_eq!(4, raw.iter().filter(|entry| entry.is_match()).count());
+
+        let options = DiffOptions {
+            min_match_blocks: Some(2),
+            ..DiffOptions::default()
+        };
+        let folded = generate_diff_with_options(&new_file, &signature, 4, &options)
+            .unwrap()
+            .delta;
+        assert_eq!(
+            vec![
+                VerifyMatch::NoMatch(new_file[..10].to_vec()),
+                VerifyMatch::Match(5),
+                VerifyMatch::Match(6),
+                VerifyMatch::NoMatch(new_file[18..].to_vec()),
+            ],
+            folded
+        );
+        assert_eq!(new_file, reconstruct(&old_file, &folded, 4));
+
+        // A threshold of one keeps every match
+        let unfolded =
+            fold_short_match_runs(generate_diff(&new_file, &signature, 4), &new_file, 4, 1);
+        assert_eq!(raw, unfolded);
+    }
+
+    #[test]
+    pub fn test_prepended_header_is_recovered_by_rolling() {
+        let old_file: Vec<u8> = (0..64).map(|byte| byte % 50).collect();
+        let signature = signature_of_blocks(&old_file, 8);
+        let header = b"HEADER v2\n".to_vec();
+        let new_file = [&header[..], &old_file[..]].concat();
+
+        let diff = generate_diff(&new_file, &signature, 8);
+
+        let mut expected = vec![VerifyMatch::NoMatch(header)];
+        expected.extend((0..8).map(VerifyMatch::Match));
+        assert_eq!(expected, diff);
+    }
+
+    #[test]
+    pub fn test_mid_file_insertion_disturbs_one_block() {
+        // Every block after an insertion is still found, just at a shifted offset,
+        // so only the block the bytes land in is lost
+        let old_file = gen_data(15, 64 * 64);
+        let signature = get_signature(&old_file, 64);
+        let insert_at = 64 * 20 + 10;
+        let new_file = [
+            &old_file[..insert_at],
+            &gen_data(16, 37),
+            &old_file[insert_at..],
+        ]
+        .concat();
+
+        let diff = generate_diff(&new_file, &signature, 64);
+        let matched: Vec<u64> = diff
+            .iter()
+            .filter_map(|entry| match entry {
+                VerifyMatch::Match(index) => Some(*index),
+                VerifyMatch::NoMatch(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            (0..64).filter(|index| *index != 20).collect::<Vec<_>>(),
+            matched
+        );
+        assert_eq!(new_file, reconstruct(&old_file, &diff, 64));
+    }
+
+    #[test]
+    pub fn test_short_last_block_is_matched() {
+        let old_file: Vec<u8> = (0..100u32).map(|i| (i * 13 % 97) as u8).collect();
+        let signature = get_signature(&old_file, 32);
+        assert_eq!(4, signature.last_block_size());
+
+        let diff = generate_diff(&old_file, &signature, 32);
+        assert_eq!((0..4).map(VerifyMatch::Match).collect::<Vec<_>>(), diff);
+
+        // Reached by rolling, as the window shrinks at the end of the new file
+        let new_file = [&b"inserted"[..], &old_file[64..]].concat();
+        let diff = generate_diff(&new_file, &signature, 32);
+        assert_eq!(
+            vec![
+                VerifyMatch::NoMatch(b"inserted".to_vec()),
+                VerifyMatch::Match(2),
+                VerifyMatch::Match(3)
+            ],
+            diff
+        );
+    }
+
+    #[test]
+    pub fn test_diff_against_self_matches_every_block() {
+        for length in [0usize, 1, 63, 64, 65, 128, 1000, 4096, 5000, 20000] {
+            let buffer: Vec<u8> = (0..length).map(|i| (i * 31 + i / 7) as u8).collect();
+            let block_count = length.div_ceil(find_blocksize(length as u64) as usize);
+
+            let delta = diff_against_self(&buffer, &DiffOptions::default())
+                .unwrap_or_else(|error| panic!("length {}: {}", length, error));
+            assert_eq!(block_count, delta.len(), "length {}", length);
+        }
+    }
+
+    #[test]
+    pub fn test_whole_file_hash_short_circuits_identical_file() {
+        let old_file = gen_data(21, 64 * 40 + 9);
+        let options = SignatureOptions {
+            whole_file_hash: true,
+            ..SignatureOptions::default()
+        };
+        let signature = get_signature_with_options(&old_file, 64, &options).unwrap();
+        assert!(signature.whole_file_hash.is_some());
+
+        // Identical: every block matched without a single window looked up
+        let outcome =
+            generate_diff_with_options(&old_file, &signature, 64, &DiffOptions::default()).unwrap();
+        assert_eq!(0, outcome.weak_lookups);
+        assert_eq!(
+            (0..41).map(VerifyMatch::Match).collect::<Vec<_>>(),
+            outcome.delta
+        );
+        assert_eq!(old_file, reconstruct(&old_file, &outcome.delta, 64));
+
+        // One byte changed: scanned as usual
+        let mut new_file = old_file.clone();
+        new_file[100] ^= 1;
+        let outcome =
+            generate_diff_with_options(&new_file, &signature, 64, &DiffOptions::default()).unwrap();
+        assert!(outcome.weak_lookups > 0);
+        assert_eq!(new_file, reconstruct(&old_file, &outcome.delta, 64));
+
+        // Without the whole file hash, an identical file is scanned
+        let signature =
+            get_signature_with_options(&old_file, 64, &SignatureOptions::default()).unwrap();
+        let outcome =
+            generate_diff_with_options(&old_file, &signature, 64, &DiffOptions::default()).unwrap();
+        assert!(outcome.weak_lookups > 0);
+    }
+
+    #[test]
+    pub fn test_diff_batch_against_one_signature() {
+        let output_dir = temp_path("rolling-hash-diff-batch");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let new_files: Vec<PathBuf> = ["data/new.txt", "data/old.txt", "data/signature"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        let signature_file = read_handler(Path::new("data/signature")).unwrap();
+        let batch = diff_batch(
+            &signature_file,
+            &new_files,
+            &output_dir,
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(3, batch.len());
+        let signature =
+            read_signature_file(&read_handler(Path::new("data/signature")).unwrap()).unwrap();
+        for (new_file_path, (delta_path, stats)) in new_files.iter().zip(&batch) {
+            let new_file_buffer = std::fs::read(new_file_path).unwrap();
+            let new_file_length = new_file_buffer.len() as u64;
+            let expected = generate_diff(
+                &new_file_buffer,
+                &signature,
+                signature.block_chunk_size as usize,
+            );
+            let delta = read_delta(&mut read_handler(delta_path).unwrap()).unwrap();
+            assert_eq!(expected, delta);
+            assert_eq!(new_file_length, stats.new_file_length);
+            assert_eq!(
+                std::fs::metadata(delta_path).unwrap().len(),
+                stats.delta_size
+            );
+        }
+        assert_eq!(output_dir.join("new.txt.delta"), batch[0].0);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_delta_passed_as_signature_is_named() {
+        let delta_path = temp_path("rolling-hash-swapped-delta");
+        let mut delta_bytes: Vec<u8> = Vec::new();
+        write_delta(&[VerifyMatch::Match(0)], &mut delta_bytes).unwrap();
+        std::fs::write(&delta_path, &delta_bytes).unwrap();
+        let diff_path = delta_path.with_extension("out");
+
+        let error = write_diff_file(
+            &read_handler(&delta_path).unwrap(),
+            &read_handler(Path::new("data/new.txt")).unwrap(),
+            &mut File::create(&diff_path).unwrap(),
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            "invalid signature: the signature file argument is not a signature file but a delta \
+             file; are the signature and new file arguments swapped?",
+            error.to_string()
+        );
+        std::fs::remove_file(delta_path).unwrap();
+        std::fs::remove_file(diff_path).unwrap();
+    }
+
+    #[test]
+    pub fn test_diff_batch_rejects_duplicate_file_names() {
+        let signature_file = read_handler(Path::new("data/signature")).unwrap();
+        let new_files = vec![
+            PathBuf::from("data/new.txt"),
+            PathBuf::from("./data/new.txt"),
+        ];
+        let error = diff_batch(
+            &signature_file,
+            &new_files,
+            &std::env::temp_dir(),
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+            &OutputOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn test_speculated_strong_hashes_match_serial_scan() {
+        // Bytes of three values make weak hash hits, true and false, at most windows.
+        // The edited file is long enough for several batches on every thread count
+        let low_entropy_old: Vec<u8> = gen_data(31, 40_000).iter().map(|b| b % 3).collect();
+        let low_entropy_new = apply_edits(&low_entropy_old, 31, 50);
+        let random_old = gen_data(32, 100_000);
+        let random_new = apply_edits(&random_old, 32, 20);
+        let (heavy_signature, heavy_new) = collision_heavy_input();
+        let inputs = [
+            (get_signature(&low_entropy_old, 4), low_entropy_new, 4),
+            (get_signature(&random_old, 64), random_new, 64),
+            (heavy_signature, heavy_new, 4),
+        ];
+
+        for (signature, new_file, chunk_size) in &inputs {
+            for position_hints in [false, true] {
+                let diff_on = |strong_hash_threads: usize| {
+                    let options = DiffOptions {
+                        position_hints,
+                        strong_hash_threads,
+                        ..DiffOptions::default()
+                    };
+                    generate_diff_with_options(new_file, signature, *chunk_size, &options).unwrap()
+                };
+                let serial = diff_on(1);
+                for threads in [2, 3, 8] {
+                    let speculated = diff_on(threads);
+                    assert_eq!(serial.delta, speculated.delta, "{} threads", threads);
+                    assert_eq!(serial.weak_lookups, speculated.weak_lookups);
+                    assert!(speculated.strong_hashes >= serial.strong_hashes);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn test_parallel_diff_batch_matches_sequential() {
+        let root = temp_path("rolling-hash-parallel-batch");
+        let (sequential_dir, parallel_dir) = (root.join("sequential"), root.join("parallel"));
+        std::fs::create_dir_all(&sequential_dir).unwrap();
+        std::fs::create_dir_all(&parallel_dir).unwrap();
+
+        let old_file = gen_data(11, 8192);
+        let signature_path = root.join("signature");
+        let signature = get_signature(&old_file, 64);
+        write_signature(&signature, &mut File::create(&signature_path).unwrap()).unwrap();
+        let new_files: Vec<PathBuf> = (0..24u64)
+            .map(|seed| {
+                let path = root.join(format!("new-{}", seed));
+                std::fs::write(&path, apply_edits(&old_file, seed, seed as usize)).unwrap();
+                path
+            })
+            .collect();
+
+        let sequential = diff_batch(
+            &read_handler(&signature_path).unwrap(),
+            &new_files,
+            &sequential_dir,
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        let parallel = diff_batch_parallel(
+            &read_handler(&signature_path).unwrap(),
+            &new_files,
+            &parallel_dir,
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+            &OutputOptions::default(),
+            4,
+        )
+        .unwrap();
+
+        assert_eq!(new_files.len(), parallel.len());
+        for ((sequential_path, sequential_stats), (parallel_path, parallel_stats)) in
+            sequential.iter().zip(&parallel)
+        {
+            assert_eq!(sequential_path.file_name(), parallel_path.file_name());
+            assert_eq!(sequential_stats, parallel_stats);
+            assert_eq!(
+                std::fs::read(sequential_path).unwrap(),
+                std::fs::read(parallel_path).unwrap()
+            );
+        }
+
+        // A missing new file fails the batch, however many threads there are
+        let mut with_missing = new_files.clone();
+        with_missing.push(root.join("missing"));
+        let error = diff_batch_parallel(
+            &read_handler(&signature_path).unwrap(),
+            &with_missing,
+            &parallel_dir,
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+            &OutputOptions::default(),
+            64,
+        )
+        .unwrap_err();
+        assert_eq!(ErrorKind::NotFound, error.kind());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    pub fn test_validate_delta_flags_out_of_range_index() {
+        let delta = vec![
+            VerifyMatch::Match(0),
+            VerifyMatch::NoMatch(vec![1, 2]),
+            VerifyMatch::Match(3),
+            VerifyMatch::Match(4),
+        ];
+        assert!(validate_delta(&delta, 5).is_ok());
+
+        let error = validate_delta(&delta, 3).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert_eq!(
+            "delta entry 2 matches block 3, but the old file has 3 blocks",
+            error.to_string()
+        );
+        assert!(validate_delta(&[], 0).is_ok());
+    }
+
+    #[test]
+    pub fn test_file_functions_match_slice_api() {
+        let signature_path = temp_path("rolling-hash-slice-api-signature");
+        let delta_path = temp_path("rolling-hash-slice-api-delta");
+        let old_file = include_bytes!("../../data/old.txt");
+        let new_file = include_bytes!("../../data/new.txt");
+
+        // Signed a block at a time, and a batch at a time on threads
+        for threads in [0, 4] {
+            write_signature_file(
+                &File::open("data/old.txt").unwrap(),
+                &mut File::create(&signature_path).unwrap(),
+                &RetryPolicy::default(),
+                &SignatureOptions {
+                    threads,
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap();
+            let signature = read_signature_file(&File::open(&signature_path).unwrap()).unwrap();
+            assert_eq!(
+                crate::signature_of(old_file, signature.block_chunk_size),
+                signature
+            );
+
+            write_diff_with_signature(
+                &signature,
+                &File::open("data/new.txt").unwrap(),
+                &mut File::create(&delta_path).unwrap(),
+                &DiffOptions::default(),
+                &ReadOptions::default(),
+            )
+            .unwrap();
+            let delta = read_delta(&mut BufReader::new(File::open(&delta_path).unwrap())).unwrap();
+            assert_eq!(crate::diff_of(&signature, new_file), delta);
+        }
+        std::fs::remove_file(signature_path).unwrap();
+        std::fs::remove_file(delta_path).unwrap();
+    }
+
+    #[test]
+    pub fn test_diff_round_trip_on_edited_data() {
+        for seed in 0..8u64 {
+            let old_file = gen_data(seed, 4096 + 517 * seed as usize);
+            let new_file = apply_edits(&old_file, seed, 5);
+            let signature = get_signature(&old_file, 64);
+
+            let delta = generate_diff(&new_file, &signature, 64);
+            assert_eq!(
+                new_file,
+                reconstruct(&old_file, &delta, 64),
+                "seed {}",
+                seed
+            );
+
+            let optimized = optimize_delta(delta, &new_file, 64);
+            assert_eq!(
+                new_file,
+                reconstruct(&old_file, &optimized, 64),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_diff_round_trip_property() {
+        // The fuzz target's property on many small seeded cases: tiny block sizes, files
+        // shorter than a block, empty files, short last blocks and every rolling checksum
+        let mut rng = SeededRng::new(296);
+        let (mut small_alphabet_matched, mut deduped) = (0, 0);
+        for case in 0..2000u64 {
+            let block_size = 1 + rng.below(16) as u32;
+            let kind = rng.below(3);
+            let mut old_file = gen_data(case, rng.below(200));
+            // Small alphabets repeat blocks and collide weak hashes, in both files
+            if kind == 2 {
+                old_file.iter_mut().for_each(|byte| *byte %= 3);
+            }
+            let new_file = match kind {
+                0 => gen_data(case + 1, rng.below(200)),
+                1 => apply_edits(&old_file, case, 1 + rng.below(4)),
+                _ => apply_edits(&old_file, case, 2)
+                    .iter()
+                    .map(|byte| byte % 3)
+                    .collect(),
+            };
+            let options = SignatureOptions {
+                rolling_checksum: [
+                    RollingChecksumAlgorithm::Prime,
+                    RollingChecksumAlgorithm::WidePrime,
+                    RollingChecksumAlgorithm::Adler32,
+                ][rng.below(3)],
+                dedup: rng.below(2) == 0,
+                ..SignatureOptions::default()
+            };
+            let signature = get_signature_with_options(&old_file, block_size, &options).unwrap();
+
+            let delta = generate_diff(&new_file, &signature, block_size as usize);
+            let mut rebuilt: Vec<u8> = Vec::new();
+            apply_patch(&old_file, &delta, block_size, &mut rebuilt).unwrap();
+            assert_eq!(new_file, rebuilt, "case {}", case);
+            if kind == 2 && delta.iter().any(VerifyMatch::is_match) {
+                small_alphabet_matched += 1;
+            }
+            if !signature.duplicates.is_empty() {
+                deduped += 1;
+            }
+        }
+        // The small alphabet cases exercise matching and dedup, not only literals
+        assert!(small_alphabet_matched > 400, "{}", small_alphabet_matched);
+        assert!(deduped > 100, "{}", deduped);
+    }
+
+    #[test]
+    pub fn test_combined_delta_equals_sequential_application() {
+        let apply = |old: &[u8], delta: &[VerifyMatch], block_size: u32| {
+            let mut new: Vec<u8> = Vec::new();
+            apply_patch(old, delta, block_size, &mut new).unwrap();
+            new
+        };
+        for seed in 0..8u64 {
+            let v1 = gen_data(seed, 3000 + 131 * seed as usize);
+            let v2 = apply_edits(&v1, seed, 4);
+            let v3 = apply_edits(&v2, seed + 100, 4);
+            // The intermediate signature's blocks straddle the old file's
+            let first = generate_diff(&v2, &get_signature(&v1, 64), 64);
+            let intermediate_signature = get_signature(&v2, 48);
+            let second = generate_diff(&v3, &intermediate_signature, 48);
+
+            let combined =
+                combine_deltas(&first, &second, &v1, 64, &intermediate_signature).unwrap();
+            let sequential = apply(&apply(&v1, &first, 64), &second, 48);
+            assert_eq!(v3, sequential, "seed {}", seed);
+            assert_eq!(sequential, apply(&v1, &combined, 64), "seed {}", seed);
+            assert!(combined.iter().any(VerifyMatch::is_match), "seed {}", seed);
+        }
+
+        // The second delta must have been generated against the first's new file
+        let v1 = gen_data(8, 1000);
+        let first = generate_diff(&v1, &get_signature(&v1, 64), 64);
+        let error = combine_deltas(&first, &[], &v1, 64, &get_signature(&v1[1..], 64)).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    pub fn test_diff_progress_reaches_length() {
+        let old_file = gen_data(11, 50_000);
+        let new_file = apply_edits(&old_file, 11, 20);
+        let signature = get_signature(&old_file, 64);
+        let reports: RefCell<Vec<(u64, u64)>> = RefCell::new(Vec::new());
+        let progress =
+            RefCell::new(|processed, total| reports.borrow_mut().push((processed, total)));
+        let options = DiffOptions {
+            progress: Some(&progress),
+            ..DiffOptions::default()
+        };
+
+        let outcome = generate_diff_with_options(&new_file, &signature, 64, &options).unwrap();
+        assert_eq!(generate_diff(&new_file, &signature, 64), outcome.delta);
+        let reports = reports.into_inner();
+        let total = new_file.len() as u64;
+        assert!(reports.len() <= new_file.len() / (64 * PROGRESS_INTERVAL_BLOCKS as usize) + 1);
+        assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(Some(&(total, total)), reports.last());
+    }
+
+    #[test]
+    pub fn test_diff_rejects_invalid_block_size() {
+        let signature = get_signature(&[1u8; 100], 64);
+        for chunk_size in [0, MAX_BLOCK_SIZE as usize + 1] {
+            let error = generate_diff_with_options(
+                &[1u8; 100],
+                &signature,
+                chunk_size,
+                &DiffOptions::default(),
+            )
+            .unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, error.kind(), "{}", chunk_size);
+        }
+    }
+
+    #[test]
+    pub fn test_positioned_diff_offsets() {
+        let old_file = gen_data(12, 5000);
+        let new_file = apply_edits(&old_file, 12, 8);
+        let signature = get_signature(&old_file, 64);
+        assert_ne!(64, signature.last_block_size());
+
+        let positioned = generate_diff_positioned(&new_file, &signature, 64);
+        let delta = generate_diff(&new_file, &signature, 64);
+        assert!(positioned.iter().map(|(_, entry)| entry).eq(delta.iter()));
+        assert_eq!(Some(0), positioned.first().map(|(offset, _)| *offset));
+        assert!(positioned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // Each entry is the bytes of the new file at its offset, and they add up to all of it
+        let blocks: Vec<&[u8]> = old_file.chunks(64).collect();
+        let mut end = 0usize;
+        for (offset, entry) in &positioned {
+            let bytes = match entry {
+                VerifyMatch::Match(index) => blocks[*index as usize],
+                VerifyMatch::NoMatch(bytes) => bytes.as_slice(),
+            };
+            assert_eq!(end as u64, *offset);
+            assert_eq!(&new_file[end..end + bytes.len()], bytes);
+            end += bytes.len();
+        }
+        assert_eq!(new_file.len(), end);
+    }
+
+    #[test]
+    pub fn test_streaming_diff_matches_in_memory() {
+        for seed in 0..4u64 {
+            let old_file = gen_data(seed, 4096 + 517 * seed as usize);
+            let new_file = apply_edits(&old_file, seed, 5);
+            for rolling_checksum in [
+                RollingChecksumAlgorithm::Prime,
+                RollingChecksumAlgorithm::WidePrime,
+                RollingChecksumAlgorithm::Adler32,
+            ] {
+                let options = SignatureOptions {
+                    rolling_checksum,
+                    ..SignatureOptions::default()
+                };
+                let signature = get_signature_with_options(&old_file, 64, &options).unwrap();
+                let streamed: Vec<VerifyMatch> =
+                    generate_diff_streaming(ShortReads(&new_file), &signature)
+                        .collect::<Result<_>>()
+                        .unwrap();
+                assert_eq!(
+                    generate_diff(&new_file, &signature, 64),
+                    streamed,
+                    "seed {} {:?}",
+                    seed,
+                    rolling_checksum
+                );
+            }
+        }
+
+        // Unmatched input longer than a literal entry is flushed as the scan goes
+        let old_file = gen_data(9, 1000);
+        let signature = get_signature(&old_file, 64);
+        let new_file = [
+            &gen_data(10, STREAMING_LITERAL_RUN + 100)[..],
+            &old_file[..],
+        ]
+        .concat();
+        let streamed: Vec<VerifyMatch> = generate_diff_streaming(&new_file[..], &signature)
+            .collect::<Result<_>>()
+            .unwrap();
+        let options = DiffOptions {
+            max_literal_run: Some(STREAMING_LITERAL_RUN),
+            ..DiffOptions::default()
+        };
+        let outcome = generate_diff_with_options(&new_file, &signature, 64, &options).unwrap();
+        assert_eq!(outcome.delta, streamed);
+        assert_eq!(
+            Some(&VerifyMatch::NoMatch(
+                new_file[..STREAMING_LITERAL_RUN].to_vec()
+            )),
+            streamed.first()
+        );
+    }
+
+    #[test]
+    pub fn test_signatures_of_other_moduli_do_not_match() {
+        let old_file = gen_data(22, 8192);
+        let signature_with = |rolling_checksum| {
+            get_signature_with_options(
+                &old_file,
+                64,
+                &SignatureOptions {
+                    rolling_checksum,
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap()
+        };
+        let prime = signature_with(RollingChecksumAlgorithm::Prime);
+        let wide_prime = signature_with(RollingChecksumAlgorithm::WidePrime);
+        assert_ne!(prime.checksum_map, wide_prime.checksum_map);
+
+        // Rolled with the modulus it was summed with, each matches every block
+        for signature in [&prime, &wide_prime] {
+            let delta = generate_diff(&old_file, signature, 64);
+            assert_eq!(128, delta.iter().filter(|entry| entry.is_match()).count());
+        }
+
+        // Entries summed modulo one prime are never found rolling modulo the other
+        let mut mislabelled = signature_with(RollingChecksumAlgorithm::Prime);
+        mislabelled.rolling_checksum = RollingChecksumAlgorithm::WidePrime;
+        let delta = generate_diff(&old_file, &mislabelled, 64);
+        assert!(!delta.iter().any(VerifyMatch::is_match));
+    }
+
+    #[test]
+    pub fn test_adler32_signature_round_trip() {
+        let old_file = gen_data(21, 8192);
+        let new_file = apply_edits(&old_file, 21, 6);
+        for rolling_checksum in [
+            RollingChecksumAlgorithm::Prime,
+            RollingChecksumAlgorithm::WidePrime,
+            RollingChecksumAlgorithm::Adler32,
+        ] {
+            let signature = get_signature_with_options(
+                &old_file,
+                64,
+                &SignatureOptions {
+                    rolling_checksum,
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(rolling_checksum, signature.rolling_checksum);
+
+            let delta = generate_diff(&new_file, &signature, 64);
+            assert_eq!(
+                new_file,
+                reconstruct(&old_file, &delta, 64),
+                "{:?}",
+                rolling_checksum
+            );
+            assert!(delta.iter().any(VerifyMatch::is_match));
+        }
+    }
+
+    #[test]
+    pub fn test_md4_signature_round_trip() {
+        let old_file = gen_data(28, 8192);
+        let new_file = apply_edits(&old_file, 28, 6);
+        for strong_hash_len in [None, Some(8)] {
+            let signature = get_signature_with_options(
+                &old_file,
+                64,
+                &SignatureOptions {
+                    strong_hash: StrongHashAlgorithm::Md4,
+                    strong_hash_len,
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(strong_hash_len.unwrap_or(16), signature.strong_hash_len);
+
+            for position_hints in [false, true] {
+                let options = DiffOptions {
+                    position_hints,
+                    ..DiffOptions::default()
+                };
+                let delta = generate_diff_with_options(&new_file, &signature, 64, &options)
+                    .unwrap()
+                    .delta;
+                assert_eq!(new_file, reconstruct(&old_file, &delta, 64));
+                assert!(delta.iter().any(VerifyMatch::is_match));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_dedup_signature_round_trip() {
+        // Mostly zero filled, like a sparse disk image, with a repeated record
+        let mut old_file = vec![0u8; 64 * 200];
+        old_file.extend_from_slice(&gen_data(22, 64 * 20));
+        old_file.extend_from_slice(&gen_data(23, 64).repeat(30));
+        old_file.extend_from_slice(&[0u8; 64 * 50]);
+        let new_file = apply_edits(&old_file, 22, 8);
+
+        let plain = get_signature(&old_file, 64);
+        let deduped = get_signature_with_options(
+            &old_file,
+            64,
+            &SignatureOptions {
+                dedup: true,
+                ..SignatureOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(plain.block_count(), deduped.block_count());
+        assert_eq!(22, deduped.entry_count());
+        assert_eq!(3, deduped.duplicates.len());
+        let mut plain_bytes = Vec::new();
+        write_signature(&plain, &mut plain_bytes).unwrap();
+        let mut deduped_bytes = Vec::new();
+        write_signature(&deduped, &mut deduped_bytes).unwrap();
+        assert!(
+            deduped_bytes.len() * 5 < plain_bytes.len(),
+            "{} vs {} bytes",
+            deduped_bytes.len(),
+            plain_bytes.len()
+        );
+        assert_eq!(
+            deduped,
+            read_signature(&mut deduped_bytes.as_slice()).unwrap()
+        );
+
+        for position_hints in [false, true] {
+            let options = DiffOptions {
+                position_hints,
+                ..DiffOptions::default()
+            };
+            let delta = generate_diff_with_options(&new_file, &deduped, 64, &options)
+                .unwrap()
+                .delta;
+            assert_eq!(new_file, reconstruct(&old_file, &delta, 64));
+            assert!(deduped.check_delta(&delta).is_ok());
+        }
+    }
+
+    #[test]
+    pub fn test_diff_against_empty_signature() {
+        let signature = get_signature(&[], find_blocksize(0));
+        let new_file = gen_data(24, 1000);
+
+        let outcome =
+            generate_diff_with_options(&new_file, &signature, 64, &DiffOptions::default()).unwrap();
+        assert_eq!(vec![VerifyMatch::NoMatch(new_file.clone())], outcome.delta);
+        assert_eq!(0, outcome.weak_lookups);
+        assert!(generate_diff(&[], &signature, 64).is_empty());
+    }
+
+    #[test]
+    pub fn test_max_literal_run_bounds_literal_entries() {
+        let old_file = gen_data(26, 64 * 16);
+        let signature = get_signature(&old_file, 64);
+        let mut new_file = old_file[..64 * 8].to_vec();
+        new_file.extend_from_slice(&gen_data(27, 10 << 20));
+        new_file.extend_from_slice(&old_file[64 * 8..]);
+
+        let max_run = 1 << 20;
+        for optimize in [false, true] {
+            let options = DiffOptions {
+                max_literal_run: Some(max_run),
+                optimize,
+                ..DiffOptions::default()
+            };
+            let delta = generate_diff_with_options(&new_file, &signature, 64, &options)
+                .unwrap()
+                .delta;
+            let literal_runs: Vec<usize> = delta
+                .iter()
+                .filter_map(|entry| entry.literal_bytes().map(<[u8]>::len))
+                .collect();
+            assert_eq!(10, literal_runs.len());
+            assert!(literal_runs.iter().all(|len| *len <= max_run));
+            assert_eq!(16, delta.iter().filter(|entry| entry.is_match()).count());
+            assert_eq!(new_file, reconstruct(&old_file, &delta, 64));
+        }
+
+        let options = DiffOptions {
+            max_literal_run: Some(3),
+            ..DiffOptions::default()
+        };
+        let outcome =
+            generate_diff_with_options(b"abcdefg", &get_signature(&[], 64), 64, &options).unwrap();
+        assert_eq!(
+            vec![
+                VerifyMatch::NoMatch(b"abc".to_vec()),
+                VerifyMatch::NoMatch(b"def".to_vec()),
+                VerifyMatch::NoMatch(b"g".to_vec()),
+            ],
+            outcome.delta
+        );
+    }
+
+    #[test]
+    pub fn test_borrowed_literals_slice_new_file() {
+        let old_file = gen_data(28, 64 * 32);
+        let signature = get_signature(&old_file, 64);
+        // Mostly changed: one block in four of the old file survives
+        let mut new_file: Vec<u8> = Vec::new();
+        for (index, block) in old_file.chunks(64).enumerate() {
+            if index % 4 == 0 {
+                new_file.extend_from_slice(block);
+            } else {
+                new_file.extend_from_slice(&gen_data(index as u64, 100));
+            }
+        }
+
+        for max_literal_run in [None, Some(50)] {
+            let options = DiffOptions {
+                max_literal_run,
+                ..DiffOptions::default()
+            };
+            let borrowed = generate_diff_borrowed(&new_file, &signature, 64, &options).unwrap();
+            let buffer = new_file.as_ptr_range();
+            assert!(borrowed.delta.iter().all(|entry| match entry {
+                DeltaEntry::Literal(bytes) => buffer.contains(&bytes.as_ptr()),
+                DeltaEntry::Match(_) => true,
+            }));
+
+            let mut borrowed_file: Vec<u8> = Vec::new();
+            write_delta_entries(
+                borrowed.delta.iter().copied(),
+                BlockIndexBase::One,
+                64,
+                &mut borrowed_file,
+            )
+            .unwrap();
+            let owned = generate_diff_with_options(&new_file, &signature, 64, &options).unwrap();
+            let mut owned_file: Vec<u8> = Vec::new();
+            write_delta_with_base(&owned.delta, BlockIndexBase::One, 64, &mut owned_file).unwrap();
+            assert_eq!(owned_file, borrowed_file);
+            assert_eq!(owned, borrowed.into_owned());
+        }
+    }
+
+    #[test]
+    pub fn test_delta_header() {
+        let delta = vec![VerifyMatch::Match(1), VerifyMatch::NoMatch(b"new".to_vec())];
+        let mut written: Vec<u8> = Vec::new();
+        write_delta(&delta, &mut written).unwrap();
+        assert_eq!(b"RHDL\x01\0\0\0\0\0\x02\0\0\0\0\0\0\0", &written[..18]);
+        assert_eq!(delta, read_delta(&mut written.as_slice()).unwrap());
+
+        // Delta written before the header was introduced
+        let legacy = bincode::serialize(&delta).unwrap();
+        let error = read_delta(&mut legacy.as_slice()).unwrap_err();
+        assert_eq!(
+            "invalid delta: not a delta file (no RHDL header)",
+            error.to_string()
+        );
+
+        // A signature where a delta is expected
+        let mut signature: Vec<u8> = Vec::new();
+        write_signature(&get_signature(b"old", 64), &mut signature).unwrap();
+        let error = read_delta(&mut signature.as_slice()).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::InvalidDelta(_)),
+            "{:?}",
+            error
+        );
+
+        written[4] = DELTA_VERSION + 1;
+        let error = read_delta(&mut written.as_slice()).unwrap_err();
+        assert!(
+            error.to_string().contains("version 2"),
+            "{}",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    pub fn test_truncated_delta_reports_entry_count() {
+        let delta = vec![
+            VerifyMatch::Match(0),
+            VerifyMatch::NoMatch(b"literal".to_vec()),
+            VerifyMatch::Match(2),
+        ];
+        let mut written: Vec<u8> = Vec::new();
+        write_delta(&delta, &mut written).unwrap();
+
+        // Cut in the middle of the literal, the second entry
+        let first_entry_end = 18 + serialized_size(&delta[0]).unwrap() as usize;
+        let error = read_delta(&mut &written[..first_entry_end + 6]).unwrap_err();
+        assert_eq!(
+            "invalid delta: truncated delta: 1 of 3 declared entries decoded",
+            error.to_string()
+        );
+        // Cut inside the entry count
+        let error = read_delta(&mut &written[..14]).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::InvalidDelta(_)),
+            "{:?}",
+            error
+        );
+        // Cut between entries
+        let error = read_delta(&mut &written[..written.len() - 12]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("2 of 3 declared entries decoded"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    pub fn test_block_index_bases_round_trip_through_apply() {
+        let old_file = gen_data(207, 1000);
+        let signature = get_signature(&old_file, 64);
+        let new_file = apply_edits(&old_file, 207, 5);
+        let delta = generate_diff(&new_file, &signature, 64);
+        assert!(delta.iter().any(VerifyMatch::is_match));
+
+        for (base, first_match) in [
+            (BlockIndexBase::Zero, 0),
+            (BlockIndexBase::One, 1),
+            (BlockIndexBase::Offset, 0),
+        ] {
+            let mut written: Vec<u8> = Vec::new();
+            write_delta_with_base(&delta, base, 64, &mut written).unwrap();
+            assert_eq!(base.tag(), written[5]);
+            // The first entry's serialized block number, after the tag of the enum variant
+            let serialized =
+                |entries: &[u8]| u64::from_le_bytes(entries[4..12].try_into().unwrap());
+            if let VerifyMatch::Match(index) = delta[0] {
+                let expected = match base {
+                    BlockIndexBase::Offset => index * 64,
+                    _ => index + first_match,
+                };
+                assert_eq!(expected, serialized(&written[18..]));
+            }
+
+            let read = read_delta(&mut written.as_slice()).unwrap();
+            assert_eq!(delta, read, "{:?}", base);
+            assert_eq!(
+                new_file,
+                apply_from_slices(&old_file, &written, 64),
+                "{:?}",
+                base
+            );
+        }
+
+        // Values with no 0-based index are rejected
+        let mut written: Vec<u8> = Vec::new();
+        write_delta_with_base(
+            &[VerifyMatch::Match(0)],
+            BlockIndexBase::Zero,
+            64,
+            &mut written,
+        )
+        .unwrap();
+        written[5] = BlockIndexBase::One.tag();
+        assert!(read_delta(&mut written.as_slice())
+            .unwrap_err()
+            .to_string()
+            .contains("0 is not a 1-based block number"));
+        let mut written: Vec<u8> = Vec::new();
+        write_delta_with_base(
+            &[VerifyMatch::Match(1)],
+            BlockIndexBase::Offset,
+            64,
+            &mut written,
+        )
+        .unwrap();
+        written[6..10].copy_from_slice(&48u32.to_le_bytes());
+        assert!(read_delta(&mut written.as_slice())
+            .unwrap_err()
+            .to_string()
+            .contains("64 is not a byte offset block number for blocks of 48 bytes"));
+    }
+
+    #[test]
+    pub fn test_diff_stats_of_hand_built_delta() {
+        let delta = vec![
+            VerifyMatch::Match(0),
+            VerifyMatch::NoMatch(vec![1; 100]),
+            VerifyMatch::Match(5),
+            VerifyMatch::Match(6),
+            VerifyMatch::NoMatch(vec![2; 60]),
+        ];
+        let stats = diff_stats(&delta, 1024);
+        assert_eq!(3, stats.matched_blocks);
+        assert_eq!(3072, stats.matched_bytes);
+        assert_eq!(160, stats.literal_bytes);
+        assert_eq!(3232, stats.new_file_length);
+        let mut written: Vec<u8> = Vec::new();
+        write_delta(&delta, &mut written).unwrap();
+        assert_eq!(written.len() as u64, stats.delta_size);
+        assert!((stats.reuse() - 3072.0 / 3232.0).abs() < 1e-9);
+        assert!(stats.compression_ratio() < 0.1);
+        assert_eq!(
+            "matched 3 blocks (3 KiB), 160 B literal, 95% reuse",
+            stats.to_string()
+        );
+
+        let empty = diff_stats(&[], 1024);
+        assert_eq!(0, empty.new_file_length);
+        assert_eq!(0.0, empty.reuse());
     }
 }
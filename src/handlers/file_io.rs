@@ -1,47 +1,525 @@
-use std::fs::File;
-use std::io::{BufReader, Read, Result};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const RETRY_READ_SIZE: usize = 64 * 1024;
 
 pub fn read_file_to_buffer(reader: &mut BufReader<&File>) -> Result<Vec<u8>> {
-    let mut buffer: Vec<u8> = Vec::new();
+    // Allocate a regular file's length up front instead of growing the buffer as it fills.
+    // Pipes and other streams report no useful length and grow as before
+    let metadata = reader.get_ref().metadata()?;
+    let capacity = if metadata.is_file() {
+        usize::try_from(metadata.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    let mut buffer: Vec<u8> = Vec::with_capacity(capacity);
     reader.read_to_end(&mut buffer)?;
     Ok(buffer)
 }
 
-pub fn read_handler(input_path: &Path) -> Result<File> {
-    match File::open(input_path) {
-        Ok(file) => Ok(file),
-        Err(err) => {
-            eprintln!(
-                "cannot open file for reading: {:?}, error: {}",
-                input_path, err
-            );
-            Err(err)
+// How many times to retry a read failing with a transient error, and the delay before
+// the first retry. The delay doubles with each consecutive failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, failures: u32) -> Duration {
+        self.delay.saturating_mul(2u32.saturating_pow(failures))
+    }
+}
+
+// Options for reading an input which may be a slow or flaky stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadOptions {
+    // Give up on a stream whose producer hasn't closed it within timeout
+    pub timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+}
+
+// Transient errors, e.g. from a network filesystem, are worth retrying.
+// Others, like NotFound or PermissionDenied, won't go away by waiting
+fn is_retryable(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+// Like read_file_to_buffer, but retries reads failing with a transient error
+pub fn read_file_to_buffer_with_retry(
+    reader: &mut impl Read,
+    retry: &RetryPolicy,
+) -> Result<Vec<u8>> {
+    read_blocks_to_eof(reader, RETRY_READ_SIZE, retry)
+}
+
+// Fill block from the reader, returning how many bytes were read. Fewer than the block's
+// length only at EOF. Retries reads failing with a transient error
+pub fn read_block(reader: &mut impl Read, block: &mut [u8], retry: &RetryPolicy) -> Result<usize> {
+    let mut filled = 0usize;
+    let mut failures = 0u32;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(len) => {
+                filled += len;
+                failures = 0;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) if is_retryable(&err) && failures < retry.retries => {
+                thread::sleep(retry.backoff(failures));
+                failures += 1;
+            }
+            Err(err) => return Err(err),
         }
     }
+    Ok(filled)
+}
+
+// FIFOs, sockets and character devices report no meaningful length
+// and only reach EOF once the producer closes its end
+pub fn is_stream(file: &File) -> Result<bool> {
+    Ok(!file.metadata()?.is_file())
+}
+
+fn read_blocks_to_eof(
+    stream: &mut impl Read,
+    block_size: usize,
+    retry: &RetryPolicy,
+) -> Result<Vec<u8>> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut block = vec![0u8; block_size.max(1)];
+    let mut failures = 0u32;
+    loop {
+        match stream.read(&mut block) {
+            Ok(0) => return Ok(buffer),
+            Ok(len) => {
+                buffer.extend_from_slice(&block[..len]);
+                failures = 0;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) if is_retryable(&err) && failures < retry.retries => {
+                thread::sleep(retry.backoff(failures));
+                failures += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Read a stream to EOF in block sized reads.
+// Gives up with ErrorKind::TimedOut if the producer hasn't closed the stream
+// within the read timeout
+pub fn read_stream_to_buffer(
+    file: &File,
+    block_size: usize,
+    read_options: &ReadOptions,
+) -> Result<Vec<u8>> {
+    let mut stream = file.try_clone()?;
+    let retry = read_options.retry;
+    let timeout = match read_options.timeout {
+        Some(timeout) => timeout,
+        None => return read_blocks_to_eof(&mut stream, block_size, &retry),
+    };
+
+    // The reader thread is left blocked if the producer never closes the stream
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read_blocks_to_eof(&mut stream, block_size, &retry));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("stream not closed within {:?}", timeout),
+        )),
+    }
+}
+
+// Path standing for standard input where a file is read, and standard output where one is written
+pub const STDIO_PATH: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO_PATH
+}
+
+// Standard input or output as a File, so that the handlers taking a File work on it unchanged.
+// The File owns a duplicate of the handle: dropping it leaves the process's stream open
+#[cfg(unix)]
+fn duplicate_stdio(stdio: &impl std::os::fd::AsFd) -> Result<File> {
+    Ok(File::from(stdio.as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(windows)]
+fn duplicate_stdio(stdio: &impl std::os::windows::io::AsHandle) -> Result<File> {
+    Ok(File::from(stdio.as_handle().try_clone_to_owned()?))
+}
+
+pub fn read_handler(input_path: &Path) -> Result<File> {
+    if is_stdio(input_path) {
+        return duplicate_stdio(&std::io::stdin());
+    }
+    // Keep the kind so callers can still match on NotFound and the like
+    File::open(input_path).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!("cannot open {} for reading: {}", input_path.display(), err),
+        )
+    })
 }
 
 pub fn write_handler(output_path: &Path) -> Result<File> {
-    match File::create(output_path) {
-        Ok(file) => Ok(file),
-        Err(err) => {
-            eprintln!(
-                "cannot open file for writing: {:?}, error: {}",
-                output_path, err
-            );
-            Err(err)
+    if is_stdio(output_path) {
+        return duplicate_stdio(&std::io::stdout());
+    }
+    // Keep the kind so callers can still match on NotFound and the like
+    File::create(output_path).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!("cannot open {} for writing: {}", output_path.display(), err),
+        )
+    })
+}
+
+// Options for writing an AtomicOutput
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputOptions {
+    // Keep the temporary file for inspection when the output isn't committed
+    pub retain_temp: bool,
+    // fsync the temporary file before the rename and its directory after,
+    // so a committed output survives a crash
+    pub durable: bool,
+}
+
+// Output written to a temporary file next to its destination and renamed over it on commit,
+// so a failed generation never leaves a partially written output behind.
+// Dropping it without committing removes the temporary file, unless retain_temp
+// is set to keep it around for inspection.
+// Standard output ("-") is written directly, and can't be taken back on failure
+pub struct AtomicOutput {
+    file: File,
+    temp_path: PathBuf,
+    output_path: PathBuf,
+    options: OutputOptions,
+    committed: bool,
+}
+
+// Persist a rename or file creation in the directory entry itself
+fn sync_directory(directory: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = directory;
+    Ok(())
+}
+
+impl AtomicOutput {
+    pub fn create(output_path: &Path, options: &OutputOptions) -> Result<Self> {
+        if is_stdio(output_path) {
+            return Ok(Self {
+                file: write_handler(output_path)?,
+                temp_path: PathBuf::new(),
+                output_path: output_path.to_path_buf(),
+                options: *options,
+                committed: false,
+            });
         }
+        let file_name = output_path
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "output path has no file name"))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".tmp-{}", std::process::id()));
+        let temp_path = output_path.with_file_name(temp_name);
+
+        let file = write_handler(&temp_path)?;
+        Ok(Self {
+            file,
+            temp_path,
+            output_path: output_path.to_path_buf(),
+            options: *options,
+            committed: false,
+        })
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    // Move the temporary file into place
+    pub fn commit(mut self) -> Result<()> {
+        if is_stdio(&self.output_path) {
+            self.committed = true;
+            return Ok(());
+        }
+        if self.options.durable {
+            self.file.sync_all()?;
+        }
+        fs::rename(&self.temp_path, &self.output_path)?;
+        self.committed = true;
+        if self.options.durable {
+            let directory = match self.output_path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            sync_directory(directory)?;
+        }
+        Ok(())
     }
 }
 
+impl Drop for AtomicOutput {
+    fn drop(&mut self) {
+        if self.committed || is_stdio(&self.output_path) {
+            return;
+        }
+        if self.options.retain_temp {
+            eprintln!("retained temporary file: {:?}", self.temp_path);
+        } else {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::handlers::testutil::temp_path;
 
     #[test]
     pub fn test_read_handler() {
         let new_file: File = read_handler(Path::new("data/new.txt")).unwrap();
         assert_eq!(3096, new_file.metadata().unwrap().len());
+        assert!(!is_stream(&new_file).unwrap());
+    }
+
+    #[test]
+    pub fn test_read_file_to_buffer_allocates_file_length() {
+        let new_file: File = read_handler(Path::new("data/new.txt")).unwrap();
+        let buffer = read_file_to_buffer(&mut BufReader::new(&new_file)).unwrap();
+        assert_eq!(3096, buffer.len());
+        // Allocated once, at the file length
+        assert_eq!(3096, buffer.capacity());
+        assert_eq!(std::fs::read("data/new.txt").unwrap(), buffer);
+    }
+
+    // Fails with the given error kind a number of times before reading the data
+    struct FlakyReader {
+        failures_left: u32,
+        kind: ErrorKind,
+        reads: u32,
+        data: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl FlakyReader {
+        fn new(failures: u32, kind: ErrorKind) -> Self {
+            Self {
+                failures_left: failures,
+                kind,
+                reads: 0,
+                data: std::io::Cursor::new(vec![7u8; 100]),
+            }
+        }
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.reads += 1;
+            if self.failures_left > 0 {
+                self.failures_left -= 1;
+                return Err(Error::new(self.kind, "flaky read"));
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    pub fn test_read_retries_transient_errors() {
+        let retry = RetryPolicy {
+            retries: 3,
+            delay: Duration::from_millis(1),
+        };
+        let mut reader = FlakyReader::new(3, ErrorKind::TimedOut);
+        let buffer = read_file_to_buffer_with_retry(&mut reader, &retry).unwrap();
+        assert_eq!(vec![7u8; 100], buffer);
+
+        let mut reader = FlakyReader::new(4, ErrorKind::TimedOut);
+        let err = read_file_to_buffer_with_retry(&mut reader, &retry).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+    }
+
+    #[test]
+    pub fn test_read_fails_fast_on_permanent_errors() {
+        let retry = RetryPolicy {
+            retries: 3,
+            delay: Duration::from_millis(1),
+        };
+        let mut reader = FlakyReader::new(1, ErrorKind::PermissionDenied);
+        let err = read_file_to_buffer_with_retry(&mut reader, &retry).unwrap_err();
+        assert_eq!(ErrorKind::PermissionDenied, err.kind());
+        assert_eq!(1, reader.reads);
+    }
+
+    #[test]
+    pub fn test_atomic_output_commit() {
+        use std::io::Write;
+
+        for durable in [false, true] {
+            let output_path = temp_path("rolling-hash-atomic-commit");
+            let options = OutputOptions {
+                durable,
+                ..OutputOptions::default()
+            };
+            let mut output = AtomicOutput::create(&output_path, &options).unwrap();
+            assert_eq!(durable, output.options.durable);
+            let temp_path = output.temp_path().to_path_buf();
+            output.file().write_all(b"signature").unwrap();
+            assert!(!output_path.exists());
+
+            output.commit().unwrap();
+            assert!(!temp_path.exists());
+            assert_eq!(b"signature".to_vec(), fs::read(&output_path).unwrap());
+            fs::remove_file(&output_path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_stdio_path_output_creates_no_file() {
+        let options = OutputOptions {
+            durable: true,
+            ..OutputOptions::default()
+        };
+        let output = AtomicOutput::create(Path::new(STDIO_PATH), &options).unwrap();
+        assert!(output.temp_path().as_os_str().is_empty());
+        output.commit().unwrap();
+        assert!(!Path::new(STDIO_PATH).exists());
+        assert!(is_stdio(Path::new("-")));
+        assert!(!is_stdio(Path::new("./-")));
+    }
+
+    #[test]
+    pub fn test_durable_commit_relative_path() {
+        let output_path = PathBuf::from(format!(
+            "rolling-hash-durable-relative-{}",
+            std::process::id()
+        ));
+        let options = OutputOptions {
+            durable: true,
+            ..OutputOptions::default()
+        };
+        AtomicOutput::create(&output_path, &options)
+            .unwrap()
+            .commit()
+            .unwrap();
+        assert!(output_path.exists());
+        fs::remove_file(&output_path).unwrap();
+    }
+
+    #[test]
+    pub fn test_failed_generation_cleans_up_temp_file() {
+        use crate::handlers::file_diff::{write_diff_file, DiffOptions};
+
+        for retain_temp in [false, true] {
+            let options = OutputOptions {
+                retain_temp,
+                ..OutputOptions::default()
+            };
+            let signature_file = read_handler(Path::new("data/signature")).unwrap();
+            // Reading a directory as the new file fails part way through generation
+            let new_file = read_handler(Path::new("data")).unwrap();
+            let output_path = temp_path("rolling-hash-atomic-failure");
+            let mut output = AtomicOutput::create(&output_path, &options).unwrap();
+            let temp_path = output.temp_path().to_path_buf();
+            let result = write_diff_file(
+                &signature_file,
+                &new_file,
+                output.file(),
+                &DiffOptions::default(),
+                &ReadOptions::default(),
+            );
+            assert!(result.is_err());
+            drop(output);
+
+            assert!(!output_path.exists());
+            assert_eq!(retain_temp, temp_path.exists());
+            let _ = fs::remove_file(&temp_path);
+        }
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    fn make_fifo(name: &str) -> std::path::PathBuf {
+        let path = temp_path(name);
+        let _ = std::fs::remove_file(&path);
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_read_stream_from_fifo() {
+        use std::io::Write;
+
+        let fifo = make_fifo("rolling-hash-fifo-read");
+        let producer_path = fifo.clone();
+        let producer = thread::spawn(move || {
+            let mut producer = File::create(producer_path).unwrap();
+            producer.write_all(&[42u8; 1000]).unwrap();
+        });
+
+        let stream = read_handler(&fifo).unwrap();
+        assert!(is_stream(&stream).unwrap());
+        let read_options = ReadOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..ReadOptions::default()
+        };
+        let buffer = read_stream_to_buffer(&stream, 64, &read_options).unwrap();
+        producer.join().unwrap();
+        std::fs::remove_file(&fifo).unwrap();
+
+        assert_eq!(vec![42u8; 1000], buffer);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_read_stream_times_out_on_open_fifo() {
+        let fifo = make_fifo("rolling-hash-fifo-timeout");
+        let producer_path = fifo.clone();
+        let producer = thread::spawn(move || {
+            let producer = File::create(producer_path).unwrap();
+            thread::sleep(Duration::from_millis(500));
+            drop(producer);
+        });
+
+        let stream = read_handler(&fifo).unwrap();
+        let read_options = ReadOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..ReadOptions::default()
+        };
+        let err = read_stream_to_buffer(&stream, 64, &read_options).unwrap_err();
+        producer.join().unwrap();
+        std::fs::remove_file(&fifo).unwrap();
+
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+    }
+}
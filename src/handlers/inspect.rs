@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Result, Write};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::error::RollingHashError;
+use super::file_diff::{read_delta, VerifyMatch};
+use super::signature::{BlockChunkHashes, DuplicateRun, FileChunkSignature, StrongHashAlgorithm};
+use super::window_checksum::RollingChecksumAlgorithm;
+
+const HEXDUMP_LINE_WIDTH: usize = 16;
+
+// Render bytes as a classic hexdump: offset, hex bytes in two groups of 8 and an ASCII gutter.
+// Non-printable bytes show as '.' in the gutter. At most max_lines lines are rendered,
+// followed by a line counting the bytes left out
+pub fn hexdump_lines(bytes: &[u8], max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for (line_index, line_bytes) in bytes.chunks(HEXDUMP_LINE_WIDTH).take(max_lines).enumerate() {
+        let mut hex = String::new();
+        for column in 0..HEXDUMP_LINE_WIDTH {
+            if column == HEXDUMP_LINE_WIDTH / 2 {
+                hex.push(' ');
+            }
+            match line_bytes.get(column) {
+                Some(byte) => hex.push_str(&format!("{:02x} ", byte)),
+                None => hex.push_str("   "),
+            }
+        }
+        let ascii: String = line_bytes
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        lines.push(format!(
+            "{:08x}  {} |{}|",
+            line_index * HEXDUMP_LINE_WIDTH,
+            hex,
+            ascii
+        ));
+    }
+
+    let shown = (max_lines * HEXDUMP_LINE_WIDTH).min(bytes.len());
+    if shown < bytes.len() {
+        lines.push(format!("... {} more bytes", bytes.len() - shown));
+    }
+    lines
+}
+
+// Read the list of matches and literal runs from a delta file
+pub fn read_delta_file(
+    delta_file: &File,
+) -> std::result::Result<Vec<VerifyMatch>, RollingHashError> {
+    read_delta(&mut BufReader::new(delta_file))
+}
+
+// Write one line per delta entry, optionally followed by a hexdump of each literal run
+pub fn write_delta_report(
+    delta: &[VerifyMatch],
+    hexdump_max_lines: Option<usize>,
+    out: &mut impl Write,
+) -> Result<()> {
+    for (position, entry) in delta.iter().enumerate() {
+        match entry {
+            VerifyMatch::Match(index) => writeln!(out, "{:>6}: match block {}", position, index)?,
+            VerifyMatch::NoMatch(bytes) => {
+                writeln!(out, "{:>6}: literal {} bytes", position, bytes.len())?;
+                if let Some(max_lines) = hexdump_max_lines {
+                    for line in hexdump_lines(bytes, max_lines) {
+                        writeln!(out, "        {}", line)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Write a summary of a signature: block size, block and weak hash counts
+// and the approximate memory it takes once loaded
+pub fn write_signature_report(signature: &FileChunkSignature, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "Block size: {} bytes", signature.block_chunk_size)?;
+    writeln!(out, "Blocks: {}", signature.block_count())?;
+    if !signature.duplicates.is_empty() {
+        writeln!(
+            out,
+            "Duplicate blocks: {}",
+            signature.block_count() - signature.entry_count()
+        )?;
+    }
+    writeln!(
+        out,
+        "Distinct weak hashes: {}",
+        signature.checksum_map.len()
+    )?;
+    writeln!(
+        out,
+        "Memory footprint: ~{} bytes",
+        signature.memory_footprint()
+    )
+}
+
+// Write every parameter a signature was generated with, for a signature read in the given
+// format version: block size, chunking, weak checksum and its modulus, strong hash and how
+// much of it is kept, block count and signed file length
+pub fn write_signature_details(
+    signature: &FileChunkSignature,
+    version: u8,
+    out: &mut impl Write,
+) -> Result<()> {
+    writeln!(out, "Format version: {}", version)?;
+    writeln!(out, "Block size: {} bytes", signature.block_chunk_size)?;
+    // Blocks are always cut at multiples of the block size
+    writeln!(out, "Chunking: fixed")?;
+    writeln!(
+        out,
+        "Rolling checksum: {} (modulus {})",
+        value_name(signature.rolling_checksum),
+        signature.rolling_checksum.modulus()
+    )?;
+    writeln!(
+        out,
+        "Strong hash: {}, {} of {} bytes kept",
+        value_name(signature.strong_hash),
+        signature.strong_hash_len,
+        signature.strong_hash.digest_len()
+    )?;
+    writeln!(out, "Total chunks: {}", signature.total_chunks)?;
+    writeln!(out, "Original length: {} bytes", signature.original_length)?;
+    if let Some(hash) = signature.whole_file_hash {
+        writeln!(out, "Whole file SHA-256: {}", to_hex(&hash))?;
+    }
+    if let Some(domain_tag) = &signature.domain_tag {
+        writeln!(out, "Domain tag: {:?}", domain_tag)?;
+    }
+    Ok(())
+}
+
+// Name the algorithm is chosen by on the command line
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+// How the signature's entries share weak hashes. Every entry in a bucket of more than one
+// costs a strong hash when a window of the new file has that weak hash. Repeated blocks
+// share a bucket too, unless the signature was generated with dedup
+#[derive(Debug, PartialEq, Eq)]
+pub struct CollisionReport {
+    pub buckets: usize,
+    // Buckets with more than one entry
+    pub colliding_buckets: usize,
+    // Entries in those buckets
+    pub colliding_entries: usize,
+    pub largest_bucket: usize,
+}
+
+pub fn signature_collision_report(signature: &FileChunkSignature) -> CollisionReport {
+    let mut report = CollisionReport {
+        buckets: signature.checksum_map.len(),
+        colliding_buckets: 0,
+        colliding_entries: 0,
+        largest_bucket: 0,
+    };
+    for bucket in signature.checksum_map.values() {
+        if bucket.len() > 1 {
+            report.colliding_buckets += 1;
+            report.colliding_entries += bucket.len();
+        }
+        report.largest_bucket = report.largest_bucket.max(bucket.len());
+    }
+    report
+}
+
+pub fn write_collision_report(report: &CollisionReport, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "Weak hash buckets: {}", report.buckets)?;
+    writeln!(
+        out,
+        "Buckets with collisions: {} ({} entries)",
+        report.colliding_buckets, report.colliding_entries
+    )?;
+    writeln!(out, "Largest bucket: {} entries", report.largest_bucket)
+}
+
+// Signature as written by write_signature_json: the blocks listed in index order,
+// with the kept bytes of the strong hashes in lowercase hex, so equal signatures give
+// identical JSON
+#[derive(Serialize, Deserialize)]
+struct SignatureJson {
+    block_chunk_size: u32,
+    total_chunks: u64,
+    original_length: u64,
+    rolling_checksum: RollingChecksumAlgorithm,
+    strong_hash_algorithm: StrongHashAlgorithm,
+    strong_hash_len: u8,
+    blocks: Vec<BlockJson>,
+    duplicates: Vec<DuplicateRun>,
+    whole_file_checksum: Option<u32>,
+    whole_file_hash: Option<String>,
+    domain_tag: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockJson {
+    index: u64,
+    weak_hash: u32,
+    strong_hash: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Strong hash of len bytes from hex
+fn strong_hash_from_hex(hex: &str, len: u8) -> Option<Vec<u8>> {
+    if hex.len() != len as usize * 2 || !hex.is_ascii() {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok())
+        .collect()
+}
+
+// Write a signature as pretty printed JSON, for reading or for other tools.
+// The signature file format itself stays bincode
+pub fn write_signature_json(signature: &FileChunkSignature, out: &mut impl Write) -> Result<()> {
+    let mut blocks: Vec<BlockJson> = signature
+        .checksum_map
+        .iter()
+        .flat_map(|(weak_hash, hashes)| {
+            hashes.iter().map(|entry| BlockJson {
+                index: entry.index,
+                weak_hash: *weak_hash,
+                strong_hash: to_hex(&entry.hash),
+            })
+        })
+        .collect();
+    blocks.sort_by_key(|block| block.index);
+    let json = SignatureJson {
+        block_chunk_size: signature.block_chunk_size,
+        total_chunks: signature.total_chunks,
+        original_length: signature.original_length,
+        rolling_checksum: signature.rolling_checksum,
+        strong_hash_algorithm: signature.strong_hash,
+        strong_hash_len: signature.strong_hash_len,
+        blocks,
+        duplicates: signature.duplicates.clone(),
+        whole_file_checksum: signature.whole_file_checksum,
+        whole_file_hash: signature.whole_file_hash.as_ref().map(|hash| to_hex(hash)),
+        domain_tag: signature.domain_tag.clone(),
+    };
+    serde_json::to_writer_pretty(&mut *out, &json)?;
+    writeln!(out)
+}
+
+// Read a signature back from the JSON written by write_signature_json, checked as
+// read_signature checks a signature file
+pub fn signature_from_json(
+    json: &str,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    let invalid = |reason: String| RollingHashError::InvalidSignature(reason);
+    let json: SignatureJson =
+        serde_json::from_str(json).map_err(|error| invalid(error.to_string()))?;
+    let mut checksum_map: HashMap<u32, Vec<BlockChunkHashes>> = HashMap::new();
+    for block in json.blocks {
+        let hash =
+            strong_hash_from_hex(&block.strong_hash, json.strong_hash_len).ok_or_else(|| {
+                invalid(format!(
+                    "strong hash of block {} is not {} hex digits",
+                    block.index,
+                    json.strong_hash_len as usize * 2
+                ))
+            })?;
+        checksum_map
+            .entry(block.weak_hash)
+            .or_default()
+            .push(BlockChunkHashes {
+                index: block.index,
+                hash,
+            });
+    }
+    let whole_file_hash = match json.whole_file_hash {
+        Some(hex) => Some(
+            strong_hash_from_hex(&hex, 32)
+                .and_then(|hash| hash.try_into().ok())
+                .ok_or_else(|| invalid("whole file hash is not 64 hex digits".to_string()))?,
+        ),
+        None => None,
+    };
+    let signature = FileChunkSignature {
+        block_chunk_size: json.block_chunk_size,
+        checksum_map,
+        rolling_checksum: json.rolling_checksum,
+        total_chunks: json.total_chunks,
+        original_length: json.original_length,
+        duplicates: json.duplicates,
+        strong_hash: json.strong_hash_algorithm,
+        strong_hash_len: json.strong_hash_len,
+        whole_file_checksum: json.whole_file_checksum,
+        whole_file_hash,
+        domain_tag: json.domain_tag,
+    };
+    signature.check_parameters()?;
+    signature.check_totals().map_err(invalid)?;
+    Ok(signature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::signature::{chunk_sha256_hash, get_signature};
+    use crate::handlers::testutil::{empty_signature, gen_data};
+
+    #[test]
+    pub fn test_signature_collision_report() {
+        // [1, 0, 0, 1] and [0, 1, 1, 0] share a weak hash but not a strong hash
+        let signature = get_signature(&[1, 0, 0, 1, 0, 1, 1, 0, 5, 5, 5, 5, 1, 0, 0, 1], 4);
+        assert_eq!(
+            CollisionReport {
+                buckets: 2,
+                colliding_buckets: 1,
+                colliding_entries: 3,
+                largest_bucket: 3,
+            },
+            signature_collision_report(&signature)
+        );
+
+        let mut out: Vec<u8> = Vec::new();
+        write_collision_report(&signature_collision_report(&signature), &mut out).unwrap();
+        assert_eq!(
+            "Weak hash buckets: 2\nBuckets with collisions: 1 (3 entries)\nLargest bucket: 3 entries\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn test_hexdump_short_run() {
+        let lines = hexdump_lines(b"Hi\x00\x7f there", 4);
+        assert_eq!(
+            vec!["00000000  48 69 00 7f 20 74 68 65  72 65                    |Hi.. there|"],
+            lines
+        );
+    }
+
+    #[test]
+    pub fn test_hexdump_long_run_is_truncated() {
+        let bytes: Vec<u8> = (0u8..=255).collect();
+        let lines = hexdump_lines(&bytes, 2);
+
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            "00000000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|",
+            lines[0]
+        );
+        assert_eq!(
+            "00000010  10 11 12 13 14 15 16 17  18 19 1a 1b 1c 1d 1e 1f  |................|",
+            lines[1]
+        );
+        assert_eq!("... 224 more bytes", lines[2]);
+    }
+
+    #[test]
+    pub fn test_delta_report() {
+        let delta = vec![VerifyMatch::Match(2), VerifyMatch::NoMatch(b"abc".to_vec())];
+        let mut plain: Vec<u8> = Vec::new();
+        write_delta_report(&delta, None, &mut plain).unwrap();
+        assert_eq!(
+            "     0: match block 2\n     1: literal 3 bytes\n",
+            String::from_utf8(plain).unwrap()
+        );
+
+        let mut with_hexdump: Vec<u8> = Vec::new();
+        write_delta_report(&delta, Some(1), &mut with_hexdump).unwrap();
+        let report = String::from_utf8(with_hexdump).unwrap();
+        assert_eq!(3, report.lines().count());
+        assert!(report.ends_with("|abc|\n"));
+    }
+
+    #[test]
+    pub fn test_signature_report() {
+        let signature = empty_signature(64);
+        let mut report: Vec<u8> = Vec::new();
+        write_signature_report(&signature, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+
+        assert!(report.starts_with("Block size: 64 bytes\nBlocks: 0\nDistinct weak hashes: 0\n"));
+        assert!(report.contains(&format!("~{} bytes", signature.memory_footprint())));
+    }
+
+    #[test]
+    pub fn test_signature_details() {
+        let signature = FileChunkSignature {
+            rolling_checksum: RollingChecksumAlgorithm::Adler32,
+            strong_hash: StrongHashAlgorithm::Md4,
+            strong_hash_len: 8,
+            ..empty_signature(128)
+        };
+        let mut details: Vec<u8> = Vec::new();
+        write_signature_details(&signature, 3, &mut details).unwrap();
+        assert_eq!(
+            "Format version: 3\n\
+             Block size: 128 bytes\n\
+             Chunking: fixed\n\
+             Rolling checksum: adler32 (modulus 65521)\n\
+             Strong hash: md4, 8 of 16 bytes kept\n\
+             Total chunks: 0\n\
+             Original length: 0 bytes\n",
+            String::from_utf8(details).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn test_signature_json_round_trip() {
+        let signature = get_signature(&gen_data(25, 300), 64);
+        let mut json: Vec<u8> = Vec::new();
+        write_signature_json(&signature, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+
+        assert_eq!(signature, signature_from_json(&json).unwrap());
+        let mut again: Vec<u8> = Vec::new();
+        write_signature_json(&signature_from_json(&json).unwrap(), &mut again).unwrap();
+        assert_eq!(json, String::from_utf8(again).unwrap());
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(5, value["total_chunks"]);
+        let blocks = value["blocks"].as_array().unwrap();
+        let indices: Vec<u64> = blocks
+            .iter()
+            .map(|b| b["index"].as_u64().unwrap())
+            .collect();
+        assert_eq!(vec![0, 1, 2, 3, 4], indices);
+        let first_hash = chunk_sha256_hash(&gen_data(25, 300)[..64]);
+        assert_eq!(to_hex(&first_hash), blocks[0]["strong_hash"]);
+        assert!(to_hex(&first_hash)
+            .chars()
+            .all(|digit| matches!(digit, '0'..='9' | 'a'..='f')));
+
+        let error = signature_from_json(&json.replacen(&to_hex(&first_hash), "xyz", 1));
+        assert!(
+            matches!(error, Err(RollingHashError::InvalidSignature(_))),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    pub fn test_signature_json_is_checked() {
+        let signature = get_signature(&gen_data(26, 300), 64);
+        let mut json: Vec<u8> = Vec::new();
+        write_signature_json(&signature, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+
+        // Each edit leaves well formed JSON of a signature this tool can't have written
+        let edits: [(&str, serde_json::Value); 5] = [
+            ("block_chunk_size", 0.into()),
+            ("strong_hash_len", 0.into()),
+            ("strong_hash_len", 33.into()),
+            ("total_chunks", 4.into()),
+            ("original_length", 3000.into()),
+        ];
+        for (key, value) in edits {
+            let mut edited = json.clone();
+            edited[key] = value.clone();
+            let error = signature_from_json(&edited.to_string());
+            assert!(
+                matches!(error, Err(RollingHashError::InvalidSignature(_))),
+                "{} = {}: {:?}",
+                key,
+                value,
+                error
+            );
+        }
+        let mut out_of_range = json.clone();
+        out_of_range["blocks"][4]["index"] = 9.into();
+        assert!(matches!(
+            signature_from_json(&out_of_range.to_string()),
+            Err(RollingHashError::InvalidSignature(reason)) if reason.contains("out of range")
+        ));
+    }
+}
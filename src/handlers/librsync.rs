@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use clap::ValueEnum;
+
+use super::error::RollingHashError;
+use super::file_io::{self, RetryPolicy};
+use super::md4::md4;
+use super::signature::{
+    check_strong_hash_len, signature_block_size, SignatureOptions, StrongHashAlgorithm,
+};
+use super::window_checksum::validate_block_size;
+
+// File format of generate-signature's output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SignatureFormat {
+    // This tool's RHSG signature file
+    #[default]
+    Native,
+    // librsync's signature with MD4 strong sums, as written by `rdiff signature`, for
+    // diffing with rdiff. Only the block size and strong hash length options apply
+    Librsync,
+}
+
+// librsync signature layout, all integers big endian:
+//   magic (4 bytes), block length (4 bytes), strong sum length (4 bytes)
+//   per block: weak sum (4 bytes), strong sum (strong sum length bytes)
+// The weak sum is librsync's rollsum and the strong sum a truncated MD4 digest
+pub const LIBRSYNC_MD4_SIG_MAGIC: u32 = 0x7273_0136;
+
+// Offset added to every byte by librsync's rollsum
+const ROLLSUM_CHAR_OFFSET: u16 = 31;
+
+// librsync's rollsum of a block: s1 the sum of the bytes plus the offset, s2 the sum of
+// the running s1, both modulo 2^16, with s2 in the high half
+pub fn librsync_weak_sum(block: &[u8]) -> u32 {
+    let (mut s1, mut s2) = (0u16, 0u16);
+    for byte in block {
+        s1 = s1.wrapping_add(*byte as u16 + ROLLSUM_CHAR_OFFSET);
+        s2 = s2.wrapping_add(s1);
+    }
+    ((s2 as u32) << 16) | s1 as u32
+}
+
+// Write the librsync signature of the input, read one block at a time
+pub fn write_librsync_signature(
+    input: &mut impl Read,
+    block_size: u32,
+    strong_hash_len: u8,
+    retry: &RetryPolicy,
+    out: &mut impl Write,
+) -> Result<(), RollingHashError> {
+    validate_block_size(block_size)?;
+    check_strong_hash_len(StrongHashAlgorithm::Md4, strong_hash_len)?;
+
+    out.write_all(&LIBRSYNC_MD4_SIG_MAGIC.to_be_bytes())?;
+    out.write_all(&block_size.to_be_bytes())?;
+    out.write_all(&(strong_hash_len as u32).to_be_bytes())?;
+    let mut block = vec![0u8; block_size as usize];
+    loop {
+        let block_len = file_io::read_block(input, &mut block, retry)?;
+        if block_len == 0 {
+            break;
+        }
+        out.write_all(&librsync_weak_sum(&block[..block_len]).to_be_bytes())?;
+        out.write_all(&md4(&block[..block_len])[..strong_hash_len as usize])?;
+        if block_len < block.len() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Like write_signature_file, writing a librsync signature. The block size is chosen as for
+// a native signature, and the strong sums keep the whole MD4 digest unless
+// options.strong_hash_len is set
+pub fn write_librsync_signature_file(
+    input_file: &File,
+    signature_file: &mut File,
+    retry: &RetryPolicy,
+    options: &SignatureOptions,
+) -> Result<(), RollingHashError> {
+    let strong_hash_len = options
+        .strong_hash_len
+        .unwrap_or(StrongHashAlgorithm::Md4.digest_len());
+    let mut signature_writer = BufWriter::new(signature_file);
+    // A stream has no length to derive the block size from until it has been read whole
+    if options.fixed_block_size.is_some() || !file_io::is_stream(input_file)? {
+        let block_size = signature_block_size(input_file.metadata()?.len(), options);
+        write_librsync_signature(
+            &mut BufReader::new(input_file),
+            block_size,
+            strong_hash_len,
+            retry,
+            &mut signature_writer,
+        )?;
+    } else {
+        let input_file_buf =
+            file_io::read_file_to_buffer_with_retry(&mut BufReader::new(input_file), retry)?;
+        let block_size = signature_block_size(input_file_buf.len() as u64, options);
+        write_librsync_signature(
+            &mut input_file_buf.as_slice(),
+            block_size,
+            strong_hash_len,
+            retry,
+            &mut signature_writer,
+        )?;
+    }
+    signature_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn librsync_signature(input: &[u8], block_size: u32, strong_hash_len: u8) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        write_librsync_signature(
+            &mut &input[..],
+            block_size,
+            strong_hash_len,
+            &RetryPolicy::default(),
+            &mut out,
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    pub fn test_librsync_signature_bytes() {
+        // rdiff isn't available to generate a fixture, so the expected bytes are built from
+        // librsync's format: the MD4 digests are the RFC 1320 test vectors of "abc" and "a",
+        // and the rollsums worked out by hand
+        let expected: Vec<u8> = [
+            &[0x72, 0x73, 0x01, 0x36][..],
+            &[0, 0, 0, 3],
+            &[0, 0, 0, 8],
+            // "abc": s1 = 128 + 129 + 130 = 387, s2 = 128 + 257 + 387 = 772
+            &[0x03, 0x04, 0x01, 0x83],
+            &[0xa4, 0x48, 0x01, 0x7a, 0xaf, 0x21, 0xd8, 0x52],
+            // "a": s1 = s2 = 128
+            &[0x00, 0x80, 0x00, 0x80],
+            &[0xbd, 0xe5, 0x2c, 0xb3, 0x1d, 0xe3, 0x3e, 0x46],
+        ]
+        .concat();
+        assert_eq!(expected, librsync_signature(b"abca", 3, 8));
+
+        // An empty file has the header alone
+        assert_eq!(expected[..12], librsync_signature(b"", 3, 8)[..]);
+    }
+
+    #[test]
+    pub fn test_librsync_weak_sum_wraps() {
+        // Closed forms of s1 and s2 for n bytes of 255, taken modulo 2^16 at the end
+        let n: u64 = 1000;
+        let s1 = n * 286 % (1 << 16);
+        let s2 = 286 * (n * (n + 1) / 2) % (1 << 16);
+        assert_eq!(((s2 << 16) | s1) as u32, librsync_weak_sum(&[255u8; 1000]));
+    }
+
+    #[test]
+    pub fn test_librsync_signature_rejects_long_strong_hash() {
+        let mut out: Vec<u8> = Vec::new();
+        let error =
+            write_librsync_signature(&mut &b"abc"[..], 64, 17, &RetryPolicy::default(), &mut out)
+                .unwrap_err();
+        assert!(matches!(error, RollingHashError::Io(_)), "{:?}", error);
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::{deserialize_from, serialize_into};
+
+use super::error::RollingHashError;
+use super::file_diff::{diff_batch_file, DiffOptions, DiffStats};
+use super::file_io::{read_handler, OutputOptions, ReadOptions, RetryPolicy};
+use super::signature::{
+    get_signature, read_signature, signature_of_file, write_signature, FileChunkSignature,
+    SignatureOptions,
+};
+
+// Signatures of every regular file under a directory, keyed by the file's path relative
+// to the directory with '/' separators
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FileManifestSignature {
+    pub files: BTreeMap<String, FileChunkSignature>,
+}
+
+// Manifest file layout:
+//   magic "RHMF", format version (1 byte), file count (u64)
+//   per file, in path order: bincode serialized path, signature as write_signature writes it
+// Each signature carries its own header, so older signature versions inside are still read
+pub(crate) const MANIFEST_MAGIC: &[u8; 4] = b"RHMF";
+const MANIFEST_VERSION: u8 = 1;
+
+// Paths of the regular files under dir relative to it, sorted. Symbolic links are skipped
+pub fn relative_files(dir: &Path) -> Result<Vec<String>> {
+    let mut files: Vec<String> = Vec::new();
+    let mut pending: Vec<(PathBuf, String)> = vec![(dir.to_path_buf(), String::new())];
+    while let Some((path, prefix)) = pending.pop() {
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file name {:?} is not valid UTF-8", name),
+                )
+            })?;
+            let relative = format!("{}{}", prefix, name);
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push((entry.path(), format!("{}/", relative)));
+            } else if file_type.is_file() {
+                files.push(relative);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Sign every regular file under dir, each as write_signature_file would
+pub fn sign_directory(
+    dir: &Path,
+    retry: &RetryPolicy,
+    options: &SignatureOptions,
+) -> std::result::Result<FileManifestSignature, RollingHashError> {
+    let mut manifest = FileManifestSignature::default();
+    for relative in relative_files(dir)? {
+        let input_file = read_handler(&dir.join(&relative))?;
+        let signature = signature_of_file(&input_file, retry, options)?;
+        manifest.files.insert(relative, signature);
+    }
+    Ok(manifest)
+}
+
+pub fn write_manifest(
+    manifest: &FileManifestSignature,
+    out: &mut impl Write,
+) -> std::result::Result<(), RollingHashError> {
+    out.write_all(MANIFEST_MAGIC)?;
+    out.write_all(&[MANIFEST_VERSION])?;
+    out.write_all(&(manifest.files.len() as u64).to_le_bytes())?;
+    for (relative, signature) in &manifest.files {
+        serialize_into(&mut *out, relative).map_err(RollingHashError::Serialize)?;
+        write_signature(signature, out)?;
+    }
+    Ok(())
+}
+
+// Read a manifest written by write_manifest, checking every signature as read_signature does
+pub fn read_manifest(
+    input: &mut impl Read,
+) -> std::result::Result<FileManifestSignature, RollingHashError> {
+    let mut header = [0u8; 13];
+    input.read_exact(&mut header)?;
+    if &header[..4] != MANIFEST_MAGIC {
+        return Err(RollingHashError::InvalidSignature(
+            "not a manifest file (no RHMF header)".to_string(),
+        ));
+    }
+    if header[4] != MANIFEST_VERSION {
+        return Err(RollingHashError::InvalidSignature(format!(
+            "unsupported manifest format version {}",
+            header[4]
+        )));
+    }
+    let file_count = u64::from_le_bytes(header[5..].try_into().unwrap());
+
+    let mut manifest = FileManifestSignature::default();
+    for _ in 0..file_count {
+        let relative: String = deserialize_from(&mut *input)?;
+        let signature = read_signature(input)?;
+        manifest.files.insert(relative, signature);
+    }
+    Ok(manifest)
+}
+
+// Sign the directory and write its manifest to the manifest file
+pub fn write_manifest_file(
+    dir: &Path,
+    manifest_file: &mut File,
+    retry: &RetryPolicy,
+    options: &SignatureOptions,
+) -> std::result::Result<(), RollingHashError> {
+    let manifest = sign_directory(dir, retry, options)?;
+    let mut manifest_writer = BufWriter::new(manifest_file);
+    write_manifest(&manifest, &mut manifest_writer)?;
+    manifest_writer.flush()?;
+    Ok(())
+}
+
+pub fn read_manifest_file(
+    manifest_file: &File,
+) -> std::result::Result<FileManifestSignature, RollingHashError> {
+    read_manifest(&mut BufReader::new(manifest_file))
+}
+
+// Diff every regular file under new_dir against its signature in the manifest, writing
+// "<relative path>.delta" under output_dir. Files the manifest has no signature of are
+// diffed against an empty file, so their deltas are all literal. Files only in the
+// manifest get no delta
+pub fn diff_directory(
+    manifest: &FileManifestSignature,
+    new_dir: &Path,
+    output_dir: &Path,
+    options: &DiffOptions,
+    read_options: &ReadOptions,
+    output_options: &OutputOptions,
+) -> Result<Vec<(String, DiffStats)>> {
+    let empty_signature = get_signature(&[], 64);
+    let mut directory_stats = Vec::new();
+    for relative in relative_files(new_dir)? {
+        let signature = manifest.files.get(&relative).unwrap_or(&empty_signature);
+        let delta_path = output_dir.join(format!("{}.delta", relative));
+        if let Some(parent) = delta_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let stats = diff_batch_file(
+            signature,
+            &new_dir.join(&relative),
+            &delta_path,
+            options,
+            read_options,
+            output_options,
+        )?;
+        directory_stats.push((relative, stats));
+    }
+    Ok(directory_stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_diff::read_delta;
+    use crate::handlers::patch::apply_patch;
+    use crate::handlers::testutil::{apply_edits, gen_data, temp_dir};
+
+    #[test]
+    pub fn test_directory_manifest_round_trip() {
+        let old_dir = temp_dir("rolling-hash-manifest-old");
+        fs::create_dir(old_dir.join("nested")).unwrap();
+        let first = gen_data(13, 3000);
+        let second = gen_data(14, 700);
+        fs::write(old_dir.join("first.bin"), &first).unwrap();
+        fs::write(old_dir.join("nested/second.bin"), &second).unwrap();
+
+        let manifest =
+            sign_directory(&old_dir, &RetryPolicy::default(), &Default::default()).unwrap();
+        assert_eq!(
+            vec!["first.bin", "nested/second.bin"],
+            manifest.files.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(get_signature(&first, 64), manifest.files["first.bin"]);
+        assert_eq!(
+            get_signature(&second, 64),
+            manifest.files["nested/second.bin"]
+        );
+
+        let mut bytes: Vec<u8> = Vec::new();
+        write_manifest(&manifest, &mut bytes).unwrap();
+        assert_eq!(manifest, read_manifest(&mut bytes.as_slice()).unwrap());
+        assert!(matches!(
+            read_signature(&mut bytes.as_slice()),
+            Err(RollingHashError::NotASignature(Some(_)))
+        ));
+
+        // Each delta rebuilds its file from the old one of the same path
+        let new_dir = temp_dir("rolling-hash-manifest-new");
+        let output_dir = temp_dir("rolling-hash-manifest-deltas");
+        fs::create_dir(new_dir.join("nested")).unwrap();
+        let new_first = apply_edits(&first, 13, 4);
+        fs::write(new_dir.join("first.bin"), &new_first).unwrap();
+        fs::write(new_dir.join("nested/second.bin"), &second).unwrap();
+        fs::write(new_dir.join("added.txt"), b"new file").unwrap();
+        let stats = diff_directory(
+            &manifest,
+            &new_dir,
+            &output_dir,
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["added.txt", "first.bin", "nested/second.bin"],
+            stats.iter().map(|(path, _)| path).collect::<Vec<_>>()
+        );
+        for (relative, old, new) in [
+            ("added.txt", &[][..], &b"new file"[..]),
+            ("first.bin", &first, &new_first),
+            ("nested/second.bin", &second, &second),
+        ] {
+            let delta_file = File::open(output_dir.join(format!("{}.delta", relative))).unwrap();
+            let delta = read_delta(&mut BufReader::new(delta_file)).unwrap();
+            let block_size = manifest
+                .files
+                .get(relative)
+                .map_or(64, |signature| signature.block_chunk_size);
+            let mut rebuilt: Vec<u8> = Vec::new();
+            apply_patch(old, &delta, block_size, &mut rebuilt).unwrap();
+            assert_eq!(new, rebuilt.as_slice(), "{}", relative);
+        }
+
+        for dir in [old_dir, new_dir, output_dir] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// MD4 (RFC 1320), the strong hash librsync's older signature formats use.
+// Broken as a cryptographic hash; only meant for matching blocks with other tools
+const INITIAL_STATE: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+// Shift amounts of each round, by step within a group of four
+const ROUND_1_SHIFTS: [u32; 4] = [3, 7, 11, 19];
+const ROUND_2_SHIFTS: [u32; 4] = [3, 5, 9, 13];
+const ROUND_3_SHIFTS: [u32; 4] = [3, 9, 11, 15];
+// Order round 3 takes the words of a block in
+const ROUND_3_WORDS: [usize; 16] = [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15];
+
+pub fn md4(data: &[u8]) -> [u8; 16] {
+    let mut state = INITIAL_STATE;
+
+    // Pad with a one bit, zeros up to 56 bytes modulo 64, then the bit length little endian
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let mut words = [0u32; 16];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        compress(&mut state, &words);
+    }
+
+    let mut digest = [0u8; 16];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+fn compress(state: &mut [u32; 4], words: &[u32; 16]) {
+    let [mut a, mut b, mut c, mut d] = *state;
+    let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+    let g = |x: u32, y: u32, z: u32| (x & y) | (x & z) | (y & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+    // Each step updates one of a, b, c, d in turn, so the four rotate through the roles
+    for step in 0..16 {
+        let value = a
+            .wrapping_add(f(b, c, d))
+            .wrapping_add(words[step])
+            .rotate_left(ROUND_1_SHIFTS[step % 4]);
+        (a, b, c, d) = (d, value, b, c);
+    }
+    for step in 0..16 {
+        let word = words[(step % 4) * 4 + step / 4];
+        let value = a
+            .wrapping_add(g(b, c, d))
+            .wrapping_add(word)
+            .wrapping_add(0x5a82_7999)
+            .rotate_left(ROUND_2_SHIFTS[step % 4]);
+        (a, b, c, d) = (d, value, b, c);
+    }
+    for step in 0..16 {
+        let value = a
+            .wrapping_add(h(b, c, d))
+            .wrapping_add(words[ROUND_3_WORDS[step]])
+            .wrapping_add(0x6ed9_eba1)
+            .rotate_left(ROUND_3_SHIFTS[step % 4]);
+        (a, b, c, d) = (d, value, b, c);
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    pub fn test_rfc_1320_vectors() {
+        let vectors: [(&[u8], &str); 7] = [
+            (b"", "31d6cfe0d16ae931b73c59d7e0c089c0"),
+            (b"a", "bde52cb31de33e46245e05fbdbd6fb24"),
+            (b"abc", "a448017aaf21d8525fc10ae87aa6729d"),
+            (b"message digest", "d9130a8164549fe818874806e1c7014b"),
+            (
+                b"abcdefghijklmnopqrstuvwxyz",
+                "d79e1c308aa5bbcdeea8ed63df412da9",
+            ),
+            (
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "043f8582f241db351ce627e153e7f0e4",
+            ),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "e33b4ddc9c38f2199c3e7b164fcc0536",
+            ),
+        ];
+        for (input, expected) in vectors {
+            assert_eq!(expected, hex(md4(input)), "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Result, Write};
+
+use super::error::RollingHashError;
+use super::file_diff::{
+    read_delta_with_numbering, validate_delta, BlockIndexBase, DeltaNumbering, VerifyMatch,
+};
+use super::file_io::{read_file_to_buffer_with_retry, RetryPolicy};
+use super::signature::{find_blocksize, FileChunkSignature};
+use super::window_checksum::validate_block_size;
+
+// Byte offset of a block in the old file, or None if it doesn't fit in 64 bits
+pub fn block_offset(index: u64, block_size: u32) -> Option<u64> {
+    index.checked_mul(block_size as u64)
+}
+
+// Rebuild the new file from the old file and a delta generated against the old file's
+// signature, writing matched blocks of block_size bytes from the old file and literal
+// runs as they are. Returns the number of bytes written
+pub fn apply_patch(
+    old_file: &[u8],
+    delta: &[VerifyMatch],
+    block_size: u32,
+    out: &mut impl Write,
+) -> Result<u64> {
+    validate_block_size(block_size)?;
+    let old_length = old_file.len() as u64;
+    validate_delta(delta, old_length.div_ceil(block_size as u64))?;
+
+    let mut written = 0u64;
+    for entry in delta {
+        let bytes = match entry {
+            VerifyMatch::Match(index) => {
+                // In range after validate_delta, so the offset is below old_length
+                let start = block_offset(*index, block_size).unwrap();
+                let end = old_length.min(start + block_size as u64);
+                &old_file[start as usize..end as usize]
+            }
+            VerifyMatch::NoMatch(bytes) => bytes.as_slice(),
+        };
+        out.write_all(bytes)?;
+        written += bytes.len() as u64;
+    }
+    Ok(written)
+}
+
+// Block size to apply a delta to old_file with, checking the delta and old_file against
+// the signature if there is one. The old file's block size is the signature's, or without
+// one the size signing derives from the old file's length. A delta whose header records
+// another block size, as when the old file was signed again at a different size, only
+// applies if its matches are byte offsets: those locate blocks of the recorded size at
+// any block size, while block indices would read misaligned blocks
+fn delta_block_size(
+    old_file: &[u8],
+    delta: &[VerifyMatch],
+    numbering: DeltaNumbering,
+    signature: Option<&FileChunkSignature>,
+    verify_blocks: bool,
+) -> std::result::Result<u32, RollingHashError> {
+    let old_block_size = match signature {
+        Some(signature) => signature.block_chunk_size,
+        None => find_blocksize(old_file.len() as u64),
+    };
+    if numbering.block_size != 0 && numbering.block_size != old_block_size {
+        if numbering.base != BlockIndexBase::Offset {
+            return Err(RollingHashError::InvalidDelta(format!(
+                "delta of blocks of {} bytes can't be applied to an old file in blocks of {} \
+                 bytes; only a delta of byte offsets (--block-index-base offset) can",
+                numbering.block_size, old_block_size
+            )));
+        }
+        if verify_blocks {
+            return Err(RollingHashError::InvalidDelta(format!(
+                "blocks of {} bytes can't be verified against a signature of {} byte blocks",
+                numbering.block_size, old_block_size
+            )));
+        }
+        if let Some(signature) = signature {
+            signature.check_old_file(old_file)?;
+        }
+        return Ok(numbering.block_size);
+    }
+    if let Some(signature) = signature {
+        signature.check_delta(delta)?;
+        signature.check_old_file(old_file)?;
+        if verify_blocks {
+            signature.check_matched_blocks(old_file, delta)?;
+        }
+    }
+    Ok(old_block_size)
+}
+
+// Apply a delta file to the old file and write the new file.
+// The block size the delta was generated with is found by delta_block_size. With the
+// signature, the delta's matches are also checked against the number of blocks the
+// signature recorded, and the old file against the signed file's length and first block.
+// With verify_blocks, every block the delta copies is also checked against its strong
+// hash before anything is written
+pub fn apply_patch_file(
+    old_file: &File,
+    delta_file: &File,
+    output_file: &mut File,
+    signature: Option<&FileChunkSignature>,
+    verify_blocks: bool,
+    retry: &RetryPolicy,
+) -> std::result::Result<u64, RollingHashError> {
+    let old_file_buf = read_file_to_buffer_with_retry(&mut BufReader::new(old_file), retry)?;
+    let (numbering, delta) = read_delta_with_numbering(&mut BufReader::new(delta_file))?;
+    let block_size = delta_block_size(&old_file_buf, &delta, numbering, signature, verify_blocks)?;
+
+    let mut output_writer = BufWriter::new(output_file);
+    let written = apply_patch(&old_file_buf, &delta, block_size, &mut output_writer)?;
+    output_writer.flush()?;
+    Ok(written)
+}
+
+// Sink comparing the bytes written to it with the expected file, recording the offset
+// of the first byte that differs
+struct CompareWriter<'a> {
+    expected: &'a [u8],
+    position: u64,
+    first_difference: Option<u64>,
+}
+
+impl Write for CompareWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.first_difference.is_none() {
+            let start = (self.position as usize).min(self.expected.len());
+            let expected = &self.expected[start..];
+            let differing = buf
+                .iter()
+                .zip(expected)
+                .position(|(written, expected)| written != expected)
+                .or((buf.len() > expected.len()).then_some(expected.len()));
+            self.first_difference = differing.map(|offset| self.position + offset as u64);
+        }
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Check that applying the delta to the old file reproduces new_file, without keeping the
+// reconstruction. Returns the offset of the first byte that differs, which is the length
+// of the shorter file if one is a prefix of the other, or None if they are identical
+pub fn verify_patch(
+    old_file: &[u8],
+    delta: &[VerifyMatch],
+    block_size: u32,
+    new_file: &[u8],
+) -> Result<Option<u64>> {
+    let mut compare = CompareWriter {
+        expected: new_file,
+        position: 0,
+        first_difference: None,
+    };
+    let written = apply_patch(old_file, delta, block_size, &mut compare)?;
+    Ok(compare
+        .first_difference
+        .or((written < new_file.len() as u64).then_some(written)))
+}
+
+// verify_patch for files. The block size is found as apply_patch_file finds it
+pub fn verify_patch_file(
+    old_file: &File,
+    delta_file: &File,
+    new_file: &File,
+    signature: Option<&FileChunkSignature>,
+    retry: &RetryPolicy,
+) -> std::result::Result<Option<u64>, RollingHashError> {
+    let old_file_buf = read_file_to_buffer_with_retry(&mut BufReader::new(old_file), retry)?;
+    let (numbering, delta) = read_delta_with_numbering(&mut BufReader::new(delta_file))?;
+    let new_file_buf = read_file_to_buffer_with_retry(&mut BufReader::new(new_file), retry)?;
+    let block_size = delta_block_size(&old_file_buf, &delta, numbering, signature, false)?;
+    Ok(verify_patch(
+        &old_file_buf,
+        &delta,
+        block_size,
+        &new_file_buf,
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_diff::generate_diff;
+    use crate::handlers::file_diff::{
+        read_delta, write_delta, write_delta_with_base, write_diff_file, DiffOptions,
+    };
+    use crate::handlers::file_io::{read_handler, ReadOptions};
+    use crate::handlers::signature::{
+        get_signature, read_signature, write_signature, write_signature_file, BlockChunkHashes,
+        SignatureOptions,
+    };
+    use crate::handlers::testutil::{apply_edits, empty_signature, gen_data, temp_path};
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    #[test]
+    pub fn test_apply_patch_round_trip() {
+        let signature_path = temp_path("rolling-hash-patch-signature");
+        let delta_path = temp_path("rolling-hash-patch-delta");
+        let output_path = temp_path("rolling-hash-patch-output");
+
+        write_signature_file(
+            &read_handler(Path::new("data/old.txt")).unwrap(),
+            &mut File::create(&signature_path).unwrap(),
+            &RetryPolicy::default(),
+            &SignatureOptions::default(),
+        )
+        .unwrap();
+        write_diff_file(
+            &read_handler(&signature_path).unwrap(),
+            &read_handler(Path::new("data/new.txt")).unwrap(),
+            &mut File::create(&delta_path).unwrap(),
+            &DiffOptions::default(),
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        let written = apply_patch_file(
+            &read_handler(Path::new("data/old.txt")).unwrap(),
+            &read_handler(&delta_path).unwrap(),
+            &mut File::create(&output_path).unwrap(),
+            None,
+            false,
+            &RetryPolicy::default(),
+        )
+        .unwrap();
+
+        let new_file = std::fs::read("data/new.txt").unwrap();
+        assert_eq!(new_file.len() as u64, written);
+        assert_eq!(new_file, std::fs::read(&output_path).unwrap());
+        for path in [signature_path, delta_path, output_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    // Apply a delta of old_file's blocks of 64 bytes, written with base, to old_file
+    // signed again in blocks of 128 bytes
+    fn apply_across_block_sizes(
+        old_file: &[u8],
+        new_file: &[u8],
+        base: BlockIndexBase,
+        name: &str,
+    ) -> std::result::Result<Vec<u8>, RollingHashError> {
+        let old_path = temp_path(&format!("{}-old", name));
+        let delta_path = temp_path(&format!("{}-delta", name));
+        let output_path = temp_path(&format!("{}-output", name));
+        std::fs::write(&old_path, old_file).unwrap();
+        let delta = generate_diff(new_file, &get_signature(old_file, 64), 64);
+        write_delta_with_base(&delta, base, 64, &mut File::create(&delta_path).unwrap()).unwrap();
+
+        let resigned = get_signature(old_file, 128);
+        let applied = apply_patch_file(
+            &read_handler(&old_path).unwrap(),
+            &read_handler(&delta_path).unwrap(),
+            &mut File::create(&output_path).unwrap(),
+            Some(&resigned),
+            false,
+            &RetryPolicy::default(),
+        )
+        .map(|_| std::fs::read(&output_path).unwrap());
+        for path in [old_path, delta_path, output_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+        applied
+    }
+
+    #[test]
+    pub fn test_block_size_mismatch_rejects_index_delta() {
+        let old_file = gen_data(41, 64 * 20);
+        let new_file = apply_edits(&old_file, 41, 4);
+        for base in [BlockIndexBase::Zero, BlockIndexBase::One] {
+            let error =
+                apply_across_block_sizes(&old_file, &new_file, base, "rolling-hash-index-mismatch")
+                    .unwrap_err();
+            assert!(
+                matches!(&error, RollingHashError::InvalidDelta(reason)
+                    if reason.contains("blocks of 64 bytes") && reason.contains("128")),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_block_size_mismatch_applies_offset_delta() {
+        let old_file = gen_data(42, 64 * 20 + 5);
+        let new_file = apply_edits(&old_file, 42, 4);
+        let rebuilt = apply_across_block_sizes(
+            &old_file,
+            &new_file,
+            BlockIndexBase::Offset,
+            "rolling-hash-offset-mismatch",
+        )
+        .unwrap();
+        assert_eq!(new_file, rebuilt);
+    }
+
+    #[test]
+    pub fn test_apply_patch_rejects_out_of_range_block() {
+        let old_file = b"0123456789".to_vec();
+        let delta = vec![
+            VerifyMatch::Match(2),
+            VerifyMatch::NoMatch(b"new".to_vec()),
+            VerifyMatch::Match(0),
+        ];
+        let mut new_file: Vec<u8> = Vec::new();
+        assert_eq!(9, apply_patch(&old_file, &delta, 4, &mut new_file).unwrap());
+        assert_eq!(b"89new0123".to_vec(), new_file);
+
+        let error = apply_patch(&old_file, &delta, 5, &mut Vec::new()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    pub fn test_empty_delta_of_empty_file() {
+        let mut written: Vec<u8> = Vec::new();
+        write_delta(&[], &mut written).unwrap();
+        let delta = read_delta(&mut written.as_slice()).unwrap();
+        assert!(delta.is_empty());
+
+        let mut new_file: Vec<u8> = Vec::new();
+        assert_eq!(0, apply_patch(&[], &delta, 64, &mut new_file).unwrap());
+        assert!(new_file.is_empty());
+        assert_eq!(None, verify_patch(&[], &delta, 64, &[]).unwrap());
+    }
+
+    #[test]
+    pub fn test_apply_patch_file_checks_signature_block_count() {
+        let old_path = temp_path("rolling-hash-range-old");
+        let delta_path = temp_path("rolling-hash-range-delta");
+        let output_path = temp_path("rolling-hash-range-output");
+
+        // The old file has more blocks than the signature it claims to match
+        std::fs::write(&old_path, [7u8; 64 * 4]).unwrap();
+        let signature = get_signature(&[7u8; 64 * 2], 64);
+        assert_eq!(2, signature.total_chunks);
+        assert_eq!(128, signature.original_length);
+        let delta = vec![VerifyMatch::Match(0), VerifyMatch::Match(3)];
+        write_delta(&delta, &mut File::create(&delta_path).unwrap()).unwrap();
+
+        let error = apply_patch_file(
+            &read_handler(&old_path).unwrap(),
+            &read_handler(&delta_path).unwrap(),
+            &mut File::create(&output_path).unwrap(),
+            Some(&signature),
+            false,
+            &RetryPolicy::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::InvalidDelta(_)),
+            "{:?}",
+            error
+        );
+        for path in [old_path, delta_path, output_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_apply_patch_file_rejects_other_old_file() {
+        let old_path = temp_path("rolling-hash-mismatch-old");
+        let delta_path = temp_path("rolling-hash-mismatch-delta");
+        let output_path = temp_path("rolling-hash-mismatch-output");
+
+        let old_v1 = gen_data(40, 1000);
+        let signature = get_signature(&old_v1, 64);
+        let delta = generate_diff(&apply_edits(&old_v1, 40, 3), &signature, 64);
+        write_delta(&delta, &mut File::create(&delta_path).unwrap()).unwrap();
+        let apply_to = |old_file: &[u8]| {
+            std::fs::write(&old_path, old_file).unwrap();
+            apply_patch_file(
+                &read_handler(&old_path).unwrap(),
+                &read_handler(&delta_path).unwrap(),
+                &mut File::create(&output_path).unwrap(),
+                Some(&signature),
+                false,
+                &RetryPolicy::default(),
+            )
+        };
+
+        assert!(apply_to(&old_v1).is_ok());
+        // Another version of the old file, of another length
+        let error = apply_to(&old_v1[..900]).unwrap_err();
+        assert_eq!(
+            "old file does not match the signature: it is 900 bytes long, but the signed file \
+             was 1000 bytes",
+            error.to_string()
+        );
+        // Of the same length, changed in its first block
+        let mut old_v2 = old_v1.clone();
+        old_v2[10] ^= 1;
+        let error = apply_to(&old_v2).unwrap_err();
+        assert_eq!(
+            "old file does not match the signature: its first block differs from the signed \
+             file's",
+            error.to_string()
+        );
+        assert!(verify_patch_file(
+            &read_handler(&old_path).unwrap(),
+            &read_handler(&delta_path).unwrap(),
+            &read_handler(&old_path).unwrap(),
+            Some(&signature),
+            &RetryPolicy::default(),
+        )
+        .is_err_and(|error| matches!(error, RollingHashError::OldFileMismatch(_))));
+        for path in [old_path, delta_path, output_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_verify_blocks_detects_flipped_old_byte() {
+        let old_path = temp_path("rolling-hash-verify-blocks-old");
+        let delta_path = temp_path("rolling-hash-verify-blocks-delta");
+        let output_path = temp_path("rolling-hash-verify-blocks-output");
+
+        let old_file = gen_data(41, 1000);
+        let signature = get_signature(&old_file, 64);
+        let new_file = apply_edits(&old_file, 41, 3);
+        let delta = generate_diff(&new_file, &signature, 64);
+        assert!(delta.contains(&VerifyMatch::Match(9)));
+        write_delta(&delta, &mut File::create(&delta_path).unwrap()).unwrap();
+        let apply_to = |old_file: &[u8], verify_blocks: bool| {
+            std::fs::write(&old_path, old_file).unwrap();
+            apply_patch_file(
+                &read_handler(&old_path).unwrap(),
+                &read_handler(&delta_path).unwrap(),
+                &mut File::create(&output_path).unwrap(),
+                Some(&signature),
+                verify_blocks,
+                &RetryPolicy::default(),
+            )
+        };
+
+        assert!(apply_to(&old_file, true).is_ok());
+        assert_eq!(new_file, std::fs::read(&output_path).unwrap());
+
+        // Past the first block, so only the copied block's strong hash tells
+        let mut rotted = old_file.clone();
+        rotted[9 * 64 + 5] ^= 0x10;
+        assert!(apply_to(&rotted, false).is_ok());
+        assert_ne!(new_file, std::fs::read(&output_path).unwrap());
+        let error = apply_to(&rotted, true).unwrap_err();
+        assert_eq!(
+            "old file does not match the signature: block 9 differs from the signed file's",
+            error.to_string()
+        );
+        for path in [old_path, delta_path, output_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_block_indices_beyond_u32() {
+        let index = u32::MAX as u64 + 5;
+        assert_eq!(Some(index * 4096), block_offset(index, 4096));
+        assert_eq!(
+            (u32::MAX as u64 + 5) << 12,
+            block_offset(index, 4096).unwrap()
+        );
+        assert_eq!(None, block_offset(u64::MAX / 2, 4096));
+
+        // A signature of u32::MAX + 6 blocks of 4 KiB, holding only its last entry
+        let signature = FileChunkSignature {
+            checksum_map: std::collections::HashMap::from([(
+                1,
+                vec![BlockChunkHashes {
+                    index,
+                    hash: vec![0; 32],
+                }],
+            )]),
+            total_chunks: index + 1,
+            original_length: (index + 1) * 4096,
+            ..empty_signature(4096)
+        };
+        let mut written: Vec<u8> = Vec::new();
+        write_signature(&signature, &mut written).unwrap();
+        let error = read_signature(&mut written.as_slice()).unwrap_err();
+        assert!(error.to_string().contains("block entries"), "{}", error);
+        let delta = vec![VerifyMatch::Match(index)];
+        assert!(signature.check_delta(&delta).is_ok());
+        let mut delta_file: Vec<u8> = Vec::new();
+        write_delta(&delta, &mut delta_file).unwrap();
+        assert_eq!(delta, read_delta(&mut delta_file.as_slice()).unwrap());
+
+        // Out of range of a small old file, rather than wrapping around to a valid offset
+        let error = apply_patch(&[0u8; 100], &delta, 4096, &mut Vec::new()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    pub fn test_verify_patch() {
+        let old_file = gen_data(30, 1000);
+        let new_file = apply_edits(&old_file, 30, 4);
+        let signature = get_signature(&old_file, 64);
+        let delta = generate_diff(&new_file, &signature, 64);
+        assert_eq!(
+            None,
+            verify_patch(&old_file, &delta, 64, &new_file).unwrap()
+        );
+
+        // A literal byte flipped
+        let mut corrupted = generate_diff(&new_file, &signature, 64);
+        let literal = corrupted
+            .iter()
+            .position(|entry| entry.literal_bytes().is_some())
+            .unwrap();
+        let offset: u64 = corrupted[..literal]
+            .iter()
+            .map(|entry| match entry {
+                VerifyMatch::Match(_) => 64,
+                VerifyMatch::NoMatch(bytes) => bytes.len() as u64,
+            })
+            .sum();
+        if let VerifyMatch::NoMatch(bytes) = &mut corrupted[literal] {
+            bytes[0] ^= 0xff;
+        }
+        assert_eq!(
+            Some(offset),
+            verify_patch(&old_file, &corrupted, 64, &new_file).unwrap()
+        );
+
+        // A truncated or extended reconstruction differs where the shorter file ends
+        assert_eq!(
+            Some(new_file.len() as u64 - 1),
+            verify_patch(&old_file, &delta, 64, &new_file[..new_file.len() - 1]).unwrap()
+        );
+        let mut longer = new_file.clone();
+        longer.push(0);
+        assert_eq!(
+            Some(new_file.len() as u64),
+            verify_patch(&old_file, &delta, 64, &longer).unwrap()
+        );
+    }
+}
@@ -0,0 +1,46 @@
+// Throttled progress reports of the signature and diff scans to a caller's callback
+// taking (bytes processed, total bytes)
+
+// Blocks scanned between two reports
+pub(crate) const PROGRESS_INTERVAL_BLOCKS: u64 = 256;
+
+pub(crate) struct ProgressReporter<'a, 'f> {
+    callback: Option<&'a mut (dyn FnMut(u64, u64) + 'f)>,
+    total: u64,
+    interval: u64,
+    next_report: u64,
+}
+
+impl<'a, 'f> ProgressReporter<'a, 'f> {
+    pub(crate) fn new(
+        callback: Option<&'a mut (dyn FnMut(u64, u64) + 'f)>,
+        total: u64,
+        block_size: u64,
+    ) -> Self {
+        let interval = (PROGRESS_INTERVAL_BLOCKS * block_size).max(1);
+        Self {
+            callback,
+            total,
+            interval,
+            next_report: interval,
+        }
+    }
+
+    // Report once processed passes the next multiple of the interval
+    pub(crate) fn advance(&mut self, processed: u64) {
+        if processed < self.next_report || processed >= self.total {
+            return;
+        }
+        if let Some(callback) = self.callback.as_mut() {
+            callback(processed, self.total);
+        }
+        self.next_report = (processed / self.interval + 1) * self.interval;
+    }
+
+    // Report the whole input as processed, which every scan ends with
+    pub(crate) fn finish(&mut self) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(self.total, self.total);
+        }
+    }
+}
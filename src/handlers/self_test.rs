@@ -0,0 +1,244 @@
+// End to end check of signing, diffing and patching, on pseudo random data derived from a
+// seed for machines without the data/ fixtures, or on a pair of real files. A failing
+// self test run is replayed from its seed
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::error::RollingHashError;
+use super::file_diff::{generate_diff, read_delta, write_delta, DiffStats};
+use super::file_io::{read_file_to_buffer_with_retry, RetryPolicy};
+use super::patch::verify_patch;
+use super::signature::{
+    find_blocksize, get_signature_streaming, read_signature, write_signature, SignatureOptions,
+};
+
+// Longest run of bytes touched by a single edit
+const MAX_EDIT_LENGTH: usize = 32;
+
+// xorshift64* pseudo random number generator
+pub struct SeededRng(u64);
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        // The all zero state never changes, so mix the seed with a non zero constant
+        Self((seed ^ 0x9e37_79b9_7f4a_7c15).max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Uniform enough value in 0..bound
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+// len pseudo random bytes
+pub fn gen_data(seed: u64, len: usize) -> Vec<u8> {
+    let mut rng = SeededRng::new(seed);
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+// Copy of data with edit_count random edits, each overwriting, inserting or deleting
+// a run of 1 to MAX_EDIT_LENGTH bytes at a random offset
+pub fn apply_edits(data: &[u8], seed: u64, edit_count: usize) -> Vec<u8> {
+    let mut rng = SeededRng::new(seed.wrapping_add(1));
+    let mut edited = data.to_vec();
+    for _ in 0..edit_count {
+        let offset = rng.below(edited.len() + 1);
+        let length = 1 + rng.below(MAX_EDIT_LENGTH);
+        let run: Vec<u8> = (0..length).map(|_| rng.next_u64() as u8).collect();
+        match rng.below(3) {
+            0 => {
+                let end = (offset + length).min(edited.len());
+                edited.splice(offset..end, run);
+            }
+            1 => {
+                edited.splice(offset..offset, run);
+            }
+            _ => {
+                let end = (offset + length).min(edited.len());
+                edited.drain(offset..end);
+            }
+        }
+    }
+    edited
+}
+
+// Seed of a run not given one, from the clock
+pub fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+// Outcome of one self test run
+#[derive(Debug)]
+pub struct SelfTestReport {
+    pub seed: u64,
+    pub data_len: usize,
+    pub edit_count: usize,
+    pub block_size: u32,
+    // Entries of the delta, 0 if the run failed before diffing
+    pub delta_entries: usize,
+    // Why the run failed, None if the patched file is the edited file
+    pub failure: Option<String>,
+    pub elapsed: Duration,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.failure {
+            None => write!(f, "PASS seed {}", self.seed)?,
+            Some(failure) => write!(f, "FAIL seed {}: {}", self.seed, failure)?,
+        }
+        write!(
+            f,
+            " ({} bytes, {} edits, blocks of {} bytes, {} delta entries, {:.3}s)",
+            self.data_len,
+            self.edit_count,
+            self.block_size,
+            self.delta_entries,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+// Sign data_len bytes generated from seed, diff a copy with edit_count edits against the
+// signature and check that applying the delta to the original reproduces the copy. The
+// signature and the delta go through their file formats in memory
+pub fn run_self_test(seed: u64, data_len: usize, edit_count: usize) -> SelfTestReport {
+    let start = Instant::now();
+    let old_file = gen_data(seed, data_len);
+    let new_file = apply_edits(&old_file, seed, edit_count);
+    let block_size = find_blocksize(data_len as u64);
+
+    let (delta_entries, failure) = match round_trip(&old_file, &new_file, block_size) {
+        Ok(round_trip) => (
+            round_trip.delta_entries,
+            round_trip.first_difference.map(|offset| {
+                format!(
+                    "patched file differs from the edited file at byte {}",
+                    offset
+                )
+            }),
+        ),
+        Err(error) => (0, Some(error.to_string())),
+    };
+    SelfTestReport {
+        seed,
+        data_len,
+        edit_count,
+        block_size,
+        delta_entries,
+        failure,
+        elapsed: start.elapsed(),
+    }
+}
+
+// Signature and delta files of a round trip, and how well the delta rebuilt the new file
+pub struct RoundTrip {
+    pub signature_file: Vec<u8>,
+    pub delta_file: Vec<u8>,
+    pub delta_entries: usize,
+    pub stats: DiffStats,
+    // Offset of the first byte the patched file gets wrong, None if it is the new file
+    pub first_difference: Option<u64>,
+}
+
+// Sign old_file in blocks of block_size bytes, diff new_file against the signature and
+// patch old_file with the delta, the signature and the delta going through their file
+// formats in memory
+pub fn round_trip(
+    old_file: &[u8],
+    new_file: &[u8],
+    block_size: u32,
+) -> Result<RoundTrip, RollingHashError> {
+    let signature = get_signature_streaming(
+        &mut &old_file[..],
+        block_size,
+        &SignatureOptions::default(),
+        &RetryPolicy::default(),
+    )?;
+    let mut signature_file = Vec::new();
+    write_signature(&signature, &mut signature_file)?;
+    let signature = read_signature(&mut signature_file.as_slice())?;
+
+    let mut delta_file = Vec::new();
+    write_delta(
+        &generate_diff(new_file, &signature, block_size as usize),
+        &mut delta_file,
+    )?;
+    let delta = read_delta(&mut delta_file.as_slice())?;
+    signature.check_delta(&delta)?;
+    signature.check_old_file(old_file)?;
+    signature.check_matched_blocks(old_file, &delta)?;
+    let first_difference = verify_patch(old_file, &delta, block_size, new_file)?;
+    Ok(RoundTrip {
+        signature_file,
+        delta_file,
+        delta_entries: delta.len(),
+        stats: DiffStats::of(new_file.len() as u64, &delta, block_size),
+        first_difference,
+    })
+}
+
+// round_trip of two files, at the block size generate-signature picks for the old file
+pub fn round_trip_files(
+    old_file: &File,
+    new_file: &File,
+    retry: &RetryPolicy,
+) -> Result<RoundTrip, RollingHashError> {
+    let old_file_buf = read_file_to_buffer_with_retry(&mut BufReader::new(old_file), retry)?;
+    let new_file_buf = read_file_to_buffer_with_retry(&mut BufReader::new(new_file), retry)?;
+    round_trip(
+        &old_file_buf,
+        &new_file_buf,
+        find_blocksize(old_file_buf.len() as u64),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_generated_data_is_reproducible() {
+        assert_eq!(gen_data(7, 1000), gen_data(7, 1000));
+        assert_ne!(gen_data(7, 1000), gen_data(8, 1000));
+        assert_eq!(gen_data(0, 1000).len(), 1000);
+
+        let data = gen_data(3, 4096);
+        let edited = apply_edits(&data, 3, 10);
+        assert_eq!(edited, apply_edits(&data, 3, 10));
+        assert_ne!(data, edited);
+        assert_eq!(data, apply_edits(&data, 3, 0));
+    }
+
+    #[test]
+    pub fn test_self_test_passes_and_is_reproducible() {
+        for (seed, data_len) in [(1, 0), (2, 100), (3, 64 * 1024)] {
+            let report = run_self_test(seed, data_len, 20);
+            assert!(report.passed(), "{}", report);
+            assert!(report
+                .to_string()
+                .starts_with(&format!("PASS seed {} (", seed)));
+            assert_eq!(
+                report.delta_entries,
+                run_self_test(seed, data_len, 20).delta_entries
+            );
+        }
+    }
+}
@@ -1,16 +1,30 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Result};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result, Write};
+use std::mem::size_of;
 
-use bincode::serialize_into;
+use bincode::{deserialize_from, serialize_into};
+use clap::ValueEnum;
 use hmac_sha256::Hash as Sha256Hash;
 use serde::{Deserialize, Serialize};
 
-use crate::handlers::{file_io, window_checksum};
+use crate::handlers::bundle::BUNDLE_MAGIC;
+use crate::handlers::error::RollingHashError;
+use crate::handlers::file_diff::{
+    strong_hashes_by_index, validate_delta, VerifyMatch, DELTA_MAGIC,
+};
+use crate::handlers::file_io::RetryPolicy;
+use crate::handlers::manifest::MANIFEST_MAGIC;
+use crate::handlers::progress::ProgressReporter;
+use crate::handlers::window_checksum::{ChecksumState, RollingChecksumAlgorithm};
+use crate::handlers::{file_io, md4, window_checksum};
 
 // Signature of input file
-#[derive(Debug, Serialize, Deserialize)]
+// Serialized field names are part of the documented schema and kept stable
+// independently of the Rust field names
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileChunkSignature {
+    #[serde(rename = "block_size")]
     pub block_chunk_size: u32,
 
     // Rolling checksum requires a store checksum based hash to avoid collision
@@ -18,80 +32,606 @@ pub struct FileChunkSignature {
     // This weaker hash is used while shifting the rolling window
     // Hence both hashes are required.
     // This stores a mapping of index based hash to the sha256 based hash
+    #[serde(rename = "blocks")]
     pub checksum_map: HashMap<u32, Vec<BlockChunkHashes>>,
+
+    // Weak checksum the blocks were summed with, which diffing must roll with too
+    #[serde(rename = "rolling_checksum")]
+    pub rolling_checksum: RollingChecksumAlgorithm,
+
+    // Number of blocks and length in bytes of the signed file, against which signatures
+    // and deltas are checked before they are used
+    #[serde(rename = "total_chunks")]
+    pub total_chunks: u64,
+    #[serde(rename = "original_length")]
+    pub original_length: u64,
+
+    // With SignatureOptions::dedup, blocks identical to an earlier block have no entry of
+    // their own. Runs of them are listed here, in block order, against the earlier block
+    #[serde(rename = "duplicates")]
+    pub duplicates: Vec<DuplicateRun>,
+
+    // Strong hash of the blocks, and how many leading bytes of its digest the entries keep.
+    // Every entry's hash is exactly strong_hash_len bytes long
+    #[serde(rename = "strong_hash_algorithm")]
+    pub strong_hash: StrongHashAlgorithm,
+    #[serde(rename = "strong_hash_len")]
+    pub strong_hash_len: u8,
+
+    // With SignatureOptions::whole_file_hash, the rolling checksum and SHA-256 of the whole
+    // signed file, so that a new file identical to it is recognised without diffing
+    #[serde(rename = "whole_file_checksum")]
+    pub whole_file_checksum: Option<u32>,
+    #[serde(rename = "whole_file_hash")]
+    pub whole_file_hash: Option<[u8; 32]>,
+
+    // With SignatureOptions::domain_tag, the tag every strong hash input, blocks and whole
+    // file alike, is prefixed with, so these hashes can't stand in for hashes made elsewhere
+    #[serde(rename = "domain_tag")]
+    pub domain_tag: Option<String>,
 }
 
 impl FileChunkSignature {
+    // Strong hash of a chunk as the signature's entries record it, to compare with them
+    pub fn strong_hash_of(&self, chunk: &[u8]) -> Vec<u8> {
+        chunk_strong_hash(
+            self.strong_hash,
+            self.strong_hash_len,
+            self.domain_tag.as_deref(),
+            chunk,
+        )
+    }
+
     // Evaluate hash checksum for index based checksum
 
     pub fn block_chunk_hashes(&self, key: &u32) -> Option<&Vec<BlockChunkHashes>> {
         self.checksum_map.get(key)
     }
+
+    // Approximate bytes of memory held by the signature: the struct itself,
+    // the hash map's slots (key, bucket Vec and one control byte each),
+    // the allocated capacity of every bucket and of every entry's strong hash
+    pub fn memory_footprint(&self) -> usize {
+        let map_slots = self.checksum_map.capacity()
+            * (size_of::<u32>() + size_of::<Vec<BlockChunkHashes>>() + 1);
+        let buckets: usize = self
+            .checksum_map
+            .values()
+            .map(|hashes| {
+                hashes.capacity() * size_of::<BlockChunkHashes>()
+                    + hashes
+                        .iter()
+                        .map(|entry| entry.hash.capacity())
+                        .sum::<usize>()
+            })
+            .sum();
+        let duplicates = self.duplicates.capacity() * size_of::<DuplicateRun>();
+        size_of::<Self>() + map_slots + buckets + duplicates
+    }
+
+    // Number of blocks in the signed file, counting duplicates
+    pub fn block_count(&self) -> usize {
+        self.entry_count()
+            + self
+                .duplicates
+                .iter()
+                .map(|run| run.count as usize)
+                .sum::<usize>()
+    }
+
+    // Number of blocks with an entry of their own
+    pub fn entry_count(&self) -> usize {
+        self.checksum_map.values().map(Vec::len).sum()
+    }
+
+    // Length of the signed file's last block, short unless the file length is a multiple
+    // of the block size. It follows from original_length, so the format doesn't store it
+    pub fn last_block_size(&self) -> usize {
+        match self.original_length % self.block_chunk_size as u64 {
+            0 if self.original_length > 0 => self.block_chunk_size as usize,
+            tail => tail as usize,
+        }
+    }
+
+    // Length of the signed file's block with this index
+    pub fn block_len(&self, index: u64) -> usize {
+        if index + 1 == self.total_chunks {
+            self.last_block_size()
+        } else {
+            self.block_chunk_size as usize
+        }
+    }
+
+    // Whether the signature records a whole file checksum and hash, and new_file has them.
+    // The cheap length and rolling checksum are compared before hashing new_file
+    pub fn is_whole_file(&self, new_file: &[u8]) -> bool {
+        match (self.whole_file_checksum, self.whole_file_hash) {
+            (Some(checksum), Some(hash)) => {
+                new_file.len() as u64 == self.original_length
+                    && window_checksum::rolling_checksum(self.rolling_checksum, new_file)
+                        == checksum
+                    && {
+                        let mut hasher = tagged_sha256(self.domain_tag.as_deref());
+                        hasher.update(new_file);
+                        hasher.finalize() == hash
+                    }
+            }
+            _ => false,
+        }
+    }
+
+    // Check that every match of a delta refers to one of the signed file's blocks
+    pub fn check_delta(&self, delta: &[VerifyMatch]) -> std::result::Result<(), RollingHashError> {
+        validate_delta(delta, self.total_chunks)
+            .map_err(|error| RollingHashError::InvalidDelta(error.to_string()))
+    }
+
+    // Check that the old file is the file the signature was generated from, as far as its
+    // length and first block tell, so a delta isn't applied to another version of it
+    pub fn check_old_file(&self, old_file: &[u8]) -> std::result::Result<(), RollingHashError> {
+        if old_file.len() as u64 != self.original_length {
+            return Err(RollingHashError::OldFileMismatch(format!(
+                "it is {} bytes long, but the signed file was {} bytes",
+                old_file.len(),
+                self.original_length
+            )));
+        }
+        let first_block = &old_file[..old_file.len().min(self.block_chunk_size as usize)];
+        if first_block.is_empty() {
+            return Ok(());
+        }
+        let weak_key = window_checksum::rolling_checksum(self.rolling_checksum, first_block);
+        let strong_hash = self.strong_hash_of(first_block);
+        // The first block always has an entry of its own, as there is no earlier block
+        // for it to duplicate
+        let matched = self.block_chunk_hashes(&weak_key).is_some_and(|hashes| {
+            hashes
+                .iter()
+                .any(|entry| entry.index == 0 && entry.hash == strong_hash)
+        });
+        if !matched {
+            return Err(RollingHashError::OldFileMismatch(
+                "its first block differs from the signed file's".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Check every old block a delta copies against its strong hash, so a block that
+    // changed since signing isn't copied into the new file. check_delta and check_old_file
+    // must have passed, so every matched block is in range
+    pub fn check_matched_blocks(
+        &self,
+        old_file: &[u8],
+        delta: &[VerifyMatch],
+    ) -> std::result::Result<(), RollingHashError> {
+        let strong_hashes = strong_hashes_by_index(self);
+        let mut checked = vec![false; self.total_chunks as usize];
+        for index in delta.iter().filter_map(VerifyMatch::matched_block) {
+            let index = index as usize;
+            if checked[index] {
+                continue;
+            }
+            let start = index * self.block_chunk_size as usize;
+            let block = &old_file[start..start + self.block_len(index as u64)];
+            if strong_hashes.get(index).copied().flatten()
+                != Some(self.strong_hash_of(block).as_slice())
+            {
+                return Err(RollingHashError::OldFileMismatch(format!(
+                    "block {} differs from the signed file's",
+                    index
+                )));
+            }
+            checked[index] = true;
+        }
+        Ok(())
+    }
+
+    // Check that the recorded block size and strong hash length are ones signing can produce
+    pub(crate) fn check_parameters(&self) -> std::result::Result<(), RollingHashError> {
+        window_checksum::validate_block_size(self.block_chunk_size)
+            .map_err(|error| RollingHashError::InvalidSignature(error.to_string()))?;
+        check_strong_hash_len(self.strong_hash, self.strong_hash_len)
+            .map_err(|error| RollingHashError::InvalidSignature(error.to_string()))
+    }
+
+    // Check that the recorded block count and length agree with each other and with the
+    // entries, so every index a diff can match is below total_chunks, and that every
+    // strong hash has the recorded length. check_parameters must have passed
+    pub(crate) fn check_totals(&self) -> std::result::Result<(), String> {
+        let expected_chunks = self.original_length.div_ceil(self.block_chunk_size as u64);
+        if expected_chunks != self.total_chunks {
+            return Err(format!(
+                "{} blocks recorded for {} bytes in blocks of {}",
+                self.total_chunks, self.original_length, self.block_chunk_size
+            ));
+        }
+        if self.block_count() as u64 != self.total_chunks {
+            return Err(format!(
+                "{} block entries, but {} blocks recorded",
+                self.block_count(),
+                self.total_chunks
+            ));
+        }
+        if let Some(entry) = self
+            .checksum_map
+            .values()
+            .flatten()
+            .find(|entry| entry.index >= self.total_chunks)
+        {
+            return Err(format!(
+                "block entry {} is out of range for {} blocks",
+                entry.index, self.total_chunks
+            ));
+        }
+        if let Some(entry) = self
+            .checksum_map
+            .values()
+            .flatten()
+            .find(|entry| entry.hash.len() != self.strong_hash_len as usize)
+        {
+            return Err(format!(
+                "strong hash of block {} is {} bytes, not {}",
+                entry.index,
+                entry.hash.len(),
+                self.strong_hash_len
+            ));
+        }
+        match self.duplicates.iter().find(|run| {
+            run.original >= run.first_index
+                || run
+                    .first_index
+                    .checked_add(run.count)
+                    .is_none_or(|end| end > self.total_chunks)
+        }) {
+            Some(run) => Err(format!(
+                "duplicate blocks {}+{} of block {} are out of range for {} blocks",
+                run.first_index, run.count, run.original, self.total_chunks
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// count consecutive blocks starting at first_index, all identical to the earlier block
+// original
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DuplicateRun {
+    #[serde(rename = "first_index")]
+    pub first_index: u64,
+    #[serde(rename = "count")]
+    pub count: u64,
+    #[serde(rename = "original")]
+    pub original: u64,
 }
 
 // File block chunk has two hash as discussed above.
-// This structure stores both index based hash and SHA 256 checksum based hash
-#[derive(Debug, Serialize, Deserialize)]
+// This structure stores both index based hash and SHA 256 checksum based hash.
+// The strong hash is the signature's strong_hash_len leading bytes of the digest
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockChunkHashes {
-    pub index: u32,
-    pub hash: [u8; 32],
+    #[serde(rename = "index")]
+    pub index: u64,
+    #[serde(rename = "strong_hash")]
+    pub hash: Vec<u8>,
 }
 
 pub fn pointer_at_last_chunk(chunk_len: usize, buf_len: usize) -> bool {
     chunk_len == buf_len
 }
 
-// Get signature for given buffer and chunk size
-pub fn get_signature(buffer: &mut Vec<u8>, block_size: u32) -> FileChunkSignature {
-    let mut signature = FileChunkSignature {
-        block_chunk_size: block_size,
-        checksum_map: HashMap::new(),
-    };
+// Options controlling get_signature_with_options
+#[derive(Debug, Clone, Default)]
+pub struct SignatureOptions {
+    // Remember the bytes and strong hash of the last block seen for every weak hash,
+    // and reuse the strong hash when the next block with that weak hash has the same bytes.
+    // Speeds up signing repetitive files at the cost of holding a copy of their distinct blocks
+    pub strong_hash_cache: bool,
+    // Use this block size whatever the length of the file, instead of deriving it from
+    // the length, so that signatures of files of any length share block boundaries.
+    // The block size override of write_signature_file and plan_signature_file
+    pub fixed_block_size: Option<u32>,
+    // Weak checksum of the blocks, recorded in the signature
+    pub rolling_checksum: RollingChecksumAlgorithm,
+    // Record blocks identical to an earlier block as a back-reference to it instead of
+    // an entry with its own strong hash. Shrinks signatures of files with long repeated
+    // regions, such as zero filled disk images
+    pub dedup: bool,
+    // Strong hash of the blocks, recorded in the signature
+    pub strong_hash: StrongHashAlgorithm,
+    // Keep only this many leading bytes of each strong hash, between 1 and the digest
+    // length, to make the signature smaller. The whole digest when None
+    pub strong_hash_len: Option<u8>,
+    // Hash the blocks of a file on up to this many threads, reading it a batch of blocks
+    // at a time. 0 and 1 sign on the calling thread, reading a block at a time. Only used
+    // by signature_of_file, and only with the parallel feature
+    pub threads: usize,
+    // Also record the rolling checksum and SHA-256 of the whole file, for diffing to
+    // recognise an identical new file without scanning it
+    pub whole_file_hash: bool,
+    // Prefix every strong hash input with this tag, recorded in the signature
+    pub domain_tag: Option<String>,
+}
 
-    let chunk_size = block_size as usize;
-    let mut chunk_index = 0u32;
+// Get signature for given buffer and chunk size.
+// Panics on a block size validate_block_size rejects; get_signature_with_options returns
+// RollingHashError::InvalidBlockSize instead
+pub fn get_signature(buffer: &[u8], block_size: u32) -> FileChunkSignature {
+    get_signature_with_options(buffer, block_size, &SignatureOptions::default())
+        .unwrap_or_else(|error| panic!("{}", error))
+}
 
-    loop {
-        // Extract block of chunk from buffer
-        let buffer_length = buffer.len();
-        let block_chunk: &[u8] = if chunk_size < buffer_length {
-            &buffer[..buffer_length]
+// Builds a signature one block at a time, for both the in-memory and the streaming paths
+struct SignatureBuilder<'a> {
+    signature: FileChunkSignature,
+    options: &'a SignatureOptions,
+    strong_hash_cache: HashMap<u32, (Vec<u8>, Vec<u8>)>,
+    // Rolling checksum and SHA-256 of every byte signed so far, with whole_file_hash
+    whole_file: Option<(ChecksumState, Sha256Hash)>,
+}
+
+impl<'a> SignatureBuilder<'a> {
+    fn new(block_size: u32, options: &'a SignatureOptions) -> Self {
+        Self {
+            signature: FileChunkSignature {
+                block_chunk_size: block_size,
+                checksum_map: HashMap::new(),
+                rolling_checksum: options.rolling_checksum,
+                total_chunks: 0,
+                original_length: 0,
+                duplicates: Vec::new(),
+                strong_hash: options.strong_hash,
+                strong_hash_len: options
+                    .strong_hash_len
+                    .unwrap_or(u8::MAX)
+                    .clamp(1, options.strong_hash.digest_len()),
+                whole_file_checksum: None,
+                whole_file_hash: None,
+                domain_tag: options.domain_tag.clone(),
+            },
+            options,
+            strong_hash_cache: HashMap::new(),
+            whole_file: options.whole_file_hash.then(|| {
+                (
+                    ChecksumState::new(options.rolling_checksum),
+                    tagged_sha256(options.domain_tag.as_deref()),
+                )
+            }),
+        }
+    }
+
+    // Feed bytes of the signed file, in order, to the whole file checksum and hash
+    fn hash_whole_file(&mut self, bytes: &[u8]) {
+        if let Some((checksum, hasher)) = &mut self.whole_file {
+            checksum.add_bytes_at_end(bytes);
+            hasher.update(bytes);
+        }
+    }
+
+    // The signature of every block added, with the whole file checksum and hash if kept
+    fn finish(self) -> FileChunkSignature {
+        let mut signature = self.signature;
+        if let Some((checksum, hasher)) = self.whole_file {
+            signature.whole_file_checksum = Some(checksum.weak_key());
+            signature.whole_file_hash = Some(hasher.finalize());
+        }
+        signature
+    }
+
+    fn add_block(&mut self, block_chunk: &[u8]) {
+        let (index_hash, sha256_hash) = self.hash_block(block_chunk);
+        self.push_block(index_hash, sha256_hash, block_chunk.len());
+        self.hash_whole_file(block_chunk);
+    }
+
+    // Weak and strong hash of a block, reusing the cached strong hash of identical bytes
+    // with the strong hash cache
+    fn hash_block(&mut self, block_chunk: &[u8]) -> (u32, Vec<u8>) {
+        let index_hash =
+            window_checksum::rolling_checksum(self.options.rolling_checksum, block_chunk);
+
+        let sha256_hash = if self.options.strong_hash_cache {
+            match self.strong_hash_cache.get(&index_hash) {
+                Some((cached_chunk, cached_hash)) if cached_chunk.as_slice() == block_chunk => {
+                    cached_hash.clone()
+                }
+                _ => {
+                    let hash = self.signature.strong_hash_of(block_chunk);
+                    self.strong_hash_cache
+                        .insert(index_hash, (block_chunk.to_vec(), hash.clone()));
+                    hash
+                }
+            }
         } else {
-            &buffer[..chunk_size]
+            self.signature.strong_hash_of(block_chunk)
         };
+        (index_hash, sha256_hash)
+    }
 
-        let chunk_len = block_chunk.len();
-        if chunk_len == 0 {
-            break;
+    // Add the next block's entry to the signature table
+    fn push_block(&mut self, index_hash: u32, sha256_hash: Vec<u8>, block_len: usize) {
+        let index = self.signature.total_chunks;
+        let chunk_hashes = self.signature.checksum_map.entry(index_hash).or_default();
+        let original = chunk_hashes
+            .iter()
+            .find(|entry| entry.hash == sha256_hash)
+            .map(|entry| entry.index);
+
+        match original {
+            Some(original) if self.options.dedup => match self.signature.duplicates.last_mut() {
+                Some(run) if run.original == original && run.first_index + run.count == index => {
+                    run.count += 1
+                }
+                _ => self.signature.duplicates.push(DuplicateRun {
+                    first_index: index,
+                    count: 1,
+                    original,
+                }),
+            },
+            _ => chunk_hashes.push(BlockChunkHashes {
+                index,
+                hash: sha256_hash,
+            }),
         }
+        self.signature.total_chunks += 1;
+        self.signature.original_length += block_len as u64;
+    }
+}
 
-        let index_hash = window_checksum::rolling_window_checksum(block_chunk);
+// Get signature for given buffer and chunk size, optionally caching strong hashes.
+// Fails with RollingHashError::InvalidBlockSize for a block size validate_block_size rejects
+pub fn get_signature_with_options(
+    buffer: &[u8],
+    block_size: u32,
+    options: &SignatureOptions,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    sign_buffer(buffer, block_size, options, None)
+}
 
-        let sha256_hash = chunk_sha256_hash(block_chunk);
+// get_signature_with_options calling progress with the bytes signed so far and the buffer
+// length, every PROGRESS_INTERVAL_BLOCKS blocks and once the whole buffer is signed
+pub fn get_signature_with_progress(
+    buffer: &[u8],
+    block_size: u32,
+    options: &SignatureOptions,
+    progress: &mut dyn FnMut(u64, u64),
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    sign_buffer(buffer, block_size, options, Some(progress))
+}
 
-        // Add entry to signature table
-        let chunk_hashes = signature
-            .checksum_map
-            .entry(index_hash)
-            .or_insert_with(Vec::new);
+fn sign_buffer(
+    buffer: &[u8],
+    block_size: u32,
+    options: &SignatureOptions,
+    progress: Option<&mut dyn FnMut(u64, u64)>,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    window_checksum::validate_block_size(block_size)?;
+    let mut builder = SignatureBuilder::new(block_size, options);
+    let mut progress = ProgressReporter::new(progress, buffer.len() as u64, block_size as u64);
+    for block_chunk in buffer.chunks(block_size as usize) {
+        builder.add_block(block_chunk);
+        progress.advance(builder.signature.original_length);
+    }
+    progress.finish();
+    Ok(builder.finish())
+}
+
+// get_signature_with_options with the blocks hashed on up to threads threads, each taking
+// a contiguous run of blocks. Entries are added in block order afterwards, so the signature
+// is identical to the serial one. With the strong hash cache, each thread keeps its own
+// cache for its run
+#[cfg(feature = "parallel")]
+pub fn get_signature_parallel(
+    buffer: &[u8],
+    block_size: u32,
+    options: &SignatureOptions,
+    threads: usize,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    window_checksum::validate_block_size(block_size)?;
+    let mut builder = SignatureBuilder::new(block_size, options);
+    builder.add_blocks_parallel(buffer, threads);
+    Ok(builder.finish())
+}
 
-        chunk_hashes.push(BlockChunkHashes {
-            index: chunk_index,
-            hash: sha256_hash,
+// Bytes of blocks each thread of get_signature_parallel_streaming hashes per batch,
+// rounded up to a whole block
+#[cfg(feature = "parallel")]
+const PARALLEL_RUN_BYTES: usize = 1 << 20;
+
+// get_signature_parallel reading the input in batches of about PARALLEL_RUN_BYTES per
+// thread, so that only one batch and the signature are held in memory. Transient read
+// errors are retried according to the retry policy
+#[cfg(feature = "parallel")]
+pub fn get_signature_parallel_streaming(
+    input: &mut impl Read,
+    block_size: u32,
+    options: &SignatureOptions,
+    threads: usize,
+    retry: &RetryPolicy,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    window_checksum::validate_block_size(block_size)?;
+    let run_blocks = PARALLEL_RUN_BYTES.div_ceil(block_size as usize);
+    let mut batch = vec![0u8; threads.max(1) * run_blocks * block_size as usize];
+    let mut builder = SignatureBuilder::new(block_size, options);
+    loop {
+        let batch_len = file_io::read_block(input, &mut batch, retry)?;
+        builder.add_blocks_parallel(&batch[..batch_len], threads);
+        if batch_len < batch.len() {
+            break;
+        }
+    }
+    Ok(builder.finish())
+}
+
+#[cfg(feature = "parallel")]
+impl SignatureBuilder<'_> {
+    // Add the blocks of buffer, which starts at a block boundary, hashing them on up to
+    // threads threads
+    fn add_blocks_parallel(&mut self, buffer: &[u8], threads: usize) {
+        use std::thread;
+
+        let block_size = self.signature.block_chunk_size as usize;
+        let block_count = buffer.len().div_ceil(block_size);
+        let blocks_per_thread = block_count.div_ceil(threads.max(1)).max(1);
+        let run_size = blocks_per_thread * block_size;
+        let options = self.options;
+        let hashed_runs: Vec<Vec<(u32, Vec<u8>, usize)>> = thread::scope(|scope| {
+            let workers: Vec<_> = buffer
+                .chunks(run_size)
+                .map(|run| {
+                    scope.spawn(move || {
+                        // Hashes blocks the way this builder does, with a cache of its own
+                        let mut worker = SignatureBuilder::new(block_size as u32, options);
+                        run.chunks(block_size)
+                            .map(|block_chunk| {
+                                let (index_hash, strong_hash) = worker.hash_block(block_chunk);
+                                (index_hash, strong_hash, block_chunk.len())
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("signature thread panicked"))
+                .collect()
         });
 
-        if pointer_at_last_chunk(chunk_len, buffer.len()) {
+        for (index_hash, sha256_hash, block_len) in hashed_runs.into_iter().flatten() {
+            self.push_block(index_hash, sha256_hash, block_len);
+        }
+        self.hash_whole_file(buffer);
+    }
+}
+
+// Like get_signature_with_options, reading the input one block at a time, so that only
+// one block and the signature itself are held in memory. Transient read errors are
+// retried according to the retry policy. A block size validate_block_size rejects fails
+// with ErrorKind::InvalidInput before anything is read
+pub fn get_signature_streaming(
+    input: &mut impl Read,
+    block_size: u32,
+    options: &SignatureOptions,
+    retry: &RetryPolicy,
+) -> Result<FileChunkSignature> {
+    window_checksum::validate_block_size(block_size)?;
+    let mut builder = SignatureBuilder::new(block_size, options);
+    let mut block_chunk = vec![0u8; block_size as usize];
+    loop {
+        let chunk_len = file_io::read_block(input, &mut block_chunk, retry)?;
+        if chunk_len == 0 {
+            break;
+        }
+        builder.add_block(&block_chunk[..chunk_len]);
+        if chunk_len < block_chunk.len() {
             break;
         }
-        // Prepare buffer for next iteration
-        buffer.drain(..chunk_len);
-        chunk_index += 1;
     }
-    signature
+    Ok(builder.finish())
 }
 
 // Algorithm derived from https://fossies.org/linux/rdiff-backup/src/rdiff_backup/Rdiff.py
-fn find_blocksize(file_length: u64) -> u32 {
+pub(crate) fn find_blocksize(file_length: u64) -> u32 {
     if file_length <= 4096 {
         64
     } else {
@@ -99,26 +639,1138 @@ fn find_blocksize(file_length: u64) -> u32 {
     }
 }
 
-// Get signature for given input file and write the binary in a file
-pub fn write_signature_file(input_file: &File, signature_file: &mut File) -> Result<()> {
-    let file_len_res = input_file.metadata().map(|m| m.len());
-    let chunk_size = match file_len_res {
-        Ok(file_len) => find_blocksize(file_len),
-        Err(_) => 500, // Use default block chunk size of 500 if file metadata doesn't have length info
+// Signature file layout:
+//   magic "RHSG", format version (1 byte)
+//   bincode serialized FileChunkSignature
+const SIGNATURE_MAGIC: &[u8; 4] = b"RHSG";
+const SIGNATURE_VERSION: u8 = 1;
+
+// Write a signature with its header
+pub fn write_signature(
+    signature: &FileChunkSignature,
+    out: &mut impl Write,
+) -> std::result::Result<(), RollingHashError> {
+    out.write_all(SIGNATURE_MAGIC)?;
+    out.write_all(&[SIGNATURE_VERSION])?;
+    serialize_into(out, signature).map_err(RollingHashError::Serialize)
+}
+
+// Which of this tool's other files starts with the header, so a file passed in the
+// wrong argument can be named
+fn other_file_kind(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(DELTA_MAGIC) {
+        Some("a delta file")
+    } else if header.starts_with(MANIFEST_MAGIC) {
+        Some("a directory manifest")
+    } else if BUNDLE_MAGIC.starts_with(header) {
+        Some("a bundle file")
+    } else {
+        None
+    }
+}
+
+// Check the header written by write_signature, so that another kind of file is
+// reported as such rather than as a corrupt signature. Returns the format version
+fn read_signature_header(input: &mut impl Read) -> std::result::Result<u8, RollingHashError> {
+    let mut header = [0u8; 5];
+    input
+        .read_exact(&mut header)
+        .map_err(|error| match error.kind() {
+            ErrorKind::UnexpectedEof => {
+                RollingHashError::InvalidSignature("file is too short for a signature".to_string())
+            }
+            _ => RollingHashError::Io(error),
+        })?;
+    if &header[..4] != SIGNATURE_MAGIC {
+        return Err(RollingHashError::NotASignature(other_file_kind(&header)));
+    }
+    if header[4] != SIGNATURE_VERSION {
+        return Err(RollingHashError::InvalidSignature(format!(
+            "unsupported signature format version {}",
+            header[4]
+        )));
+    }
+    Ok(header[4])
+}
+
+// Read a signature written by write_signature
+pub fn read_signature(
+    input: &mut impl Read,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    Ok(read_signature_with_version(input)?.1)
+}
+
+// Like read_signature, also returning the format version the signature was written in
+pub fn read_signature_with_version(
+    input: &mut impl Read,
+) -> std::result::Result<(u8, FileChunkSignature), RollingHashError> {
+    let (version, signature) = read_signature_entries(input)?;
+    signature
+        .check_totals()
+        .map_err(RollingHashError::InvalidSignature)?;
+    Ok((version, signature))
+}
+
+// Read a signature previously written by write_signature_file
+pub fn read_signature_file(
+    signature_file: &File,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    read_signature(&mut BufReader::new(signature_file))
+}
+
+// Read a signature whose entries may be damaged, checking only its header and block size.
+// Returns the format version with the signature
+fn read_signature_entries(
+    input: &mut impl Read,
+) -> std::result::Result<(u8, FileChunkSignature), RollingHashError> {
+    let version = read_signature_header(input)?;
+    let signature: FileChunkSignature = deserialize_from(input)?;
+    signature.check_parameters()?;
+    Ok((version, signature))
+}
+
+// Block size write_signature_file signs a file of file_length bytes with: the fixed
+// block size if there is one, otherwise one derived from the length
+pub fn signature_block_size(file_length: u64, options: &SignatureOptions) -> u32 {
+    options
+        .fixed_block_size
+        .unwrap_or_else(|| find_blocksize(file_length))
+}
+
+// Shape of the signature write_signature_file would write, for reporting before signing
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignaturePlan {
+    pub file_length: u64,
+    pub block_size: u32,
+    pub block_count: u64,
+}
+
+impl SignaturePlan {
+    pub fn new(file_length: u64, options: &SignatureOptions) -> Self {
+        let block_size = signature_block_size(file_length, options);
+        SignaturePlan {
+            file_length,
+            block_size,
+            block_count: file_length.div_ceil(block_size.max(1) as u64),
+        }
+    }
+}
+
+// Plan the signature of a file without hashing it. A stream has to be read to its end
+// to learn its length
+pub fn plan_signature_file(
+    input_file: &File,
+    retry: &RetryPolicy,
+    options: &SignatureOptions,
+) -> std::result::Result<SignaturePlan, RollingHashError> {
+    let file_length = if file_io::is_stream(input_file)? {
+        file_io::read_file_to_buffer_with_retry(&mut BufReader::new(input_file), retry)?.len()
+            as u64
+    } else {
+        input_file.metadata()?.len()
     };
+    let plan = SignaturePlan::new(file_length, options);
+    window_checksum::validate_block_size(plan.block_size)?;
+    Ok(plan)
+}
 
-    let mut input_file_buf = file_io::read_file_to_buffer(&mut BufReader::new(input_file))?;
-    let signature = get_signature(&mut input_file_buf, chunk_size);
+// Get signature for given input file and write the binary in a file
+// Transient read errors are retried according to the retry policy. With default options the
+// signature is the one crate::signature_of gives for the file's contents.
+// The block size override is options.fixed_block_size rather than a parameter of its own:
+// None derives the block size from the file length, and Some(0), or a size above
+// MAX_BLOCK_SIZE, fails with RollingHashError::InvalidBlockSize before anything is read
+pub fn write_signature_file(
+    input_file: &File,
+    signature_file: &mut File,
+    retry: &RetryPolicy,
+    options: &SignatureOptions,
+) -> std::result::Result<(), RollingHashError> {
+    let signature = signature_of_file(input_file, retry, options)?;
     let mut signature_writer = BufWriter::new(signature_file);
 
-    serialize_into(&mut signature_writer, &signature).unwrap();
+    write_signature(&signature, &mut signature_writer)?;
+    signature_writer.flush()?;
     Ok(())
 }
 
+// Signature write_signature_file writes for the input file
+pub fn signature_of_file(
+    input_file: &File,
+    retry: &RetryPolicy,
+    options: &SignatureOptions,
+) -> std::result::Result<FileChunkSignature, RollingHashError> {
+    if let Some(strong_hash_len) = options.strong_hash_len {
+        check_strong_hash_len(options.strong_hash, strong_hash_len)?;
+    }
+    // A stream, such as standard input, has no length to derive the block size from
+    // until it has been read whole
+    let derived_block_size =
+        if options.fixed_block_size.is_some() || !file_io::is_stream(input_file)? {
+            Some(signature_block_size(input_file.metadata()?.len(), options))
+        } else {
+            None
+        };
+    let signature = match derived_block_size {
+        #[cfg(feature = "parallel")]
+        Some(chunk_size) if options.threads > 1 => get_signature_parallel_streaming(
+            &mut BufReader::new(input_file),
+            chunk_size,
+            options,
+            options.threads,
+            retry,
+        )?,
+        Some(chunk_size) => {
+            window_checksum::validate_block_size(chunk_size)?;
+            get_signature_streaming(&mut BufReader::new(input_file), chunk_size, options, retry)?
+        }
+        None => {
+            let input_file_buf =
+                file_io::read_file_to_buffer_with_retry(&mut BufReader::new(input_file), retry)?;
+            let chunk_size = signature_block_size(input_file_buf.len() as u64, options);
+            #[cfg(feature = "parallel")]
+            if options.threads > 1 {
+                return get_signature_parallel(
+                    &input_file_buf,
+                    chunk_size,
+                    options,
+                    options.threads,
+                );
+            }
+            get_signature_with_options(&input_file_buf, chunk_size, options)?
+        }
+    };
+    Ok(signature)
+}
+
+// Outcome of repair_signature
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignatureRepair {
+    // Entries of the source's blocks that were missing or damaged and have been rewritten
+    pub repaired: usize,
+    // Damaged entries matching no block of the source, dropped
+    pub removed: usize,
+}
+
+// Check every entry of the signature against the source file it was made from, keeping the
+// block size and every entry still right, and rewriting only the entries whose weak hash,
+// index or strong hash were damaged. The repaired signature matches a fresh signature of
+// the source at the same block size and with the same rolling checksum. Fails, leaving the
+// signature unchanged, if its block size is invalid
+pub fn repair_signature(
+    signature: &mut FileChunkSignature,
+    source: &[u8],
+) -> std::result::Result<SignatureRepair, RollingHashError> {
+    let expected = get_signature_with_options(
+        source,
+        signature.block_chunk_size,
+        &SignatureOptions {
+            rolling_checksum: signature.rolling_checksum,
+            dedup: !signature.duplicates.is_empty(),
+            strong_hash: signature.strong_hash,
+            strong_hash_len: Some(signature.strong_hash_len),
+            whole_file_hash: signature.whole_file_hash.is_some(),
+            domain_tag: signature.domain_tag.clone(),
+            ..SignatureOptions::default()
+        },
+    )?;
+    let stored_count = signature.block_count();
+    let stored_duplicates = std::mem::take(&mut signature.duplicates);
+
+    for (weak_key, hashes) in signature.checksum_map.iter_mut() {
+        let expected_hashes = expected.checksum_map.get(weak_key);
+        hashes.retain(|entry| expected_hashes.is_some_and(|expected| expected.contains(entry)));
+    }
+    signature
+        .checksum_map
+        .retain(|_, hashes| !hashes.is_empty());
+
+    let mut repaired = 0;
+    for (weak_key, expected_hashes) in expected.checksum_map {
+        let hashes = signature.checksum_map.entry(weak_key).or_default();
+        hashes.sort_by_key(|entry| entry.index);
+        hashes.dedup();
+        for entry in expected_hashes {
+            if !hashes.contains(&entry) {
+                hashes.push(entry);
+                repaired += 1;
+            }
+        }
+        hashes.sort_by_key(|entry| entry.index);
+    }
+    // Runs are few and short to describe, so damaged ones are replaced whole
+    let duplicates_kept = stored_duplicates
+        .iter()
+        .filter(|run| expected.duplicates.contains(run))
+        .map(|run| run.count as usize)
+        .sum::<usize>();
+    let expected_duplicates = expected.duplicates.iter().map(|run| run.count as usize);
+    repaired += expected_duplicates.sum::<usize>() - duplicates_kept;
+    signature.duplicates = expected.duplicates;
+    signature.total_chunks = expected.total_chunks;
+    signature.original_length = expected.original_length;
+    signature.whole_file_checksum = expected.whole_file_checksum;
+    signature.whole_file_hash = expected.whole_file_hash;
+    Ok(SignatureRepair {
+        repaired,
+        removed: stored_count + repaired - signature.block_count(),
+    })
+}
+
+// Repair a signature file against its source and write the repaired signature.
+// Fails rather than guess when the damage leaves the signature unreadable
+pub fn repair_signature_file(
+    signature_file: &File,
+    source_file: &File,
+    repaired_file: &mut File,
+    retry: &RetryPolicy,
+) -> std::result::Result<SignatureRepair, RollingHashError> {
+    let (_, mut signature) = read_signature_entries(&mut BufReader::new(signature_file))?;
+
+    let source_buf =
+        file_io::read_file_to_buffer_with_retry(&mut BufReader::new(source_file), retry)?;
+    let repair = repair_signature(&mut signature, &source_buf)?;
+
+    let mut signature_writer = BufWriter::new(repaired_file);
+    write_signature(&signature, &mut signature_writer)?;
+    signature_writer.flush()?;
+    Ok(repair)
+}
+
+// Start offsets of the blocks a file of the given length is split into,
+// including the final short block
+pub fn block_boundaries(file_length: u64, block_size: u32) -> Vec<u64> {
+    (0..file_length).step_by(block_size as usize).collect()
+}
+
+// Write block boundary offsets, one per line or as a JSON array
+pub fn write_block_boundaries(boundaries: &[u64], json: bool, out: &mut impl Write) -> Result<()> {
+    if json {
+        let offsets: Vec<String> = boundaries.iter().map(u64::to_string).collect();
+        writeln!(out, "[{}]", offsets.join(","))
+    } else {
+        boundaries
+            .iter()
+            .try_for_each(|offset| writeln!(out, "{}", offset))
+    }
+}
+
 // Calculates SHA 256 Hash
 pub fn chunk_sha256_hash(chunk: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256Hash::new();
-    hasher.update(&chunk);
+    hasher.update(chunk);
     let sha256_hash: [u8; 32] = hasher.finalize();
     sha256_hash
 }
+
+// Strong hash of the blocks of a signature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum StrongHashAlgorithm {
+    #[default]
+    #[serde(rename = "sha256")]
+    Sha256,
+    // MD4, the strong hash of older librsync signatures. Weak as a cryptographic hash,
+    // so only for matching blocks with librsync tools
+    #[serde(rename = "md4")]
+    Md4,
+}
+
+impl StrongHashAlgorithm {
+    // Bytes in a whole digest
+    pub fn digest_len(self) -> u8 {
+        match self {
+            StrongHashAlgorithm::Sha256 => 32,
+            StrongHashAlgorithm::Md4 => 16,
+        }
+    }
+}
+
+// Fail for a strong hash length of zero or longer than the algorithm's digest
+pub fn check_strong_hash_len(algorithm: StrongHashAlgorithm, len: u8) -> Result<()> {
+    if len == 0 || len > algorithm.digest_len() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "strong hash length must be between 1 and {} bytes, not {}",
+                algorithm.digest_len(),
+                len
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Bytes a domain tag prefixes strong hash inputs with: the tag's length, as 8 bytes little
+// endian, then the tag, so that no tag is a prefix of another. Nothing without a tag
+fn domain_tag_prefix(domain_tag: Option<&str>) -> Vec<u8> {
+    domain_tag.map_or_else(Vec::new, |tag| {
+        [&(tag.len() as u64).to_le_bytes()[..], tag.as_bytes()].concat()
+    })
+}
+
+// SHA-256 hasher already fed the domain tag's prefix
+fn tagged_sha256(domain_tag: Option<&str>) -> Sha256Hash {
+    let mut hasher = Sha256Hash::new();
+    hasher.update(domain_tag_prefix(domain_tag));
+    hasher
+}
+
+// The first len bytes of the digest of the chunk, prefixed with the domain tag if any
+pub fn chunk_strong_hash(
+    algorithm: StrongHashAlgorithm,
+    len: u8,
+    domain_tag: Option<&str>,
+    chunk: &[u8],
+) -> Vec<u8> {
+    let len = len.min(algorithm.digest_len()) as usize;
+    match (algorithm, domain_tag) {
+        (StrongHashAlgorithm::Sha256, _) => {
+            let mut hasher = tagged_sha256(domain_tag);
+            hasher.update(chunk);
+            hasher.finalize()[..len].to_vec()
+        }
+        (StrongHashAlgorithm::Md4, None) => md4::md4(chunk)[..len].to_vec(),
+        (StrongHashAlgorithm::Md4, Some(_)) => {
+            md4::md4(&[domain_tag_prefix(domain_tag).as_slice(), chunk].concat())[..len].to_vec()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handlers::file_diff::{generate_diff, write_delta};
+    use crate::handlers::progress::PROGRESS_INTERVAL_BLOCKS;
+    use crate::handlers::testutil::{
+        apply_edits, empty_signature, gen_data, reconstruct, temp_path, ShortReads,
+    };
+    use crate::handlers::window_checksum::MAX_BLOCK_SIZE;
+    use std::io::ErrorKind;
+
+    // Signature with one bucket per block, keyed by block index
+    fn signature_with_blocks(block_count: u32) -> FileChunkSignature {
+        let mut signature = FileChunkSignature {
+            total_chunks: block_count as u64,
+            original_length: 64 * block_count as u64,
+            ..empty_signature(64)
+        };
+        for index in 0..block_count {
+            signature
+                .checksum_map
+                .entry(index)
+                .or_default()
+                .push(BlockChunkHashes {
+                    index: index as u64,
+                    hash: vec![0; 32],
+                });
+        }
+        signature
+    }
+
+    #[test]
+    pub fn test_memory_footprint_grows_with_block_count() {
+        let small = signature_with_blocks(1000);
+        let large = signature_with_blocks(4000);
+        assert_eq!(4000, large.block_count());
+
+        let per_block = size_of::<BlockChunkHashes>();
+        assert!(small.memory_footprint() > 1000 * per_block);
+        let ratio = large.memory_footprint() as f64 / small.memory_footprint() as f64;
+        assert!((3.0..=5.0).contains(&ratio), "ratio {}", ratio);
+    }
+
+    #[test]
+    pub fn test_signature_has_one_entry_per_block() {
+        // Several whole blocks of distinct data, so every block has its own weak hash
+        let buffer = gen_data(9, 64 * 6);
+        let signature = get_signature(&buffer, 64);
+        assert_eq!(6, signature.checksum_map.len());
+        assert_eq!(6, signature.block_count());
+        for (index, block) in buffer.chunks(64).enumerate() {
+            let hashes = signature
+                .block_chunk_hashes(&window_checksum::rolling_window_checksum(block))
+                .unwrap();
+            assert_eq!(index as u64, hashes[0].index);
+            assert_eq!(chunk_sha256_hash(block).to_vec(), hashes[0].hash);
+        }
+
+        // Shorter than one block
+        let signature = get_signature(&buffer[..10], 64);
+        assert_eq!(1, signature.checksum_map.len());
+    }
+
+    #[test]
+    pub fn test_streaming_signature_matches_in_memory() {
+        let mut repetitive = gen_data(4, 256).repeat(4);
+        repetitive.extend_from_slice(&gen_data(5, 100));
+        for buffer in [
+            Vec::new(),
+            gen_data(3, 1),
+            gen_data(3, 64),
+            gen_data(3, 1000),
+            repetitive,
+        ] {
+            for strong_hash_cache in [false, true] {
+                let options = SignatureOptions {
+                    strong_hash_cache,
+                    ..SignatureOptions::default()
+                };
+                let streamed = get_signature_streaming(
+                    &mut ShortReads(&buffer),
+                    64,
+                    &options,
+                    &RetryPolicy::default(),
+                )
+                .unwrap();
+                let in_memory = get_signature_with_options(&buffer, 64, &options).unwrap();
+                assert_eq!(in_memory, streamed, "length {}", buffer.len());
+                assert_eq!(buffer.len().div_ceil(64), streamed.block_count());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_empty_file_signature() {
+        let path = temp_path("rolling-hash-empty-signature");
+        std::fs::write(&path, b"").unwrap();
+        let signature_path = path.with_extension("signature");
+        write_signature_file(
+            &File::open(&path).unwrap(),
+            &mut File::create(&signature_path).unwrap(),
+            &RetryPolicy::default(),
+            &SignatureOptions::default(),
+        )
+        .unwrap();
+
+        let signature = read_signature_file(&File::open(&signature_path).unwrap()).unwrap();
+        assert_eq!(0, signature.total_chunks);
+        assert_eq!(0, signature.original_length);
+        assert_eq!(0, signature.block_count());
+        assert!(signature.checksum_map.is_empty());
+        assert_eq!(get_signature(&[], find_blocksize(0)), signature);
+        for path in [path, signature_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_signature_plan() {
+        for file_length in [0, 1000, 4097, 1_000_000, 50_000_000] {
+            let plan = SignaturePlan::new(file_length, &SignatureOptions::default());
+            assert_eq!(find_blocksize(file_length), plan.block_size);
+            assert_eq!(
+                file_length.div_ceil(plan.block_size as u64),
+                plan.block_count
+            );
+        }
+        let fixed = SignatureOptions {
+            fixed_block_size: Some(100),
+            ..SignatureOptions::default()
+        };
+        assert_eq!(
+            SignaturePlan {
+                file_length: 1_000_000,
+                block_size: 100,
+                block_count: 10_000
+            },
+            SignaturePlan::new(1_000_000, &fixed)
+        );
+
+        // Planned from the file, as write_signature_file signs it
+        let plan = plan_signature_file(
+            &File::open("data/old.txt").unwrap(),
+            &RetryPolicy::default(),
+            &SignatureOptions::default(),
+        )
+        .unwrap();
+        let signature = read_signature_file(&File::open("data/signature").unwrap()).unwrap();
+        assert_eq!(signature.block_chunk_size, plan.block_size);
+        assert_eq!(signature.total_chunks, plan.block_count);
+        assert_eq!(signature.original_length, plan.file_length);
+    }
+
+    #[test]
+    pub fn test_signature_progress_reaches_length() {
+        let buffer = gen_data(6, 100_000);
+        let mut reports: Vec<(u64, u64)> = Vec::new();
+        let signature = get_signature_with_progress(
+            &buffer,
+            64,
+            &SignatureOptions::default(),
+            &mut |processed, total| reports.push((processed, total)),
+        )
+        .unwrap();
+        assert_eq!(get_signature(&buffer, 64), signature);
+
+        // Every PROGRESS_INTERVAL_BLOCKS blocks, not every block
+        assert_eq!(
+            100_000 / (64 * PROGRESS_INTERVAL_BLOCKS as usize) + 1,
+            reports.len()
+        );
+        assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(Some(&(100_000, 100_000)), reports.last());
+    }
+
+    #[test]
+    pub fn test_signature_of_invalid_block_size_is_an_error() {
+        let options = SignatureOptions::default();
+        for block_size in [0, MAX_BLOCK_SIZE + 1] {
+            assert!(matches!(
+                get_signature_with_options(&[1u8; 100], block_size, &options),
+                Err(RollingHashError::InvalidBlockSize(size)) if size == block_size
+            ));
+            assert!(matches!(
+                get_signature_with_progress(&[1u8; 100], block_size, &options, &mut |_, _| {}),
+                Err(RollingHashError::InvalidBlockSize(size)) if size == block_size
+            ));
+            #[cfg(feature = "parallel")]
+            assert!(matches!(
+                get_signature_parallel(&[1u8; 100], block_size, &options, 4),
+                Err(RollingHashError::InvalidBlockSize(size)) if size == block_size
+            ));
+
+            let error = get_signature_streaming(
+                &mut [1u8; 100].as_slice(),
+                block_size,
+                &options,
+                &RetryPolicy::default(),
+            )
+            .unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, error.kind());
+            assert!(matches!(
+                error.into_inner().unwrap().downcast_ref::<RollingHashError>(),
+                Some(RollingHashError::InvalidBlockSize(size)) if *size == block_size
+            ));
+        }
+
+        let mut signature = get_signature(&[1u8; 100], 64);
+        signature.block_chunk_size = 0;
+        assert!(matches!(
+            repair_signature(&mut signature, &[1u8; 100]),
+            Err(RollingHashError::InvalidBlockSize(0))
+        ));
+    }
+
+    #[test]
+    pub fn test_find_blocksize() {
+        assert_eq!(64, find_blocksize(1000));
+        assert_eq!(1008, find_blocksize(1_000_000));
+        assert_eq!(1 << 20, find_blocksize(1 << 40));
+    }
+
+    #[test]
+    pub fn test_strong_hash_cache_leaves_signature_unchanged() {
+        let cached = SignatureOptions {
+            strong_hash_cache: true,
+            ..SignatureOptions::default()
+        };
+        let mixed: Vec<u8> = (0..4096u32)
+            .map(|i| if (i / 64) % 3 == 0 { 0 } else { (i * 7) as u8 })
+            .collect();
+        // [1, 0, 0, 1] and [0, 1, 1, 0] share a weak hash but not their bytes
+        let colliding = [1u8, 0, 0, 1, 0, 1, 1, 0].repeat(16);
+        for buffer in [vec![0u8; 64 * 100], mixed, colliding] {
+            for block_size in [4, 64] {
+                assert_eq!(
+                    get_signature(&buffer, block_size),
+                    get_signature_with_options(&buffer, block_size, &cached).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_fixed_block_size_ignores_file_length() {
+        let sign = |name: &str, length: usize| {
+            let input_path = temp_path(name);
+            let signature_path = input_path.with_extension("signature");
+            std::fs::write(&input_path, vec![5u8; length]).unwrap();
+            write_signature_file(
+                &File::open(&input_path).unwrap(),
+                &mut File::create(&signature_path).unwrap(),
+                &RetryPolicy::default(),
+                &SignatureOptions {
+                    fixed_block_size: Some(128),
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap();
+            let signature = read_signature_file(&File::open(&signature_path).unwrap()).unwrap();
+            std::fs::remove_file(input_path).unwrap();
+            std::fs::remove_file(signature_path).unwrap();
+            signature
+        };
+
+        let short = sign("rolling-hash-fixed-short", 1000);
+        let long = sign("rolling-hash-fixed-long", 1_000_000);
+        assert_ne!(find_blocksize(1000), find_blocksize(1_000_000));
+        assert_eq!(128, short.block_chunk_size);
+        assert_eq!(128, long.block_chunk_size);
+        assert_eq!(1000usize.div_ceil(128), short.block_count());
+        assert_eq!(1_000_000usize.div_ceil(128), long.block_count());
+
+        let short_boundaries = block_boundaries(1000, short.block_chunk_size);
+        let long_boundaries = block_boundaries(1_000_000, long.block_chunk_size);
+        assert!(long_boundaries.starts_with(&short_boundaries));
+    }
+
+    #[test]
+    pub fn test_repair_signature_rewrites_damaged_entries() {
+        let source = gen_data(5, 5000);
+        let expected = get_signature(&source, 64);
+
+        let mut intact = get_signature(&source, 64);
+        let repair = repair_signature(&mut intact, &source).unwrap();
+        assert_eq!(
+            SignatureRepair {
+                repaired: 0,
+                removed: 0
+            },
+            repair
+        );
+        assert_eq!(expected, intact);
+
+        // Flip a strong hash byte, point an entry at the wrong block and move one
+        // entry under another weak hash
+        let mut damaged = get_signature(&source, 64);
+        let mut weak_keys: Vec<u32> = damaged.checksum_map.keys().copied().collect();
+        weak_keys.sort_unstable();
+        damaged.checksum_map.get_mut(&weak_keys[0]).unwrap()[0].hash[7] ^= 0xff;
+        damaged.checksum_map.get_mut(&weak_keys[1]).unwrap()[0].index += 1000;
+        let moved = damaged.checksum_map.remove(&weak_keys[2]).unwrap();
+        damaged.checksum_map.insert(weak_keys[2] + 1, moved);
+
+        let repair = repair_signature(&mut damaged, &source).unwrap();
+        assert_eq!(
+            SignatureRepair {
+                repaired: 3,
+                removed: 3
+            },
+            repair
+        );
+        assert_eq!(expected, damaged);
+
+        // A run of duplicates pointing at the wrong block
+        let mut repeated = source[..64 * 78].to_vec();
+        repeated.extend_from_slice(&source[..64].repeat(4));
+        let dedup = SignatureOptions {
+            dedup: true,
+            ..SignatureOptions::default()
+        };
+        let expected = get_signature_with_options(&repeated, 64, &dedup).unwrap();
+        let mut damaged = get_signature_with_options(&repeated, 64, &dedup).unwrap();
+        damaged.duplicates[0].original += 1;
+        let repair = repair_signature(&mut damaged, &repeated).unwrap();
+        assert_eq!(
+            SignatureRepair {
+                repaired: 4,
+                removed: 4
+            },
+            repair
+        );
+        assert_eq!(expected, damaged);
+    }
+
+    #[test]
+    pub fn test_block_size_override_is_stored() {
+        let signature_path = temp_path("rolling-hash-block-size-signature");
+        let sign_on = |block_size: u32, threads: usize| {
+            write_signature_file(
+                &File::open("data/old.txt").unwrap(),
+                &mut File::create(&signature_path).unwrap(),
+                &RetryPolicy::default(),
+                &SignatureOptions {
+                    fixed_block_size: Some(block_size),
+                    threads,
+                    ..SignatureOptions::default()
+                },
+            )
+        };
+        let sign = |block_size: u32| sign_on(block_size, 0);
+
+        sign(100).unwrap();
+        let signature = read_signature_file(&File::open(&signature_path).unwrap()).unwrap();
+        assert_ne!(100, find_blocksize(3091));
+        assert_eq!(100, signature.block_chunk_size);
+        assert_eq!(3091usize.div_ceil(100), signature.block_count());
+
+        match sign(0).unwrap_err() {
+            RollingHashError::InvalidBlockSize(0) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+        // On threads, and when only planning, too
+        match sign_on(0, 4).unwrap_err() {
+            RollingHashError::InvalidBlockSize(0) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+        let zero = SignatureOptions {
+            fixed_block_size: Some(0),
+            ..SignatureOptions::default()
+        };
+        let plan = plan_signature_file(
+            &File::open("data/old.txt").unwrap(),
+            &RetryPolicy::default(),
+            &zero,
+        );
+        assert!(matches!(plan, Err(RollingHashError::InvalidBlockSize(0))));
+        std::fs::remove_file(signature_path).unwrap();
+    }
+
+    #[test]
+    pub fn test_block_boundaries() {
+        assert_eq!(vec![0, 1024, 2048], block_boundaries(2500, 1024));
+        assert_eq!(vec![0, 1024], block_boundaries(2048, 1024));
+        assert_eq!(vec![0], block_boundaries(10, 1024));
+        assert!(block_boundaries(0, 1024).is_empty());
+    }
+
+    #[test]
+    pub fn test_write_block_boundaries() {
+        let boundaries = block_boundaries(150, 64);
+
+        let mut lines: Vec<u8> = Vec::new();
+        write_block_boundaries(&boundaries, false, &mut lines).unwrap();
+        assert_eq!("0\n64\n128\n", String::from_utf8(lines).unwrap());
+
+        let mut json: Vec<u8> = Vec::new();
+        write_block_boundaries(&boundaries, true, &mut json).unwrap();
+        let parsed: Vec<u64> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(boundaries, parsed);
+
+        let mut empty: Vec<u8> = Vec::new();
+        write_block_boundaries(&[], true, &mut empty).unwrap();
+        assert_eq!("[]\n", String::from_utf8(empty).unwrap());
+    }
+
+    #[test]
+    pub fn test_strong_hash_lengths_round_trip() {
+        let old_file = gen_data(30, 1000);
+        let new_file = apply_edits(&old_file, 30, 3);
+        let mut sizes: Vec<usize> = Vec::new();
+        for strong_hash_len in [8, 32] {
+            let options = SignatureOptions {
+                strong_hash_len: Some(strong_hash_len),
+                ..SignatureOptions::default()
+            };
+            let signature = get_signature_with_options(&old_file, 64, &options).unwrap();
+            for entry in signature.checksum_map.values().flatten() {
+                let block = old_file.chunks(64).nth(entry.index as usize).unwrap();
+                assert_eq!(
+                    chunk_sha256_hash(block)[..strong_hash_len as usize],
+                    entry.hash
+                );
+            }
+
+            let mut written: Vec<u8> = Vec::new();
+            write_signature(&signature, &mut written).unwrap();
+            let read = read_signature(&mut written.as_slice()).unwrap();
+            assert_eq!(signature, read);
+            sizes.push(written.len());
+
+            // The shorter hashes find the same blocks
+            let delta = generate_diff(&new_file, &read, 64);
+            assert_eq!(
+                generate_diff(&new_file, &get_signature(&old_file, 64), 64),
+                delta
+            );
+        }
+        // 24 fewer bytes for each of the 16 entries
+        assert_eq!(sizes[1] - 24 * 16, sizes[0]);
+
+        // A hash of another length than the signature records is rejected
+        let mut signature = get_signature(&old_file, 64);
+        signature.checksum_map.values_mut().next().unwrap()[0]
+            .hash
+            .pop();
+        let mut written: Vec<u8> = Vec::new();
+        write_signature(&signature, &mut written).unwrap();
+        let error = read_signature(&mut written.as_slice()).unwrap_err();
+        assert!(
+            error.to_string().contains("is 31 bytes, not 32"),
+            "{}",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    pub fn test_truncated_md4_signature() {
+        let buffer = gen_data(29, 1000);
+        let options = SignatureOptions {
+            strong_hash: StrongHashAlgorithm::Md4,
+            strong_hash_len: Some(8),
+            ..SignatureOptions::default()
+        };
+        let signature = get_signature_with_options(&buffer, 64, &options).unwrap();
+        assert_eq!(StrongHashAlgorithm::Md4, signature.strong_hash);
+        assert_eq!(8, signature.strong_hash_len);
+
+        let entries: Vec<&BlockChunkHashes> = signature.checksum_map.values().flatten().collect();
+        assert_eq!(16, entries.len());
+        for entry in entries {
+            let block = buffer.chunks(64).nth(entry.index as usize).unwrap();
+            assert_eq!(md4::md4(block)[..8], entry.hash);
+            // Serialized as the index, the hash length and the hash bytes
+            let serialized = bincode::serialize(entry).unwrap();
+            assert_eq!(&md4::md4(block)[..8], &serialized[16..]);
+        }
+
+        let mut written: Vec<u8> = Vec::new();
+        write_signature(&signature, &mut written).unwrap();
+        assert_eq!(signature, read_signature(&mut written.as_slice()).unwrap());
+        let mut repaired = read_signature(&mut written.as_slice()).unwrap();
+        assert_eq!(
+            0,
+            repair_signature(&mut repaired, &buffer).unwrap().repaired
+        );
+        assert_eq!(signature, repaired);
+
+        // A length the digest doesn't have
+        let error = check_strong_hash_len(StrongHashAlgorithm::Md4, 17).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(check_strong_hash_len(StrongHashAlgorithm::Sha256, 0).is_err());
+        assert!(check_strong_hash_len(StrongHashAlgorithm::Sha256, 32).is_ok());
+    }
+
+    #[test]
+    pub fn test_domain_tag_separates_strong_hashes() {
+        let old_file = gen_data(27, 64 * 20 + 5);
+        let new_file = apply_edits(&old_file, 27, 4);
+        for strong_hash in [StrongHashAlgorithm::Sha256, StrongHashAlgorithm::Md4] {
+            let untagged = get_signature_with_options(
+                &old_file,
+                64,
+                &SignatureOptions {
+                    strong_hash,
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap();
+            let tagged = get_signature_with_options(
+                &old_file,
+                64,
+                &SignatureOptions {
+                    strong_hash,
+                    domain_tag: Some("example.com/backups".to_string()),
+                    whole_file_hash: true,
+                    ..SignatureOptions::default()
+                },
+            )
+            .unwrap();
+
+            // The same block, hashed with and without the tag
+            let block = &old_file[..64];
+            assert_ne!(untagged.strong_hash_of(block), tagged.strong_hash_of(block));
+            assert_eq!(
+                untagged.strong_hash_of(block),
+                chunk_strong_hash(strong_hash, strong_hash.digest_len(), None, block)
+            );
+            for (weak_key, hashes) in &tagged.checksum_map {
+                let untagged_hashes = untagged.block_chunk_hashes(weak_key).unwrap();
+                for (entry, untagged_entry) in hashes.iter().zip(untagged_hashes) {
+                    assert_ne!(untagged_entry.hash, entry.hash, "block {}", entry.index);
+                }
+            }
+
+            // The tag survives the file format and is applied when diffing and checking
+            let mut written: Vec<u8> = Vec::new();
+            write_signature(&tagged, &mut written).unwrap();
+            let tagged = read_signature(&mut written.as_slice()).unwrap();
+            assert_eq!(Some("example.com/backups"), tagged.domain_tag.as_deref());
+            assert!(tagged.is_whole_file(&old_file));
+            let delta = generate_diff(&new_file, &tagged, 64);
+            assert!(delta.iter().any(VerifyMatch::is_match));
+            tagged.check_old_file(&old_file).unwrap();
+            tagged.check_matched_blocks(&old_file, &delta).unwrap();
+            assert_eq!(new_file, reconstruct(&old_file, &delta, 64));
+
+            // Tagged entries don't match blocks hashed without the tag
+            let mut stripped = tagged;
+            stripped.domain_tag = None;
+            assert!(!stripped.is_whole_file(&old_file));
+            assert!(!generate_diff(&new_file, &stripped, 64)
+                .iter()
+                .any(VerifyMatch::is_match));
+            assert!(matches!(
+                stripped.check_old_file(&old_file),
+                Err(RollingHashError::OldFileMismatch(_))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_signature_json_schema_keys() {
+        let signature = get_signature(&[7u8; 64], 64);
+        let json = serde_json::to_value(&signature).unwrap();
+
+        let mut keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(
+            vec![
+                "block_size",
+                "blocks",
+                "domain_tag",
+                "duplicates",
+                "original_length",
+                "rolling_checksum",
+                "strong_hash_algorithm",
+                "strong_hash_len",
+                "total_chunks",
+                "whole_file_checksum",
+                "whole_file_hash"
+            ],
+            keys
+        );
+        assert_eq!("prime", json["rolling_checksum"]);
+        assert_eq!("sha256", json["strong_hash_algorithm"]);
+
+        let blocks = json["blocks"].as_object().unwrap();
+        let (_, hashes) = blocks.iter().next().unwrap();
+        let mut block_keys: Vec<&String> = hashes[0].as_object().unwrap().keys().collect();
+        block_keys.sort();
+        assert_eq!(vec!["index", "strong_hash"], block_keys);
+    }
+
+    #[test]
+    pub fn test_signature_header() {
+        let signature = get_signature(&gen_data(6, 300), 64);
+        let mut written: Vec<u8> = Vec::new();
+        write_signature(&signature, &mut written).unwrap();
+        assert_eq!(b"RHSG\x01", &written[..5]);
+        assert_eq!(signature, read_signature(&mut written.as_slice()).unwrap());
+
+        // A delta where a signature is expected
+        let delta = bincode::serialize(&vec![VerifyMatch::Match(0)]).unwrap();
+        let error = read_signature(&mut delta.as_slice()).unwrap_err();
+        assert_eq!(
+            "invalid signature: not a signature file (no RHSG header)",
+            error.to_string()
+        );
+        let mut delta: Vec<u8> = Vec::new();
+        write_delta(&[VerifyMatch::Match(0)], &mut delta).unwrap();
+        let error = read_signature(&mut delta.as_slice()).unwrap_err();
+        assert_eq!(
+            "invalid signature: not a signature file but a delta file",
+            error.to_string()
+        );
+        assert!(matches!(
+            error,
+            RollingHashError::NotASignature(Some("a delta file"))
+        ));
+        let error = read_signature(&mut &b"RHBUNDLE\x01"[..]).unwrap_err();
+        assert_eq!(
+            "invalid signature: not a signature file but a bundle file",
+            error.to_string()
+        );
+        let error = read_signature(&mut &b"RH"[..]).unwrap_err();
+        assert!(
+            matches!(error, RollingHashError::InvalidSignature(_)),
+            "{:?}",
+            error
+        );
+
+        // Written by a newer version of the tool
+        written[4] = SIGNATURE_VERSION + 1;
+        let error = read_signature(&mut written.as_slice()).unwrap_err();
+        assert!(
+            error.to_string().contains("version 2"),
+            "{}",
+            error.to_string()
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn test_parallel_signature_matches_serial() {
+        let mut buffer = gen_data(12, 64 * 100 + 17);
+        buffer.extend_from_slice(&gen_data(13, 64).repeat(5));
+        for rolling_checksum in [
+            RollingChecksumAlgorithm::Prime,
+            RollingChecksumAlgorithm::WidePrime,
+            RollingChecksumAlgorithm::Adler32,
+        ] {
+            let options = SignatureOptions {
+                rolling_checksum,
+                whole_file_hash: true,
+                ..SignatureOptions::default()
+            };
+            let serial = get_signature_with_options(&buffer, 64, &options).unwrap();
+            assert!(serial.is_whole_file(&buffer));
+            // Each thread's own strong hash cache leaves the signature unchanged too
+            let cached = SignatureOptions {
+                strong_hash_cache: true,
+                ..options.clone()
+            };
+            for (options, threads) in [&options, &cached]
+                .into_iter()
+                .flat_map(|options| [1, 3, 8, 1000].map(|threads| (options, threads)))
+            {
+                let parallel = get_signature_parallel(&buffer, 64, options, threads).unwrap();
+                assert_eq!(
+                    serial.checksum_map, parallel.checksum_map,
+                    "{} threads",
+                    threads
+                );
+                assert_eq!(serial, parallel);
+            }
+        }
+        assert_eq!(
+            get_signature(&[], 64),
+            get_signature_parallel(&[], 64, &SignatureOptions::default(), 4).unwrap()
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn test_parallel_streaming_signature_reads_in_batches() {
+        // Blocks of PARALLEL_RUN_BYTES make batches of one block per thread, so the input
+        // spans several batches, the last one short, and a block repeats one of an earlier
+        // batch
+        let block_size = PARALLEL_RUN_BYTES as u32;
+        let mut buffer = gen_data(28, 4 * PARALLEL_RUN_BYTES);
+        buffer.extend_from_within(..PARALLEL_RUN_BYTES);
+        buffer.extend_from_slice(&gen_data(29, 17));
+        let options = SignatureOptions {
+            dedup: true,
+            whole_file_hash: true,
+            ..SignatureOptions::default()
+        };
+        let serial = get_signature_with_options(&buffer, block_size, &options).unwrap();
+        assert_eq!(1, serial.duplicates.len());
+        for threads in [1, 2, 4] {
+            let streamed = get_signature_parallel_streaming(
+                &mut buffer.as_slice(),
+                block_size,
+                &options,
+                threads,
+                &RetryPolicy::default(),
+            )
+            .unwrap();
+            assert_eq!(serial, streamed, "{} threads", threads);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    pub fn test_signature_of_file_threads() {
+        let signature_with = |threads: usize| {
+            let input_file = File::open("data/old.txt").unwrap();
+            let options = SignatureOptions {
+                threads,
+                dedup: true,
+                ..SignatureOptions::default()
+            };
+            signature_of_file(&input_file, &RetryPolicy::default(), &options).unwrap()
+        };
+        // One thread takes the serial, block at a time path
+        let serial = signature_with(1);
+        for threads in [0, 2, 4] {
+            let threaded = signature_with(threads);
+            assert_eq!(
+                serial.checksum_map, threaded.checksum_map,
+                "{} threads",
+                threads
+            );
+            assert_eq!(serial, threaded);
+        }
+    }
+}
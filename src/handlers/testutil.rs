@@ -0,0 +1,95 @@
+// Test inputs and fixtures. The pseudo random data and edits derived from a seed, so that
+// a failing test can be replayed from the seed alone, are shared with self_test
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Result};
+use std::path::PathBuf;
+
+use super::file_diff::{read_delta, VerifyMatch};
+use super::file_io::RetryPolicy;
+use super::patch::apply_patch;
+pub use super::self_test::{apply_edits, gen_data, SeededRng};
+use super::signature::{
+    get_signature_streaming, read_signature, write_signature, FileChunkSignature, SignatureOptions,
+};
+
+// Path named name in the system's temporary directory, suffixed with the process id so
+// that concurrent test runs don't share files
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}", name, std::process::id()))
+}
+
+// Empty directory at temp_path(name), cleared of anything an earlier run left in it
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = temp_path(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Signature of blocks of block_size bytes holding no blocks, for tests to fill in the
+// entries and totals they need
+pub fn empty_signature(block_size: u32) -> FileChunkSignature {
+    FileChunkSignature {
+        block_chunk_size: block_size,
+        checksum_map: HashMap::new(),
+        rolling_checksum: Default::default(),
+        total_chunks: 0,
+        original_length: 0,
+        duplicates: Vec::new(),
+        strong_hash: Default::default(),
+        strong_hash_len: 32,
+        whole_file_checksum: None,
+        whole_file_hash: None,
+        domain_tag: None,
+    }
+}
+
+// Rebuild the new file from the old file and a delta
+pub fn reconstruct(old_file: &[u8], delta: &[VerifyMatch], block_size: usize) -> Vec<u8> {
+    let blocks: Vec<&[u8]> = old_file.chunks(block_size).collect();
+    delta
+        .iter()
+        .flat_map(|entry| match entry {
+            VerifyMatch::Match(index) => blocks[*index as usize].to_vec(),
+            VerifyMatch::NoMatch(bytes) => bytes.clone(),
+        })
+        .collect()
+}
+
+// Signature of data as a signature file would hold it, without a file: data is signed
+// through a Cursor as a file is streamed, and the signature read back from the bytes
+// write_signature writes
+pub fn signature_from_slice(data: &[u8], block_size: u32) -> FileChunkSignature {
+    let signature = get_signature_streaming(
+        &mut Cursor::new(data),
+        block_size,
+        &SignatureOptions::default(),
+        &RetryPolicy::default(),
+    )
+    .unwrap();
+    let mut signature_file = Cursor::new(Vec::new());
+    write_signature(&signature, &mut signature_file).unwrap();
+    signature_file.set_position(0);
+    read_signature(&mut signature_file).unwrap()
+}
+
+// Rebuild the new file from the old file and the bytes of a delta file, as apply-patch
+// does with files
+pub fn apply_from_slices(old_file: &[u8], delta_file: &[u8], block_size: u32) -> Vec<u8> {
+    let delta = read_delta(&mut Cursor::new(delta_file)).unwrap();
+    let mut new_file = Cursor::new(Vec::new());
+    apply_patch(old_file, &delta, block_size, &mut new_file).unwrap();
+    new_file.into_inner()
+}
+
+// Returns at most 7 bytes per read, so blocks are assembled from several reads
+pub struct ShortReads<'a>(pub &'a [u8]);
+
+impl Read for ShortReads<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.0.len()).min(7);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
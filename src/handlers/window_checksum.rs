@@ -1,13 +1,79 @@
-pub struct RollingWindow {
+use std::io::{Result, Write};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::error::RollingHashError;
+
+// Weak checksum rolled over the windows of the new file. Recorded in the signature,
+// since diffing has to use the checksum signing used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum RollingChecksumAlgorithm {
+    // Position weighted byte sums modulo a prime (RollingWindow)
+    #[default]
+    #[serde(rename = "prime")]
+    Prime,
+    // The same sums modulo 65521, the largest prime whose square fits the u32 weak key.
+    // Fewer weak hash collisions, so fewer strong hashes on large files (WidePrimeWindow)
+    #[serde(rename = "prime65521")]
+    #[value(name = "prime65521")]
+    WidePrime,
+    // Adler-32, the checksum rsync's is derived from (Adler32Window)
+    #[serde(rename = "adler32")]
+    Adler32,
+}
+
+impl RollingChecksumAlgorithm {
+    // Prime the checksum's sums are reduced modulo
+    pub fn modulus(self) -> u32 {
+        match self {
+            RollingChecksumAlgorithm::Prime => RollingWindow::LARGE_PRIME_MOD,
+            RollingChecksumAlgorithm::WidePrime => WidePrimeWindow::LARGE_PRIME_MOD,
+            RollingChecksumAlgorithm::Adler32 => Adler32Window::ADLER_MOD,
+        }
+    }
+}
+
+// Weak checksum of a window of bytes which can grow at its end and roll forward
+// one byte at a time
+pub trait RollingChecksum: Default {
+    // Append bytes at the end of the window
+    fn add_bytes_at_end(&mut self, byte_buf: &[u8]);
+
+    // Remove prev from the beginning of the window and append next, if any, at the end.
+    // Without next the window shrinks by one byte
+    fn roll_window(&mut self, prev: u8, next: Option<u8>);
+
+    // Key of the window in the signature's checksum map
+    fn weak_key(&self) -> u32;
+
+    // Write the internal state and weak key as one line, for debugging the rolling hash
+    fn write_state(&self, out: &mut (impl Write + ?Sized)) -> Result<()>;
+}
+
+// Weak checksum of a window of bytes x[0], ..., x[n - 1], with p = MODULUS, a prime:
+//   block_sum      = (x[0] + x[1] + ... + x[n - 1]) mod p
+//   all_blocks_sum = (n * x[0] + (n - 1) * x[1] + ... + 1 * x[n - 1]) mod p
+//   weak_key       = block_sum + all_blocks_sum * p
+// The digest only depends on the bytes of the window, however it was built: appending,
+// rolling and shrinking give the same key as summing the same bytes from scratch, and
+// every step is reduced modulo p, so no window size can overflow the sums.
+// p must be below 2^16 for the weak key to fit in u32
+pub struct PrimeWindow<const MODULUS: u32> {
     pub block_sum: u32,
     pub all_blocks_sum: u32,
     pub window_size: u32,
 }
 
-impl RollingWindow {
-    // Select a large prime number to be used as modulus
-    // Use one that doesn't overflow u32 from https://primes.utm.edu/curios/index.php?start=5&stop=5
-    const LARGE_PRIME_MOD: u32 = 21191;
+// Select a large prime number to be used as modulus
+// Use one that doesn't overflow u32 from https://primes.utm.edu/curios/index.php?start=5&stop=5
+pub type RollingWindow = PrimeWindow<21191>;
+
+// The largest prime below 2^16, for RollingChecksumAlgorithm::WidePrime
+pub type WidePrimeWindow = PrimeWindow<65521>;
+
+impl<const MODULUS: u32> PrimeWindow<MODULUS> {
+    const LARGE_PRIME_MOD: u32 = MODULUS;
 
     pub fn generate() -> Self {
         Self {
@@ -18,62 +84,201 @@ impl RollingWindow {
     }
 }
 
-impl Default for RollingWindow {
+impl<const MODULUS: u32> Default for PrimeWindow<MODULUS> {
     fn default() -> Self {
         Self::generate()
     }
 }
 
-impl RollingWindow {
-    //Get rolling hash after shift operation
-    pub fn sha256_digest(&self) -> u32 {
-        // If we used different modulo, we would have here r = r1 + (r2 * MODULO).
-        // Because MODULO is 1 << 16 we can left shift bits also here.
-        //(self.block_sum % IndexHash::MODULO + (self.r2 * IndexHash::MODULO)) % IndexHash::MODULO
-        self.block_sum + (self.all_blocks_sum * RollingWindow::LARGE_PRIME_MOD)
+impl<const MODULUS: u32> RollingChecksum for PrimeWindow<MODULUS> {
+    // Weak hash of the window, used as the key of the signature's checksum map by both
+    // signing and diffing. Each sum is reduced modulo LARGE_PRIME_MOD, so the key is below
+    // LARGE_PRIME_MOD squared, which fits in u32, and distinct pairs of sums give distinct keys
+    fn weak_key(&self) -> u32 {
+        let block_sum = self.block_sum % Self::LARGE_PRIME_MOD;
+        let all_blocks_sum = self.all_blocks_sum % Self::LARGE_PRIME_MOD;
+        block_sum + all_blocks_sum * Self::LARGE_PRIME_MOD
     }
 
-    // Append bytes slices to the current checksum state while doing mod of large prime number at every step
-    pub fn add_bytes_at_end(&mut self, byte_buf: &[u8]) {
-        let mut block_size: u32 = 0;
-        let mut all_blocks_size: u32 = 0;
-        let byte_lengh = byte_buf.len() as u32;
+    // Append bytes slices to the current checksum state while doing mod of large prime number at every step.
+    // Each appended byte adds one weight to every byte before it, the block sum, and enters
+    // with weight 1
+    fn add_bytes_at_end(&mut self, byte_buf: &[u8]) {
+        let modulus = Self::LARGE_PRIME_MOD as u64;
+        let mut block_sum = self.block_sum as u64 % modulus;
+        let mut all_blocks_sum = self.all_blocks_sum as u64 % modulus;
+        for byte in byte_buf {
+            block_sum = (block_sum + *byte as u64) % modulus;
+            all_blocks_sum = (all_blocks_sum + block_sum) % modulus;
+        }
+        self.block_sum = block_sum as u32;
+        self.all_blocks_sum = all_blocks_sum as u32;
+        self.window_size = self.window_size.wrapping_add(byte_buf.len() as u32);
+    }
+
+    // Roll window : Remove one block of byte from the beginning and add one at the end.
+    // prev leaves all_blocks_sum with its weight, the window size, and every other byte
+    // moves one place closer to the start, gaining one weight each: the new block_sum.
+    // Without next the window shrinks instead, and the other bytes keep their weights.
+    // The sums are kept below LARGE_PRIME_MOD, so subtracting adds the modulus first
+    fn roll_window(&mut self, prev: u8, next: Option<u8>) {
+        let modulus = Self::LARGE_PRIME_MOD as u64;
+        let prev = prev as u64;
+        let block_sum = (self.block_sum as u64 % modulus + modulus - prev % modulus
+            + next.map_or(0, u64::from))
+            % modulus;
+        let removed = self.window_size as u64 % modulus * prev % modulus;
+        let mut all_blocks_sum =
+            (self.all_blocks_sum as u64 % modulus + modulus - removed) % modulus;
+        match next {
+            Some(_) => all_blocks_sum = (all_blocks_sum + block_sum) % modulus,
+            None => self.window_size = self.window_size.wrapping_sub(1),
+        }
+        self.block_sum = block_sum as u32;
+        self.all_blocks_sum = all_blocks_sum as u32;
+    }
+
+    fn write_state(&self, out: &mut (impl Write + ?Sized)) -> Result<()> {
+        writeln!(
+            out,
+            "block_sum={} all_blocks_sum={} window_size={} weak_key={}",
+            self.block_sum,
+            self.all_blocks_sum,
+            self.window_size,
+            self.weak_key()
+        )
+    }
+}
+
+// Adler-32 of the window: a is one plus the byte sum and b the sum of a over every prefix
+// of the window, both modulo the largest prime below 2^16
+#[derive(Debug)]
+pub struct Adler32Window {
+    pub a: u32,
+    pub b: u32,
+    pub window_size: u32,
+}
 
-        byte_buf.iter().enumerate().for_each(|(index, byte)| {
-            block_size += *byte as u32;
-            all_blocks_size += (*byte as u32) * (byte_lengh - (index as u32));
-        });
+impl Adler32Window {
+    const ADLER_MOD: u32 = 65521;
+}
 
-        self.block_sum = (self.block_sum.wrapping_add(block_size)) % RollingWindow::LARGE_PRIME_MOD;
-        self.all_blocks_sum =
-            (self.all_blocks_sum.wrapping_add(all_blocks_size)) % RollingWindow::LARGE_PRIME_MOD;
-        self.window_size =
-            (self.window_size.wrapping_add(byte_lengh)) % RollingWindow::LARGE_PRIME_MOD;
+impl Default for Adler32Window {
+    fn default() -> Self {
+        Self {
+            a: 1,
+            b: 0,
+            window_size: 0,
+        }
     }
+}
 
-    // Roll window : Remove one block of byte from the beginning and add one at the end
-    pub fn roll_window(&mut self, prev: u8, next: Option<u8>) {
-        self.block_sum = (self
-            .block_sum
-            .wrapping_sub(prev as u32)
-            .wrapping_add(next.map_or(0, u32::from)))
-            % RollingWindow::LARGE_PRIME_MOD;
-        self.all_blocks_sum = (self
-            .all_blocks_sum
-            .wrapping_sub(self.window_size * (prev as u32))
-            .wrapping_add(self.block_sum))
-            % RollingWindow::LARGE_PRIME_MOD;
-        if next.is_none() {
-            self.window_size = self.window_size.wrapping_sub(1);
+impl RollingChecksum for Adler32Window {
+    fn add_bytes_at_end(&mut self, byte_buf: &[u8]) {
+        for byte in byte_buf {
+            self.a = (self.a + *byte as u32) % Adler32Window::ADLER_MOD;
+            self.b = (self.b + self.a) % Adler32Window::ADLER_MOD;
         }
+        self.window_size = self.window_size.wrapping_add(byte_buf.len() as u32);
+    }
+
+    // Removing prev takes it from a, and window_size copies of it plus the initial one
+    // from b. Appending next then adds the new a to b
+    fn roll_window(&mut self, prev: u8, next: Option<u8>) {
+        let modulus = Adler32Window::ADLER_MOD as u64;
+        let prev = prev as u64;
+        let a = (self.a as u64 + modulus - prev + next.map_or(0, u64::from)) % modulus;
+        let removed = (self.window_size as u64 * prev + 1) % modulus;
+        let mut b = (self.b as u64 + modulus - removed) % modulus;
+        match next {
+            Some(_) => b = (b + a) % modulus,
+            None => self.window_size = self.window_size.wrapping_sub(1),
+        }
+        self.a = a as u32;
+        self.b = b as u32;
+    }
+
+    fn weak_key(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+
+    fn write_state(&self, out: &mut (impl Write + ?Sized)) -> Result<()> {
+        writeln!(
+            out,
+            "a={} b={} window_size={} weak_key={}",
+            self.a,
+            self.b,
+            self.window_size,
+            self.weak_key()
+        )
     }
 }
 
+// Largest block size accepted. Blocks are read, hashed and copied whole
+pub const MAX_BLOCK_SIZE: u32 = 1 << 30;
+
+// Fail for an empty block size, or one too large to hold a block in memory.
+// Block sizes larger than the file are fine: small files get the default block size,
+// and a fixed block size is meant for files of any length
+pub fn validate_block_size(block_size: u32) -> std::result::Result<(), RollingHashError> {
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        return Err(RollingHashError::InvalidBlockSize(block_size));
+    }
+    Ok(())
+}
+
 // Calculate hash of rolling window based on index of bytes
 pub fn rolling_window_checksum(chunk: &[u8]) -> u32 {
-    let mut checksum = RollingWindow::generate();
+    window_checksum::<RollingWindow>(chunk)
+}
+
+// Weak key of a whole chunk with the given checksum
+pub fn rolling_checksum(algorithm: RollingChecksumAlgorithm, chunk: &[u8]) -> u32 {
+    match algorithm {
+        RollingChecksumAlgorithm::Prime => window_checksum::<RollingWindow>(chunk),
+        RollingChecksumAlgorithm::WidePrime => window_checksum::<WidePrimeWindow>(chunk),
+        RollingChecksumAlgorithm::Adler32 => window_checksum::<Adler32Window>(chunk),
+    }
+}
+
+fn window_checksum<C: RollingChecksum>(chunk: &[u8]) -> u32 {
+    let mut checksum = C::default();
     checksum.add_bytes_at_end(chunk);
-    checksum.sha256_digest()
+    checksum.weak_key()
+}
+
+// Weak key of bytes fed in pieces with the checksum chosen at run time, equal to
+// rolling_checksum of all the pieces joined
+pub enum ChecksumState {
+    Prime(RollingWindow),
+    WidePrime(WidePrimeWindow),
+    Adler32(Adler32Window),
+}
+
+impl ChecksumState {
+    pub fn new(algorithm: RollingChecksumAlgorithm) -> Self {
+        match algorithm {
+            RollingChecksumAlgorithm::Prime => ChecksumState::Prime(Default::default()),
+            RollingChecksumAlgorithm::WidePrime => ChecksumState::WidePrime(Default::default()),
+            RollingChecksumAlgorithm::Adler32 => ChecksumState::Adler32(Default::default()),
+        }
+    }
+
+    pub fn add_bytes_at_end(&mut self, byte_buf: &[u8]) {
+        match self {
+            ChecksumState::Prime(window) => window.add_bytes_at_end(byte_buf),
+            ChecksumState::WidePrime(window) => window.add_bytes_at_end(byte_buf),
+            ChecksumState::Adler32(window) => window.add_bytes_at_end(byte_buf),
+        }
+    }
+
+    pub fn weak_key(&self) -> u32 {
+        match self {
+            ChecksumState::Prime(window) => window.weak_key(),
+            ChecksumState::WidePrime(window) => window.weak_key(),
+            ChecksumState::Adler32(window) => window.weak_key(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -86,29 +291,164 @@ mod test {
         assert_eq!(rolling_win.window_size, 0);
         assert_eq!(rolling_win.block_sum, 0);
         assert_eq!(rolling_win.all_blocks_sum, 0);
-        assert_eq!(rolling_win.sha256_digest(), 0);
+        assert_eq!(rolling_win.weak_key(), 0);
     }
 
     #[test]
     pub fn test_rolling_window_checksum() {
         let mut rolling_win = RollingWindow::generate();
-        rolling_win.add_bytes_at_end(vec!['a' as u8, 'b' as u8, 'c' as u8, 'd' as u8].as_slice());
+        rolling_win.add_bytes_at_end(vec![b'a', b'b', b'c', b'd'].as_slice());
         assert_eq!(rolling_win.window_size, 4);
-        assert_eq!(20767574, rolling_win.sha256_digest());
+        assert_eq!(20767574, rolling_win.weak_key());
 
-        rolling_win.add_bytes_at_end(vec!['e' as u8, 'f' as u8, 'g' as u8, 'h' as u8].as_slice());
+        rolling_win.add_bytes_at_end(vec![b'e', b'f', b'g', b'h'].as_slice());
         assert_eq!(rolling_win.window_size, 8);
-        assert_eq!(42382804, rolling_win.sha256_digest());
+        assert_eq!(75779820, rolling_win.weak_key());
+        assert_eq!(rolling_window_checksum(b"abcdefgh"), rolling_win.weak_key());
 
-        rolling_win.roll_window(1, Some('i' as u8));
+        rolling_win.roll_window(b'a', Some(b'i'));
         assert_eq!(rolling_win.window_size, 8);
-        assert_eq!(61454808, rolling_win.sha256_digest());
+        assert_eq!(rolling_window_checksum(b"bcdefghi"), rolling_win.weak_key());
 
-        rolling_win.roll_window(2, Some('j' as u8));
-        rolling_win.roll_window(3, Some('k' as u8));
-        rolling_win.roll_window(4, None);
+        rolling_win.roll_window(b'b', Some(b'j'));
+        rolling_win.roll_window(b'c', Some(b'k'));
+        rolling_win.roll_window(b'd', None);
         assert_eq!(rolling_win.window_size, 7);
-        assert_eq!(128588100, rolling_win.sha256_digest());
+        assert_eq!(rolling_window_checksum(b"efghijk"), rolling_win.weak_key());
+    }
+
+    #[test]
+    pub fn test_weak_key_distinguishes_swapped_sums() {
+        let window = |block_sum, all_blocks_sum| RollingWindow {
+            block_sum,
+            all_blocks_sum,
+            window_size: 4,
+        };
+        assert_ne!(window(5, 9).weak_key(), window(9, 5).weak_key());
+        assert_ne!(window(0, 1).weak_key(), window(1, 0).weak_key());
+
+        // Largest sums after the modulus still fit in u32
+        let max_sum = RollingWindow::LARGE_PRIME_MOD - 1;
+        assert_eq!(
+            max_sum + max_sum * RollingWindow::LARGE_PRIME_MOD,
+            window(max_sum, max_sum).weak_key()
+        );
+        assert_eq!(
+            window(3, 4).weak_key(),
+            window(3, 4 + RollingWindow::LARGE_PRIME_MOD).weak_key()
+        );
+    }
+
+    #[test]
+    pub fn test_validate_block_size() {
+        for block_size in [0, MAX_BLOCK_SIZE + 1, u32::MAX] {
+            let error = validate_block_size(block_size).unwrap_err();
+            assert!(
+                matches!(error, RollingHashError::InvalidBlockSize(size) if size == block_size),
+                "{:?}",
+                error
+            );
+            assert_eq!(
+                std::io::ErrorKind::InvalidInput,
+                std::io::Error::from(error).kind()
+            );
+        }
+        for block_size in [1, 64, MAX_BLOCK_SIZE] {
+            assert!(validate_block_size(block_size).is_ok());
+        }
+    }
+
+    #[test]
+    pub fn test_large_block_of_high_bytes() {
+        assert!(validate_block_size(1 << 16).is_ok());
+
+        // Sums of every byte 255, from the closed forms, with the modulus taken at the end
+        let n: u64 = 1 << 16;
+        let prime = RollingWindow::LARGE_PRIME_MOD as u64;
+        let block_sum = 255 * n % prime;
+        let all_blocks_sum = 255 * (n * (n + 1) / 2) % prime;
+        let reference = (block_sum + all_blocks_sum * prime) as u32;
+
+        let window = vec![u8::MAX; n as usize];
+        assert_eq!(reference, rolling_window_checksum(&window));
+        let mut rolling_window = RollingWindow::generate();
+        rolling_window.add_bytes_at_end(&window);
+        assert_eq!(n as u32, rolling_window.window_size);
+        assert_eq!(block_sum as u32, rolling_window.block_sum);
+
+        // Built in two appends
+        let mut appended = RollingWindow::generate();
+        appended.add_bytes_at_end(&window[..1000]);
+        appended.add_bytes_at_end(&window[1000..]);
+        assert_eq!(reference, appended.weak_key());
+    }
+
+    #[test]
+    pub fn test_adler32_matches_reference_and_rolls() {
+        // Adler-32 of "Wikipedia", as listed in the Adler-32 article
+        assert_eq!(
+            0x11e6_0398,
+            rolling_checksum(RollingChecksumAlgorithm::Adler32, b"Wikipedia")
+        );
+        assert_eq!(1, rolling_checksum(RollingChecksumAlgorithm::Adler32, b""));
+
+        // Rolling over high bytes and shrinking at the tail agrees with computing each window
+        let data: Vec<u8> = (0..200u32).map(|i| (i * 97 % 256) as u8).collect();
+        let mut window = Adler32Window::default();
+        window.add_bytes_at_end(&data[..32]);
+        for start in 1..data.len() {
+            window.roll_window(data[start - 1], data.get(start + 31).copied());
+            let end = data.len().min(start + 32);
+            assert_eq!(
+                rolling_checksum(RollingChecksumAlgorithm::Adler32, &data[start..end]),
+                window.weak_key(),
+                "window at {}",
+                start
+            );
+        }
+        assert_eq!(1, window.window_size);
+    }
+
+    #[test]
+    pub fn test_rolling_window_matches_recomputed_windows() {
+        // High bytes, so that removing them would underflow the sums without the modulus
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 131 % 256) as u8).collect();
+        for window_size in 1..=70 {
+            let mut window = RollingWindow::generate();
+            window.add_bytes_at_end(&data[..window_size]);
+            for start in 1..data.len() {
+                window.roll_window(data[start - 1], data.get(start + window_size - 1).copied());
+                let end = data.len().min(start + window_size);
+                assert_eq!(
+                    rolling_window_checksum(&data[start..end]),
+                    window.weak_key(),
+                    "window of {} bytes at {}",
+                    window_size,
+                    start
+                );
+                assert_eq!((end - start) as u32, window.window_size);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_wide_prime_window_rolls() {
+        // Large enough for the sums to wrap the modulus many times
+        let data: Vec<u8> = (0..20_000u32).map(|i| (255 - i % 7) as u8).collect();
+        let mut window = WidePrimeWindow::generate();
+        window.add_bytes_at_end(&data[..10_000]);
+        for start in 1..=10_000 {
+            window.roll_window(data[start - 1], Some(data[start + 9_999]));
+        }
+        assert_eq!(
+            window_checksum::<WidePrimeWindow>(&data[10_000..]),
+            window.weak_key()
+        );
+        assert!(window.block_sum < 65521 && window.all_blocks_sum < 65521);
+        assert_ne!(
+            rolling_checksum(RollingChecksumAlgorithm::Prime, &data),
+            rolling_checksum(RollingChecksumAlgorithm::WidePrime, &data)
+        );
     }
 
     #[test]
@@ -120,7 +460,7 @@ mod test {
             rolling_window_bytes.push(i as u8);
         }
         rolling_window.add_bytes_at_end(rolling_window_bytes.as_slice());
-        assert_eq!(11785356, rolling_window.sha256_digest());
+        assert_eq!(11785356, rolling_window.weak_key());
     }
 
     #[test]
@@ -1,3 +1,65 @@
-pub mod handlers;
+//! Rolling hash file diffing: sign an old file, diff a new file against the signature and
+//! rebuild the new file from the old one and the delta.
+//!
+//! Everything also works on buffers in memory:
+//!
+//! ```
+//! use rolling_hash_rs::diff::VerifyMatch;
+//! use rolling_hash_rs::patch::apply_patch;
+//! use rolling_hash_rs::{diff_of, signature_of};
+//!
+//! let old_file: Vec<u8> = (0..128u8).collect();
+//! let mut new_file = old_file.clone();
+//! new_file.splice(20..20, b"inserted".iter().copied());
+//!
+//! let signature = signature_of(&old_file, 16);
+//! let delta = diff_of(&signature, &new_file);
+//! assert!(delta.iter().any(VerifyMatch::is_match));
+//!
+//! let mut rebuilt: Vec<u8> = Vec::new();
+//! apply_patch(&old_file, &delta, signature.block_chunk_size, &mut rebuilt).unwrap();
+//! assert_eq!(new_file, rebuilt);
+//! ```
 
-pub use handlers::{file_diff, file_io, signature, window_checksum};
+mod handlers;
+
+pub use handlers::{
+    bundle, chain, compare, dedup, error, file_diff, inspect, librsync, manifest, patch, self_test,
+    signature, window_checksum,
+};
+
+// Delta generation under a shorter path. Kept stable as file_diff grows
+pub mod diff {
+    pub use crate::handlers::file_diff::{
+        combine_deltas, diff_against_signature, diff_stats, fold_short_match_runs, generate_diff,
+        generate_diff_borrowed, generate_diff_positioned, generate_diff_streaming,
+        generate_diff_with_options, optimize_delta, read_delta, read_delta_with_numbering,
+        validate_delta, visit_delta, write_delta, write_delta_entries, write_delta_with_base,
+        BlockIndexBase, CollisionPolicy, DeltaEntry, DeltaNumbering, DeltaVisitor, DiffOptions,
+        DiffOutcome, DiffStats, VerifyMatch,
+    };
+}
+
+pub use handlers::file_diff::{generate_diff, VerifyMatch};
+pub use handlers::signature::{chunk_sha256_hash, get_signature, FileChunkSignature};
+pub use handlers::window_checksum::{
+    rolling_window_checksum, RollingChecksum, RollingChecksumAlgorithm, RollingWindow,
+    WidePrimeWindow,
+};
+
+// Signature of a buffer, without any file I/O. Signing a file goes through the same builder
+pub fn signature_of(data: &[u8], block_size: u32) -> FileChunkSignature {
+    get_signature(data, block_size)
+}
+
+// Delta turning the signed data into new, without any file I/O, at the signature's block size.
+// Panics on a signature whose block size validate_block_size rejects
+pub fn diff_of(signature: &FileChunkSignature, new: &[u8]) -> Vec<VerifyMatch> {
+    diff::diff_against_signature(new, signature, &diff::DiffOptions::default())
+        .unwrap_or_else(|error| panic!("{}", error))
+}
+
+// Opening, atomically writing and retrying reads of files, shared with the command line
+// tool. Not part of the library API
+#[doc(hidden)]
+pub use handlers::file_io;
@@ -1,34 +1,584 @@
 use clap::Parser;
 use cli_parser::*;
-use handlers::file_diff::write_diff_file;
-use handlers::file_io::{read_handler, write_handler};
-use handlers::signature::write_signature_file;
+use rolling_hash_rs::bundle::{bundle_files, unbundle_file};
+use rolling_hash_rs::chain::write_chain;
+use rolling_hash_rs::compare::{
+    compare_files, first_divergence_in_files, write_change_map_pbm, SimilarityThresholds,
+    COMPARE_ERROR_EXIT_CODE,
+};
+use rolling_hash_rs::dedup::self_dedup_file;
+use rolling_hash_rs::error::RollingHashError;
+#[cfg(not(feature = "parallel"))]
+use rolling_hash_rs::file_diff::diff_batch;
+#[cfg(feature = "parallel")]
+use rolling_hash_rs::file_diff::diff_batch_parallel;
+use rolling_hash_rs::file_diff::{
+    estimate_diff_file, write_diff_file, write_self_diff_file, DiffOptions,
+};
+use rolling_hash_rs::file_io::{
+    read_handler, AtomicOutput, OutputOptions, ReadOptions, RetryPolicy,
+};
+use rolling_hash_rs::inspect::{
+    read_delta_file, signature_collision_report, write_collision_report, write_delta_report,
+    write_signature_details, write_signature_json, write_signature_report,
+};
+use rolling_hash_rs::librsync::{write_librsync_signature_file, SignatureFormat};
+use rolling_hash_rs::manifest::{diff_directory, read_manifest_file, write_manifest_file};
+use rolling_hash_rs::patch::{apply_patch_file, verify_patch_file};
+use rolling_hash_rs::self_test::{clock_seed, round_trip_files, run_self_test};
+use rolling_hash_rs::signature::{
+    block_boundaries, plan_signature_file, read_signature_file, read_signature_with_version,
+    repair_signature_file, write_block_boundaries, write_signature_file, SignatureOptions,
+};
+use rolling_hash_rs::window_checksum::validate_block_size;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 
 mod cli_parser;
-mod handlers;
+
+// Informational message on stderr, left out with --quiet. Errors are always printed
+macro_rules! status {
+    ($quiet:expr, $($arg:tt)*) => {
+        if !$quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// Exit code of errors, including invalid arguments, except under compare --exit-code
+const ERROR_EXIT_CODE: i32 = 2;
 
 fn main() {
-    let opts = CliOptions::parse();
+    let opts = match CliOptions::try_parse().and_then(|opts| opts.check().map(|()| opts)) {
+        Ok(opts) => opts,
+        // compare --exit-code uses 2 for a bucket, so its usage errors exit like its other
+        // errors. The arguments didn't parse, so --exit-code is looked for among them as given
+        Err(error) if error.use_stderr() => {
+            error.print().unwrap();
+            let compare_exit_code = std::env::args_os().any(|arg| arg == "compare")
+                && std::env::args_os().any(|arg| arg == "--exit-code");
+            std::process::exit(if compare_exit_code {
+                COMPARE_ERROR_EXIT_CODE
+            } else {
+                ERROR_EXIT_CODE
+            });
+        }
+        Err(error) => error.exit(),
+    };
+    // Under compare --exit-code errors exit like a panic would, so they can't be mistaken
+    // for a bucket
+    let error_exit_code = match &opts.sub_command {
+        SubCommand::Compare(compare_command) if compare_command.exit_code => {
+            COMPARE_ERROR_EXIT_CODE
+        }
+        _ => ERROR_EXIT_CODE,
+    };
+    if let Err(error) = run(opts) {
+        eprintln!("error: {}", error);
+        std::process::exit(error_exit_code);
+    }
+}
+
+fn run(opts: CliOptions) -> Result<(), RollingHashError> {
+    let quiet = opts.quiet;
+    let output_options = OutputOptions {
+        retain_temp: opts.retain_temp,
+        durable: opts.durable,
+    };
+    let retry = RetryPolicy {
+        retries: opts.io_retries,
+        delay: Duration::from_millis(opts.io_retry_delay),
+    };
 
     match opts.sub_command {
         SubCommand::GenerateSignature(gen_sign_command) => {
-            let old_file = read_handler(&gen_sign_command.old_file).unwrap();
-            let mut signature_file = write_handler(&gen_sign_command.signature_file).unwrap();
-            write_signature_file(&old_file, &mut signature_file).unwrap();
-            println!(
+            let signature_options = SignatureOptions {
+                strong_hash_cache: gen_sign_command.cache_strong_hashes,
+                fixed_block_size: gen_sign_command.fixed_block_size,
+                rolling_checksum: gen_sign_command.rolling_checksum,
+                dedup: gen_sign_command.dedup,
+                whole_file_hash: gen_sign_command.whole_file_hash,
+                domain_tag: gen_sign_command.domain_tag.clone(),
+                strong_hash: gen_sign_command.strong_hash,
+                strong_hash_len: gen_sign_command.strong_hash_len,
+                #[cfg(feature = "parallel")]
+                threads: gen_sign_command.threads.map_or_else(
+                    || std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+                    |threads| threads as usize,
+                ),
+                #[cfg(not(feature = "parallel"))]
+                threads: 1,
+            };
+            if gen_sign_command.old_file.is_dir() {
+                if gen_sign_command.dry_run
+                    || gen_sign_command.signature_format != SignatureFormat::Native
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "a directory is only signed as a native manifest, without --dry-run",
+                    )
+                    .into());
+                }
+                let mut manifest_file =
+                    AtomicOutput::create(&gen_sign_command.signature_file, &output_options)?;
+                write_manifest_file(
+                    &gen_sign_command.old_file,
+                    manifest_file.file(),
+                    &retry,
+                    &signature_options,
+                )?;
+                manifest_file.commit()?;
+                status!(
+                    quiet,
+                    "Generated manifest file: {}",
+                    gen_sign_command.signature_file.display()
+                );
+                return Ok(());
+            }
+            let old_file = read_handler(&gen_sign_command.old_file)?;
+            if gen_sign_command.dry_run {
+                let plan = plan_signature_file(&old_file, &retry, &signature_options)?;
+                eprintln!("Block size: {} bytes", plan.block_size);
+                eprintln!("File length: {} bytes", plan.file_length);
+                eprintln!("Blocks: {}", plan.block_count);
+                return Ok(());
+            }
+            let mut signature_file =
+                AtomicOutput::create(&gen_sign_command.signature_file, &output_options)?;
+            match gen_sign_command.signature_format {
+                SignatureFormat::Native => write_signature_file(
+                    &old_file,
+                    signature_file.file(),
+                    &retry,
+                    &signature_options,
+                )?,
+                SignatureFormat::Librsync => write_librsync_signature_file(
+                    &old_file,
+                    signature_file.file(),
+                    &retry,
+                    &signature_options,
+                )?,
+            }
+            signature_file.commit()?;
+            status!(
+                quiet,
                 "Generated signature file: {}",
                 gen_sign_command.signature_file.display()
             );
         }
         SubCommand::GenerateDiff(gen_diff_command) => {
-            let signature_file = read_handler(&gen_diff_command.signature_file).unwrap();
-            let new_file = read_handler(&gen_diff_command.new_file).unwrap();
-            let mut diff_file = write_handler(&gen_diff_command.delta_file).unwrap();
-            write_diff_file(&signature_file, &new_file, &mut diff_file).unwrap();
-            println!(
+            let rolling_state_dump = match &gen_diff_command.dump_rolling_state {
+                Some(path) => Some(RefCell::new(BufWriter::new(File::create(path)?))),
+                None => None,
+            };
+            let diff_options = DiffOptions {
+                collision_policy: gen_diff_command.on_collision,
+                max_collision_rate: gen_diff_command.max_collision_rate,
+                position_hints: gen_diff_command.position_hints,
+                optimize: gen_diff_command.optimize,
+                max_literal_run: gen_diff_command.max_literal_run.map(|bytes| bytes as usize),
+                min_match_blocks: gen_diff_command
+                    .min_match_blocks
+                    .map(|blocks| blocks as usize),
+                #[cfg(feature = "parallel")]
+                strong_hash_threads: gen_diff_command.threads as usize,
+                block_index_base: gen_diff_command.block_index_base,
+                dump_rolling_state: rolling_state_dump
+                    .as_ref()
+                    .map(|dump| dump as &RefCell<dyn Write>),
+                ..DiffOptions::default()
+            };
+            let read_options = ReadOptions {
+                timeout: gen_diff_command.read_timeout,
+                retry,
+            };
+            // A directory is diffed against a manifest, into a directory of deltas
+            if gen_diff_command.new_file.is_dir() {
+                let manifest_path = gen_diff_command.signature_file.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "a directory is diffed against the manifest of the old directory",
+                    )
+                })?;
+                let manifest = read_manifest_file(&read_handler(&manifest_path)?)?;
+                let directory_stats = diff_directory(
+                    &manifest,
+                    &gen_diff_command.new_file,
+                    &gen_diff_command.delta_file,
+                    &diff_options,
+                    &read_options,
+                    &output_options,
+                )?;
+                for (relative, stats) in directory_stats {
+                    status!(quiet, "{}: {}", relative, stats);
+                }
+                if let Some(dump) = rolling_state_dump {
+                    dump.into_inner().flush()?;
+                }
+                status!(
+                    quiet,
+                    "Generated diff directory: {}",
+                    gen_diff_command.delta_file.display()
+                );
+                return Ok(());
+            }
+            let new_file = read_handler(&gen_diff_command.new_file)?;
+            let mut diff_file =
+                AtomicOutput::create(&gen_diff_command.delta_file, &output_options)?;
+            let stats = match gen_diff_command.signature_file {
+                Some(signature_path) => {
+                    let signature_file = read_handler(&signature_path)?;
+                    write_diff_file(
+                        &signature_file,
+                        &new_file,
+                        diff_file.file(),
+                        &diff_options,
+                        &read_options,
+                    )?
+                }
+                None => {
+                    write_self_diff_file(&new_file, diff_file.file(), &diff_options, &read_options)?
+                }
+            };
+            if let Some(dump) = rolling_state_dump {
+                dump.into_inner().flush()?;
+            }
+            diff_file.commit()?;
+            status!(
+                quiet,
                 "Generated diff file: {}",
                 gen_diff_command.delta_file.display()
             );
+            status!(quiet, "{}", stats);
+        }
+        SubCommand::ApplyPatch(patch_command) => {
+            let old_file = read_handler(&patch_command.old_file)?;
+            let delta_file = read_handler(&patch_command.delta_file)?;
+            let signature = match patch_command.signature_file {
+                Some(signature_path) => Some(read_signature_file(&read_handler(&signature_path)?)?),
+                None => None,
+            };
+            let mut output_file =
+                AtomicOutput::create(&patch_command.output_file, &output_options)?;
+            apply_patch_file(
+                &old_file,
+                &delta_file,
+                output_file.file(),
+                signature.as_ref(),
+                patch_command.verify_blocks,
+                &retry,
+            )?;
+            output_file.commit()?;
+            status!(
+                quiet,
+                "Generated new file: {}",
+                patch_command.output_file.display()
+            );
+        }
+        SubCommand::Verify(verify_command) => {
+            let signature = match verify_command.signature_file {
+                Some(signature_path) => Some(read_signature_file(&read_handler(&signature_path)?)?),
+                None => None,
+            };
+            match verify_patch_file(
+                &read_handler(&verify_command.old_file)?,
+                &read_handler(&verify_command.delta_file)?,
+                &read_handler(&verify_command.new_file)?,
+                signature.as_ref(),
+                &retry,
+            )? {
+                Some(offset) => {
+                    eprintln!(
+                        "Delta does not reconstruct the new file: first difference at byte {}",
+                        offset
+                    );
+                    std::process::exit(1);
+                }
+                None => status!(quiet, "Delta reconstructs the new file"),
+            }
+        }
+        SubCommand::DiffBatch(batch_command) => {
+            let signature_file = read_handler(&batch_command.signature_file)?;
+            let read_options = ReadOptions {
+                timeout: None,
+                retry,
+            };
+            #[cfg(feature = "parallel")]
+            let batch = diff_batch_parallel(
+                &signature_file,
+                &batch_command.new_files,
+                &batch_command.output_dir,
+                &DiffOptions::default(),
+                &read_options,
+                &output_options,
+                batch_command.parallel_files as usize,
+            );
+            #[cfg(not(feature = "parallel"))]
+            let batch = diff_batch(
+                &signature_file,
+                &batch_command.new_files,
+                &batch_command.output_dir,
+                &DiffOptions::default(),
+                &read_options,
+                &output_options,
+            );
+            let batch = batch?;
+            for (delta_path, stats) in batch {
+                status!(
+                    quiet,
+                    "Generated diff file: {} ({} bytes: {} matched blocks, {} of {} bytes literal)",
+                    delta_path.display(),
+                    stats.delta_size,
+                    stats.matched_blocks,
+                    stats.literal_bytes,
+                    stats.new_file_length
+                );
+            }
+        }
+        SubCommand::Chain(chain_command) => {
+            let links = write_chain(
+                &chain_command.files,
+                &chain_command.output_dir,
+                &output_options,
+            )?;
+            for link in links {
+                status!(
+                    quiet,
+                    "Generated diff file: {} ({} -> {})",
+                    link.delta_path.display(),
+                    link.from.display(),
+                    link.to.display()
+                );
+            }
+        }
+        SubCommand::Estimate(estimate_command) => {
+            let signature_file = read_handler(&estimate_command.signature_file)?;
+            let new_file = read_handler(&estimate_command.new_file)?;
+            let read_options = ReadOptions {
+                timeout: None,
+                retry,
+            };
+            let estimate = estimate_diff_file(&signature_file, &new_file, &read_options)?;
+            println!(
+                "New file: {} bytes in {} blocks",
+                estimate.new_file_length, estimate.block_count
+            );
+            println!(
+                "Best case (all blocks matched): {} bytes",
+                estimate.best_case
+            );
+            println!("Worst case (all literal): {} bytes", estimate.worst_case);
+            println!(
+                "Per entry overhead: {} bytes per matched block, {} bytes per literal run",
+                estimate.match_entry_size, estimate.literal_entry_overhead
+            );
+        }
+        SubCommand::SelfDedup(self_dedup_command) => {
+            let input_file = read_handler(&self_dedup_command.file)?;
+            let report = self_dedup_file(&input_file, self_dedup_command.block_size)?;
+            println!(
+                "{} blocks of {} bytes: {} unique, {} duplicate",
+                report.total_blocks,
+                report.block_size,
+                report.unique_blocks,
+                report.duplicate_blocks
+            );
+            println!(
+                "Storing duplicates once would save {} bytes",
+                report.bytes_saved
+            );
+        }
+        SubCommand::InspectDelta(inspect_command) => {
+            let delta_file = read_handler(&inspect_command.delta_file)?;
+            let delta = read_delta_file(&delta_file)?;
+            if inspect_command.validate_delta {
+                let signature_path = inspect_command.signature_file.unwrap();
+                let signature = read_signature_file(&read_handler(&signature_path)?)?;
+                signature.check_delta(&delta)?;
+            }
+            write_delta_report(&delta, inspect_command.hexdump, &mut io::stdout().lock())?;
+        }
+        SubCommand::InspectSignature(inspect_command) => {
+            let signature_file = read_handler(&inspect_command.signature_file)?;
+            let (version, signature) =
+                read_signature_with_version(&mut io::BufReader::new(&signature_file))?;
+            if inspect_command.json {
+                write_signature_json(&signature, &mut io::stdout().lock())?;
+            } else {
+                if inspect_command.verbose {
+                    write_signature_details(&signature, version, &mut io::stdout().lock())?;
+                } else {
+                    write_signature_report(&signature, &mut io::stdout().lock())?;
+                }
+                if inspect_command.stats {
+                    write_collision_report(
+                        &signature_collision_report(&signature),
+                        &mut io::stdout().lock(),
+                    )?;
+                }
+            }
+        }
+        SubCommand::Compare(compare_command) => {
+            let signature_file = read_handler(&compare_command.signature_file)?;
+            let new_file = read_handler(&compare_command.new_file)?;
+            if compare_command.expect_identical {
+                match first_divergence_in_files(&signature_file, &new_file)? {
+                    Some(offset) => {
+                        eprintln!("Files differ from the block at offset {}", offset);
+                        std::process::exit(1);
+                    }
+                    None if !quiet => println!("Files are identical"),
+                    None => {}
+                }
+                return Ok(());
+            }
+            let comparison = compare_files(&signature_file, &new_file, compare_command.count_only)?;
+            let similarity = &comparison.similarity;
+            if !quiet {
+                println!(
+                    "Similarity: {:.1}% ({} of {} bytes matched){}",
+                    similarity.fraction() * 100.0,
+                    similarity.matched_bytes,
+                    similarity.new_file_length,
+                    if similarity.approximate {
+                        ", approximate from weak checksums only"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            if let Some(image_path) = compare_command.visualize {
+                let mut image_file = AtomicOutput::create(&image_path, &output_options)?;
+                write_change_map_pbm(
+                    &comparison.changed_blocks,
+                    &mut BufWriter::new(image_file.file()),
+                )?;
+                image_file.commit()?;
+                if !quiet {
+                    eprintln!("Generated change map: {}", image_path.display());
+                }
+            }
+            if compare_command.exit_code {
+                let thresholds = SimilarityThresholds {
+                    similar: compare_command.similar_above,
+                    partial: compare_command.partial_above,
+                };
+                std::process::exit(similarity.bucket(&thresholds).exit_code());
+            }
+        }
+        SubCommand::BlockBoundaries(boundaries_command) => {
+            let block_size = match boundaries_command.signature_file {
+                Some(signature_path) => {
+                    read_signature_file(&read_handler(&signature_path)?)?.block_chunk_size
+                }
+                None => boundaries_command.block_size.unwrap(),
+            };
+            validate_block_size(block_size)?;
+            let file = read_handler(&boundaries_command.file)?;
+            let boundaries = block_boundaries(file.metadata()?.len(), block_size);
+            write_block_boundaries(
+                &boundaries,
+                boundaries_command.json,
+                &mut io::stdout().lock(),
+            )?;
+        }
+        SubCommand::Bundle(bundle_command) => {
+            let signature_file = read_handler(&bundle_command.signature_file)?;
+            let delta_file = read_handler(&bundle_command.delta_file)?;
+            let mut bundle_file =
+                AtomicOutput::create(&bundle_command.bundle_file, &output_options)?;
+            bundle_files(&signature_file, &delta_file, bundle_file.file())?;
+            bundle_file.commit()?;
+            status!(
+                quiet,
+                "Generated bundle file: {}",
+                bundle_command.bundle_file.display()
+            );
+        }
+        SubCommand::Unbundle(unbundle_command) => {
+            let bundle_file = read_handler(&unbundle_command.bundle_file)?;
+            let mut signature_file =
+                AtomicOutput::create(&unbundle_command.signature_file, &output_options)?;
+            let mut delta_file =
+                AtomicOutput::create(&unbundle_command.delta_file, &output_options)?;
+            unbundle_file(&bundle_file, signature_file.file(), delta_file.file())?;
+            signature_file.commit()?;
+            delta_file.commit()?;
+            status!(
+                quiet,
+                "Generated signature file: {}",
+                unbundle_command.signature_file.display()
+            );
+            status!(
+                quiet,
+                "Generated diff file: {}",
+                unbundle_command.delta_file.display()
+            );
+        }
+        SubCommand::RepairSignature(repair_command) => {
+            let signature_file = read_handler(&repair_command.signature_file)?;
+            let source_file = read_handler(&repair_command.source)?;
+            let repaired_path = repair_command
+                .output
+                .unwrap_or(repair_command.signature_file);
+            let mut repaired_file = AtomicOutput::create(&repaired_path, &output_options)?;
+            let repair =
+                repair_signature_file(&signature_file, &source_file, repaired_file.file(), &retry)?;
+            repaired_file.commit()?;
+            status!(
+                quiet,
+                "Repaired {} signature entries, dropped {} damaged entries: {}",
+                repair.repaired,
+                repair.removed,
+                repaired_path.display()
+            );
+        }
+        SubCommand::SelfTest(self_test_command) => {
+            let report = run_self_test(
+                self_test_command.seed.unwrap_or_else(clock_seed),
+                self_test_command.size as usize,
+                self_test_command.edits as usize,
+            );
+            println!("{}", report);
+            if !report.passed() {
+                std::process::exit(1);
+            }
+        }
+        SubCommand::VerifyRoundtrip(roundtrip_command) => {
+            let round_trip = round_trip_files(
+                &read_handler(&roundtrip_command.old)?,
+                &read_handler(&roundtrip_command.new)?,
+                &retry,
+            )?;
+            if roundtrip_command.keep_intermediates {
+                for (extension, contents) in [
+                    ("signature", &round_trip.signature_file),
+                    ("delta", &round_trip.delta_file),
+                ] {
+                    let mut path = roundtrip_command.new.clone().into_os_string();
+                    path.push(".");
+                    path.push(extension);
+                    let path = PathBuf::from(path);
+                    let mut output = AtomicOutput::create(&path, &output_options)?;
+                    output.file().write_all(contents)?;
+                    output.commit()?;
+                    status!(quiet, "Kept {} file: {}", extension, path.display());
+                }
+            }
+            match round_trip.first_difference {
+                None => println!("PASS: {}", round_trip.stats),
+                Some(offset) => {
+                    println!(
+                        "FAIL: patched file differs from the new file at byte {} ({})",
+                        offset, round_trip.stats
+                    );
+                    std::process::exit(1);
+                }
+            }
         }
     }
+    Ok(())
 }
@@ -0,0 +1,112 @@
+// Errors reach the user as one line from main, never as a panic
+use std::process::Command;
+
+const ERROR_EXIT_CODE: i32 = 2;
+// Errors of compare --exit-code, whose buckets include 2
+const COMPARE_ERROR_EXIT_CODE: i32 = 101;
+
+#[test]
+fn test_missing_signature_file() {
+    let missing = std::env::temp_dir().join(format!(
+        "rolling-hash-missing-signature-{}",
+        std::process::id()
+    ));
+    let output = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+        .arg("generate-diff")
+        .arg("-s")
+        .arg(&missing)
+        .args(["-n", "data/new.txt", "-d", "-"])
+        .output()
+        .unwrap();
+
+    assert_eq!(Some(ERROR_EXIT_CODE), output.status.code());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert_eq!(1, stderr.lines().count(), "{}", stderr);
+    assert!(stderr.starts_with("error: cannot open "), "{}", stderr);
+    assert!(stderr.contains(&*missing.to_string_lossy()), "{}", stderr);
+}
+
+#[test]
+fn test_invalid_read_timeout_is_a_usage_error() {
+    for timeout in ["-1", "NaN", "inf"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+            .args([
+                "generate-diff",
+                "-s",
+                "data/signature",
+                "-n",
+                "data/new.txt",
+            ])
+            .args(["-d", "-"])
+            .arg(format!("--read-timeout={}", timeout))
+            .output()
+            .unwrap();
+
+        assert_eq!(Some(ERROR_EXIT_CODE), output.status.code());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        assert!(stderr.contains("--read-timeout"), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_compare_exit_code_errors_exit_with_101() {
+    let missing = std::env::temp_dir().join(format!(
+        "rolling-hash-missing-compare-signature-{}",
+        std::process::id()
+    ));
+    let compare = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+            .arg("compare")
+            .arg("-s")
+            .arg(&missing)
+            .args(["-n", "data/new.txt"])
+            .args(extra_args)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+
+    assert_eq!(Some(COMPARE_ERROR_EXIT_CODE), compare(&["--exit-code"]));
+    assert_eq!(
+        Some(COMPARE_ERROR_EXIT_CODE),
+        compare(&["--exit-code", "--similar-above", "most"])
+    );
+    assert_eq!(Some(ERROR_EXIT_CODE), compare(&[]));
+    assert_eq!(Some(ERROR_EXIT_CODE), compare(&["--similar-above", "most"]));
+}
+
+#[test]
+fn test_compare_thresholds_are_checked() {
+    let compare = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+            .args(["compare", "-s", "data/signature", "-n", "data/new.txt"])
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+
+    for (arg, expected) in [
+        ("--similar-above=1.5", "--similar-above"),
+        ("--similar-above=NaN", "--similar-above"),
+        ("--partial-above=-0.1", "--partial-above"),
+        (
+            "--similar-above=0.4",
+            "--partial-above 0.5 is above --similar-above 0.4",
+        ),
+    ] {
+        let output = compare(&[arg]);
+        assert_eq!(Some(ERROR_EXIT_CODE), output.status.code(), "{}", arg);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(expected), "{}", stderr);
+
+        assert_eq!(
+            Some(COMPARE_ERROR_EXIT_CODE),
+            compare(&[arg, "--exit-code"]).status.code()
+        );
+    }
+    assert!(compare(&["--similar-above=0.5"]).status.success());
+}
@@ -0,0 +1,32 @@
+// estimate learns the new file's length whether it is a file or a stream
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_estimate_of_standard_input() {
+    let new_file = std::fs::read("data/new.txt").unwrap();
+    let estimate = |new_file_arg: &str, stdin: Option<&[u8]>| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+            .args(["estimate", "-s", "data/signature", "-n", new_file_arg])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut child_stdin = child.stdin.take().unwrap();
+        if let Some(stdin) = stdin {
+            child_stdin.write_all(stdin).unwrap();
+        }
+        drop(child_stdin);
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let from_file = estimate("data/new.txt", None);
+    assert!(
+        from_file.starts_with(&format!("New file: {} bytes", new_file.len())),
+        "{}",
+        from_file
+    );
+    assert_eq!(from_file, estimate("-", Some(&new_file)));
+}
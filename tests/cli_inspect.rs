@@ -0,0 +1,38 @@
+// inspect-signature reads back what a signature was generated with
+use std::process::Command;
+
+#[test]
+fn test_verbose_inspect_prints_block_size() {
+    let signature_path = std::env::temp_dir().join(format!(
+        "rolling-hash-inspect-signature-{}",
+        std::process::id()
+    ));
+    let status = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+        .args(["generate-signature", "-o", "data/old.txt", "-s"])
+        .arg(&signature_path)
+        .args(["--block-size", "100", "--rolling-checksum", "prime65521"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+        .args(["inspect-signature", "--verbose", "-s"])
+        .arg(&signature_path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&signature_path).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let old_length = std::fs::metadata("data/old.txt").unwrap().len();
+    for line in [
+        "Block size: 100 bytes".to_string(),
+        "Chunking: fixed".to_string(),
+        "Rolling checksum: prime65521 (modulus 65521)".to_string(),
+        "Strong hash: sha256, 32 of 32 bytes kept".to_string(),
+        format!("Total chunks: {}", old_length.div_ceil(100)),
+        format!("Original length: {} bytes", old_length),
+    ] {
+        assert!(stdout.lines().any(|printed| printed == line), "{}", stdout);
+    }
+}
@@ -0,0 +1,34 @@
+// --quiet leaves out the messages about generated files, for scripts
+use std::process::Command;
+
+#[test]
+fn test_quiet_signature_generation_prints_nothing() {
+    let signature_path = std::env::temp_dir().join(format!(
+        "rolling-hash-quiet-signature-{}",
+        std::process::id()
+    ));
+    let generate = |quiet: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"));
+        command
+            .args(["generate-signature", "-o", "data/old.txt", "-s"])
+            .arg(&signature_path);
+        if quiet {
+            command.arg("--quiet");
+        }
+        command.output().unwrap()
+    };
+
+    let output = generate(false);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Generated signature file: "));
+    std::fs::remove_file(&signature_path).unwrap();
+
+    let output = generate(true);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+    assert!(std::fs::metadata(&signature_path).unwrap().len() > 0);
+    std::fs::remove_file(&signature_path).unwrap();
+}
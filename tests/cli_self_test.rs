@@ -0,0 +1,37 @@
+// self-test runs the whole pipeline without the data/ fixtures
+use std::process::Command;
+
+#[test]
+fn test_self_test_passes() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+        .args([
+            "self-test",
+            "--seed",
+            "42",
+            "--size",
+            "65536",
+            "--edits",
+            "20",
+        ])
+        .output()
+        .unwrap();
+
+    assert_eq!(Some(0), output.status.code());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("PASS seed 42 (65536 bytes, 20 edits"),
+        "{}",
+        stdout
+    );
+    assert_eq!(1, stdout.lines().count(), "{}", stdout);
+
+    // Without a seed, the one picked is printed
+    let output = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+        .args(["self-test", "--size", "4096"])
+        .output()
+        .unwrap();
+    assert_eq!(Some(0), output.status.code());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("PASS seed "));
+}
@@ -0,0 +1,53 @@
+// apply-patch --verify-copies checks each block it copies from the old file against the
+// signature, catching changes that the old file's length and first block don't show
+use std::process::Command;
+
+#[test]
+fn test_verify_copies_detects_modified_base_block() {
+    let old_path = std::env::temp_dir().join(format!(
+        "rolling-hash-verify-copies-old-{}",
+        std::process::id()
+    ));
+    let output_path = std::env::temp_dir().join(format!(
+        "rolling-hash-verify-copies-output-{}",
+        std::process::id()
+    ));
+    // data/diff copies block 3 of data/old.txt, which is past the first block
+    let mut old_file = std::fs::read("data/old.txt").unwrap();
+    old_file[64 * 3 + 5] ^= 0xff;
+    std::fs::write(&old_path, &old_file).unwrap();
+
+    let apply = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"))
+            .args([
+                "-q",
+                "apply-patch",
+                "-d",
+                "data/diff",
+                "-s",
+                "data/signature",
+            ])
+            .arg("-o")
+            .arg(&old_path)
+            .arg("--output-file")
+            .arg(&output_path)
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+
+    // Only the length and first block are checked without it
+    assert!(apply(&[]).status.success());
+
+    let output = apply(&["--verify-copies"]);
+    assert_eq!(Some(2), output.status.code());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("block 3 differs from the signed file's"),
+        "{}",
+        stderr
+    );
+
+    std::fs::remove_file(old_path).unwrap();
+    let _ = std::fs::remove_file(output_path);
+}
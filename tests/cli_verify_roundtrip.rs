@@ -0,0 +1,57 @@
+// verify-roundtrip signs, diffs, patches and compares real files in one command
+use std::process::Command;
+
+fn verify_roundtrip(old: &str, new: &str, keep_intermediates: bool) -> std::process::Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rolling_hash_rs"));
+    command.args(["verify-roundtrip", "--old", old, "--new", new]);
+    if keep_intermediates {
+        command.arg("--keep-intermediates");
+    }
+    command.output().unwrap()
+}
+
+#[test]
+fn test_verify_roundtrip_text_fixtures() {
+    let output = verify_roundtrip("data/old.txt", "data/new.txt", false);
+    assert_eq!(Some(0), output.status.code());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("PASS: matched "), "{}", stdout);
+}
+
+#[test]
+fn test_verify_roundtrip_binary_files() {
+    let dir = std::env::temp_dir().join(format!(
+        "rolling-hash-verify-roundtrip-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Every byte value, NULs and bytes that aren't UTF-8 included
+    let old: Vec<u8> = (0..20_000u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let mut new = old.clone();
+    new.splice(5_000..5_000, [0u8, 0xff, 0xfe, 0, 0x80]);
+    new.drain(12_000..12_300);
+    let old_path = dir.join("old.bin");
+    let new_path = dir.join("new.bin");
+    std::fs::write(&old_path, &old).unwrap();
+    std::fs::write(&new_path, &new).unwrap();
+    let (old_arg, new_arg) = (old_path.to_str().unwrap(), new_path.to_str().unwrap());
+
+    let output = verify_roundtrip(old_arg, new_arg, false);
+    assert_eq!(Some(0), output.status.code());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("PASS: "));
+    // Nothing is left behind without --keep-intermediates
+    assert_eq!(2, std::fs::read_dir(&dir).unwrap().count());
+
+    let output = verify_roundtrip(old_arg, new_arg, true);
+    assert_eq!(Some(0), output.status.code());
+    let signature = std::fs::read(dir.join("new.bin.signature")).unwrap();
+    let delta = std::fs::read(dir.join("new.bin.delta")).unwrap();
+    assert!(signature.starts_with(b"RHSG"));
+    assert!(delta.starts_with(b"RHDL"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
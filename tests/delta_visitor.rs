@@ -0,0 +1,56 @@
+// VerifyMatch is non_exhaustive: a consumer outside the crate keeps compiling when
+// entry kinds are added, whether it uses a visitor, the accessors or a wildcard arm
+use rolling_hash_rs::file_diff::{visit_delta, DeltaVisitor, VerifyMatch};
+
+#[derive(Default)]
+struct Tally {
+    matched_blocks: u32,
+    literal_bytes: usize,
+}
+
+impl DeltaVisitor for Tally {
+    fn visit_match(&mut self, _index: u64) {
+        self.matched_blocks += 1;
+    }
+
+    fn visit_literal(&mut self, bytes: &[u8]) {
+        self.literal_bytes += bytes.len();
+    }
+}
+
+fn delta() -> Vec<VerifyMatch> {
+    vec![
+        VerifyMatch::Match(0),
+        VerifyMatch::NoMatch(b"abc".to_vec()),
+        VerifyMatch::Match(1),
+    ]
+}
+
+#[test]
+fn test_visitor_consumer() {
+    let mut tally = Tally::default();
+    visit_delta(&delta(), &mut tally);
+    assert_eq!(2, tally.matched_blocks);
+    assert_eq!(3, tally.literal_bytes);
+}
+
+#[test]
+fn test_accessor_and_wildcard_consumers() {
+    let delta = delta();
+    let matched: Vec<u64> = delta
+        .iter()
+        .filter_map(VerifyMatch::matched_block)
+        .collect();
+    assert_eq!(vec![0, 1], matched);
+    assert_eq!(Some(&b"abc"[..]), delta[1].literal_bytes());
+
+    let literal_entries = delta
+        .iter()
+        .filter(|entry| match entry {
+            VerifyMatch::NoMatch(_) => true,
+            // Required outside the crate, and covers entry kinds added later
+            _ => false,
+        })
+        .count();
+    assert_eq!(1, literal_entries);
+}
@@ -0,0 +1,40 @@
+// The slice API works on buffers alone, as in a build without a filesystem
+use rolling_hash_rs::diff::VerifyMatch;
+use rolling_hash_rs::patch::apply_patch;
+use rolling_hash_rs::{diff_of, get_signature, signature_of};
+
+fn patched(old: &[u8], delta: &[VerifyMatch], block_size: u32) -> Vec<u8> {
+    let mut rebuilt: Vec<u8> = Vec::new();
+    apply_patch(old, delta, block_size, &mut rebuilt).unwrap();
+    rebuilt
+}
+
+#[test]
+fn test_slice_round_trip() {
+    let old: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut new = old.clone();
+    new.splice(1000..1010, b"replaced with more bytes".iter().copied());
+    new.truncate(3900);
+
+    let signature = signature_of(&old, 64);
+    assert_eq!(get_signature(&old, 64), signature);
+    let delta = diff_of(&signature, &new);
+    assert!(delta.iter().any(VerifyMatch::is_match));
+    assert_eq!(new, patched(&old, &delta, signature.block_chunk_size));
+}
+
+#[test]
+fn test_slice_edge_cases() {
+    let data = b"identical data, one block and a bit".to_vec();
+    let signature = signature_of(&data, 16);
+    let delta = diff_of(&signature, &data);
+    assert!(delta.iter().all(VerifyMatch::is_match));
+    assert_eq!(data, patched(&data, &delta, 16));
+
+    let empty = signature_of(&[], 16);
+    assert_eq!(
+        vec![VerifyMatch::NoMatch(data.clone())],
+        diff_of(&empty, &data)
+    );
+    assert!(diff_of(&signature, &[]).is_empty());
+}